@@ -0,0 +1,288 @@
+use simple_irc::Prefix;
+
+use crate::announcement;
+use crate::config::{Announcement, AnnouncementAction, Server};
+use crate::irc_ext::{self, IrcExt};
+use crate::irc_state::IrcState;
+use crate::privmsg::{PrivMsgResponse, ResponseType};
+use crate::runtime_config;
+
+pub fn is_admin(server: &Server, user: &Prefix) -> bool {
+    let hostmask = user.to_string();
+
+    server.admins.iter().any(|mask| hostmask.matches_mask(mask))
+}
+
+pub fn is_ignored(server: &Server, irc_state: &IrcState, user: &Prefix) -> bool {
+    let hostmask = user.to_string();
+
+    irc_state.rate_limiter.is_ignored(&irc_ext::user_key(user)) || server.ignores.iter().any(|mask| hostmask.matches_mask(mask))
+}
+
+// Accepts nick, user@host or nick!user@host masks
+fn normalize_mask(mask: &str) -> String {
+    if mask.contains('!') {
+        mask.to_string()
+    } else if mask.contains('@') {
+        format!("*!{}", mask)
+    } else {
+        format!("{}!*@*", mask)
+    }
+}
+
+// Handles the built-in admin commands, returns None if the message isn't one of them
+pub fn execute(server: &mut Server, irc_state: &mut IrcState, user: &Prefix, source: &str, message: &str) -> Option<Vec<PrivMsgResponse>> {
+    let command_prefix = server.command_prefix(server.channel(source)).to_string();
+    let mut arguments: Vec<&str> = message.strip_prefix(&command_prefix)?.split_whitespace().collect();
+
+    if arguments.is_empty() {
+        return None;
+    }
+
+    let command = arguments.remove(0);
+
+    if !["plugin", "prefix", "set", "unset", "ignore", "announce"].contains(&command) {
+        return None;
+    }
+
+    // Plugins and scripts may have commands with the same name
+    if !is_admin(server, user) {
+        log::debug!("{} isn't an admin, passing {} on to the plugins", user, command);
+
+        return None;
+    }
+
+    if command == "ignore" {
+        let (message, changed) = execute_ignore(server, irc_state, &arguments, &command_prefix);
+
+        return Some(finish(server, user, message, changed));
+    }
+
+    // A lone channel prefix character is more likely a command prefix than a channel
+    let channel_name = if !arguments.is_empty() && arguments[0].len() > 1 && arguments[0].is_channel_name() {
+        arguments.remove(0).to_string()
+    } else if source.is_channel_name() {
+        source.to_string()
+    } else {
+        return Some(reply(user, "Specify a channel".to_string()));
+    };
+
+    let (message, changed) = match (command, arguments.as_slice()) {
+        ("plugin", ["list"]) | ("plugin", []) => {
+            let channel = server.channel(&channel_name);
+            let plugins: Vec<String> = server.privmsg_plugins.iter().map(|plugin| {
+                let state = if server.is_plugin_enabled(channel, plugin) { "on" } else { "off" };
+
+                format!("{} ({})", plugin, state)
+            }).collect();
+
+            (format!("Plugins in {}: {}", channel_name, plugins.join(", ")), false)
+        }
+        ("plugin", [action @ "enable", plugin]) | ("plugin", [action @ "disable", plugin]) => {
+            if !server.privmsg_plugins.iter().any(|p| p == plugin) {
+                (format!("Unknown plugin: {}", plugin), false)
+            } else {
+                server.override_channel(&channel_name, |overrides| {
+                    overrides.plugins.insert(plugin.to_string(), *action == "enable");
+                });
+
+                (format!("Plugin {} {}d in {}", plugin, action, channel_name), true)
+            }
+        }
+        ("prefix", []) => {
+            (format!("Command prefix in {}: {}", channel_name, server.command_prefix(server.channel(&channel_name))), false)
+        }
+        ("prefix", [prefix]) => {
+            server.override_channel(&channel_name, |overrides| overrides.command_prefix = Some(prefix.to_string()));
+
+            (format!("Command prefix in {} set to {}", channel_name, prefix), true)
+        }
+        ("set", [plugin, key, value @ ..]) if !value.is_empty() => {
+            let value = value.join(" ");
+            let yaml_value = serde_yaml::from_str(&value).unwrap_or_else(|_| serde_yaml::Value::String(value.clone()));
+
+            server.override_channel(&channel_name, |overrides| {
+                overrides.settings.entry(plugin.to_string()).or_default().insert(key.to_string(), Some(yaml_value));
+            });
+
+            (format!("Setting {}.{} in {} set to {}", plugin, key, channel_name, value), true)
+        }
+        ("announce", arguments) => execute_announce(server, irc_state, &channel_name, arguments, &command_prefix),
+        ("unset", [plugin, key]) => {
+            // Recorded as removed, the setting may come from config.yml
+            server.override_channel(&channel_name, |overrides| {
+                overrides.settings.entry(plugin.to_string()).or_default().insert(key.to_string(), None);
+            });
+
+            (format!("Setting {}.{} in {} removed", plugin, key, channel_name), true)
+        }
+        _ => (format!("Usage: {0}plugin [#channel] <list|enable|disable> [plugin], {0}prefix [#channel] [prefix], \
+                       {0}set [#channel] <plugin> <key> <value>, {0}unset [#channel] <plugin> <key>, {0}announce [#channel] <list|add|del>", command_prefix), false),
+    };
+
+    Some(finish(server, user, message, changed))
+}
+
+fn execute_ignore(server: &mut Server, irc_state: &mut IrcState, arguments: &[&str], command_prefix: &str) -> (String, bool) {
+    match arguments {
+        ["list"] | [] => {
+            let temporary: Vec<String> = irc_state.rate_limiter.ignored.iter()
+                .filter(|(user_key, _)| irc_state.rate_limiter.is_ignored(user_key))
+                .map(|(_, (_, hostmask))| format!("{} (temporary)", hostmask))
+                .collect();
+            let ignores: Vec<String> = server.ignores.iter().cloned().chain(temporary).collect();
+
+            if ignores.is_empty() {
+                ("Nobody is ignored".to_string(), false)
+            } else {
+                (format!("Ignored: {}", ignores.join(", ")), false)
+            }
+        }
+        ["add", mask] => {
+            let mask = normalize_mask(mask);
+
+            if server.ignores.contains(&mask) {
+                (format!("{} is already ignored", mask), false)
+            } else {
+                server.ignores.push(mask.clone());
+
+                (format!("Ignoring {}", mask), true)
+            }
+        }
+        ["del", mask] => {
+            // Temporary ignores are matched against the hostmask they were ignored with
+            let mask = normalize_mask(mask);
+            let temporary: Vec<(String, String)> = irc_state.rate_limiter.ignored.iter()
+                .filter(|(_, (_, hostmask))| hostmask.matches_mask(&mask))
+                .map(|(user_key, (_, hostmask))| (user_key.clone(), hostmask.clone()))
+                .collect();
+
+            for (user_key, _) in &temporary {
+                irc_state.rate_limiter.ignored.remove(user_key);
+            }
+
+            let temporary: Vec<String> = temporary.into_iter().map(|(_, hostmask)| hostmask).collect();
+
+            let length = server.ignores.len();
+
+            server.ignores.retain(|ignore| !ignore.eq_ignore_ascii_case(&mask));
+
+            if length != server.ignores.len() {
+                (format!("No longer ignoring {}", mask), true)
+            } else if !temporary.is_empty() {
+                (format!("No longer ignoring {}", temporary.join(", ")), false)
+            } else {
+                (format!("{} isn't ignored", mask), false)
+            }
+        }
+        _ => (format!("Usage: {}ignore <list|add|del> [nick|user@host|nick!user@host]", command_prefix), false),
+    }
+}
+
+fn execute_announce(server: &mut Server, irc_state: &mut IrcState, channel_name: &str, arguments: &[&str], command_prefix: &str) -> (String, bool) {
+    match arguments {
+        ["list"] | [] => {
+            let announcements: Vec<String> = server.channel(channel_name).map(|channel| channel.announcements.iter().map(|announcement| {
+                format!("{} ({} {}, {}): {}", announcement.name, announcement.cron, announcement.timezone.as_deref().unwrap_or("UTC"),
+                        announcement.action.name(), announcement.text)
+            }).collect()).unwrap_or_default();
+
+            if announcements.is_empty() {
+                (format!("No announcements in {}", channel_name), false)
+            } else {
+                (format!("Announcements in {}: {}", channel_name, announcements.join(" / ")), false)
+            }
+        }
+        ["add", name, action, rest @ ..] => {
+            let action = match AnnouncementAction::parse(action) {
+                Some(action) => action,
+                None => return (format!("Unknown action: {}, use message, notice, action, topic or command", action), false),
+            };
+            let mut rest = rest.to_vec();
+            let mut announcement = Announcement {
+                name: name.to_string(),
+                cron: "".to_string(),
+                action,
+                text: "".to_string(),
+                timezone: None,
+                skip_if_idle: 0,
+            };
+
+            // Options come before the cron expression
+            while let Some(option) = rest.first() {
+                if let Some(timezone) = option.strip_prefix("tz=") {
+                    announcement.timezone = Some(timezone.to_string());
+                } else if let Some(minutes) = option.strip_prefix("idle=") {
+                    match minutes.parse() {
+                        Ok(minutes) => announcement.skip_if_idle = minutes,
+                        Err(_) => return (format!("Invalid idle minutes: {}", minutes), false),
+                    }
+                } else {
+                    break;
+                }
+
+                rest.remove(0);
+            }
+
+            let cron_fields = if rest.first().is_some_and(|field| field.starts_with('@')) { 1 } else { 5 };
+
+            if rest.len() <= cron_fields {
+                return (format!("Usage: {}announce [#channel] add <name> <message|notice|action|topic|command> [tz=Area/City] [idle=minutes] <cron> <text>", command_prefix), false);
+            }
+
+            announcement.cron = rest[..cron_fields].join(" ");
+            announcement.text = rest[cron_fields..].join(" ");
+
+            if let Err(e) = announcement::validate(&announcement) {
+                return (e, false);
+            }
+
+            let message = format!("Announcement {} in {} set to {} {}", name, channel_name, announcement.cron, announcement.timezone.as_deref().unwrap_or("UTC"));
+
+            server.override_channel(channel_name, |overrides| {
+                overrides.announcements.insert(name.to_string(), Some(announcement));
+            });
+            irc_state.announcements.remove(&(irc_state.fold(channel_name), name.to_string()));
+
+            (message, true)
+        }
+        ["del", name] => {
+            let exists = server.channel(channel_name).is_some_and(|channel| channel.announcements.iter().any(|announcement| announcement.name == *name));
+
+            if exists {
+                server.override_channel(channel_name, |overrides| {
+                    overrides.announcements.insert(name.to_string(), None);
+                });
+                irc_state.announcements.remove(&(irc_state.fold(channel_name), name.to_string()));
+
+                (format!("Announcement {} removed from {}", name, channel_name), true)
+            } else {
+                (format!("There's no announcement {} in {}", name, channel_name), false)
+            }
+        }
+        _ => (format!("Usage: {0}announce [#channel] list, {0}announce [#channel] add <name> <message|notice|action|topic|command> [tz=Area/City] [idle=minutes] <cron> <text>, \
+                       {0}announce [#channel] del <name>", command_prefix), false),
+    }
+}
+
+fn finish(server: &Server, user: &Prefix, message: String, changed: bool) -> Vec<PrivMsgResponse> {
+    if changed {
+        log::info!("{} changed runtime config: {}", user, message);
+
+        if let Err(e) = runtime_config::save(server) {
+            log::error!("Couldn't save runtime config: {}", e);
+
+            return reply(user, format!("{} (but it couldn't be saved: {})", message, e));
+        }
+    }
+
+    reply(user, message)
+}
+
+fn reply(user: &Prefix, message: String) -> Vec<PrivMsgResponse> {
+    vec![PrivMsgResponse {
+        target: user.nick.clone(),
+        message,
+        response_type: ResponseType::Notice,
+    }]
+}
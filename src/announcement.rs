@@ -0,0 +1,210 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+
+use crate::config::{Announcement, Server};
+use crate::irc_state::IrcState;
+
+// Announcements running later than this, like when the bot was busy, are skipped until the next time
+const MAX_DELAY: i64 = 60;
+const WEEKDAYS: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+// Standard cron expressions have no seconds and count weekdays from 0, Sunday, what the cron crate does from 1
+fn parse_cron(expression: &str) -> Result<Schedule, String> {
+    if expression.starts_with('@') {
+        return Schedule::from_str(expression).map_err(|_| format!("Invalid cron expression: {}, use @yearly, @monthly, @weekly, @daily or @hourly", expression));
+    }
+
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+
+    if fields.len() != 5 {
+        return Err(format!("Invalid cron expression: {}, it needs 5 fields (minute hour day month weekday)", expression));
+    }
+
+    let weekdays = weekday_names(fields[4]).ok_or_else(|| format!("Invalid weekday: {}", fields[4]))?;
+
+    Schedule::from_str(&format!("0 {} {} {} {} {}", fields[0], fields[1], fields[2], fields[3], weekdays))
+        .map_err(|_| format!("Invalid cron expression: {}", expression))
+}
+
+// Numbers are replaced by names, except for steps like */2
+fn weekday_names(field: &str) -> Option<String> {
+    let mut names = String::new();
+    let mut digits = String::new();
+    let mut step = false;
+
+    for c in field.chars().chain(std::iter::once(' ')) {
+        if c.is_ascii_digit() {
+            digits.push(c);
+
+            continue;
+        }
+
+        if !digits.is_empty() {
+            if step {
+                names.push_str(&digits);
+            } else {
+                names.push_str(WEEKDAYS.get(digits.parse::<usize>().ok()?)?);
+            }
+
+            digits.clear();
+        }
+
+        step = c == '/';
+
+        if c != ' ' {
+            names.push(c);
+        }
+    }
+
+    Some(names)
+}
+
+fn parse_timezone(timezone: Option<&str>) -> Result<Tz, String> {
+    match timezone {
+        Some(timezone) => timezone.parse::<Tz>().map_err(|_| format!("Unknown timezone: {}", timezone)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+// Checked when announcements are loaded or added, so due() only sees ones that run
+pub fn validate(announcement: &Announcement) -> Result<(), String> {
+    let schedule = parse_cron(&announcement.cron)?;
+    let timezone = parse_timezone(announcement.timezone.as_deref())?;
+
+    if schedule.after(&Utc::now().with_timezone(&timezone)).next().is_none() {
+        return Err(format!("Cron expression {} never runs", announcement.cron));
+    }
+
+    Ok(())
+}
+
+fn next_run(announcement: &Announcement, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let schedule = parse_cron(&announcement.cron).ok()?;
+    let timezone = parse_timezone(announcement.timezone.as_deref()).ok()?;
+
+    schedule.after(&after.with_timezone(&timezone)).next().map(|next| next.with_timezone(&Utc))
+}
+
+// Announcements due in the channels the bot is in, each of them at most once. The times missed while
+// disconnected aren't made up for, so reconnecting doesn't flood the channels.
+// They aren't scheduler jobs: they come from the config, which admins change at runtime, they repeat and
+// a late one is skipped instead of delivered, so only the next time of each is kept, in IrcState
+pub fn due(server: &Server, irc_state: &mut IrcState) -> Vec<(String, Announcement)> {
+    let now = Utc::now();
+    let joined = irc_state.user(&server.user_data.nickname).map(|user| user.channels.clone()).unwrap_or_default();
+    let mut due: Vec<(String, Announcement)> = vec![];
+
+    for channel in server.all_channels() {
+        let channel_key = irc_state.fold(&channel.name);
+
+        if !joined.contains(&channel_key) {
+            continue;
+        }
+
+        for announcement in &channel.announcements {
+            let key = (channel_key.clone(), announcement.name.clone());
+
+            // The first time is the next one after the bot joined
+            let next = match irc_state.announcements.get(&key) {
+                Some(next) => *next,
+                None => {
+                    if let Some(next) = next_run(announcement, now) {
+                        irc_state.announcements.insert(key, next);
+                    }
+
+                    continue;
+                }
+            };
+
+            if now < next {
+                continue;
+            }
+
+            match next_run(announcement, now) {
+                Some(next) => irc_state.announcements.insert(key.clone(), next),
+                None => irc_state.announcements.remove(&key),
+            };
+
+            if (now - next).num_seconds() > MAX_DELAY {
+                log::warn!("Skipping announcement {} in {}, it was due at {}", announcement.name, channel.name, next);
+
+                continue;
+            }
+
+            let idle = irc_state.channel_activity.get(&channel_key)
+                .is_none_or(|last| last.elapsed() > Duration::from_secs(announcement.skip_if_idle * 60));
+
+            if announcement.skip_if_idle > 0 && idle {
+                log::debug!("Skipping announcement {} in {}, nobody talked there recently", announcement.name, channel.name);
+
+                continue;
+            }
+
+            due.push((channel.name.clone(), announcement.clone()));
+        }
+    }
+
+    due
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeZone, Weekday};
+
+    use super::*;
+
+    #[test]
+    fn weekday_numbers_are_named() {
+        assert_eq!(weekday_names("1").as_deref(), Some("Mon"));
+        assert_eq!(weekday_names("0,7").as_deref(), Some("Sun,Sun"));
+        assert_eq!(weekday_names("1-5").as_deref(), Some("Mon-Fri"));
+        assert_eq!(weekday_names("*").as_deref(), Some("*"));
+    }
+
+    #[test]
+    fn steps_are_kept() {
+        assert_eq!(weekday_names("*/2").as_deref(), Some("*/2"));
+        assert_eq!(weekday_names("1-5/2").as_deref(), Some("Mon-Fri/2"));
+    }
+
+    #[test]
+    fn invalid_weekdays_are_rejected() {
+        assert_eq!(weekday_names("8"), None);
+        assert_eq!(weekday_names("99999999999999999999999"), None);
+        assert!(parse_cron("0 9 * * 8").is_err());
+    }
+
+    #[test]
+    fn announcements_that_never_run_are_invalid() {
+        let announcement = |cron: &str, timezone: Option<&str>| Announcement {
+            name: "rules".to_string(),
+            cron: cron.to_string(),
+            action: Default::default(),
+            text: "Be nice".to_string(),
+            timezone: timezone.map(|timezone| timezone.to_string()),
+            skip_if_idle: 0,
+        };
+
+        assert!(validate(&announcement("0 9 * * 1-5", Some("America/Sao_Paulo"))).is_ok());
+        assert!(validate(&announcement("@daily", None)).is_ok());
+        assert_eq!(validate(&announcement("0 9 * *", None)), Err("Invalid cron expression: 0 9 * *, it needs 5 fields (minute hour day month weekday)".to_string()));
+        assert_eq!(validate(&announcement("0 9 * * *", Some("Mars/Olympus"))), Err("Unknown timezone: Mars/Olympus".to_string()));
+        assert_eq!(validate(&announcement("0 0 30 2 *", None)), Err("Cron expression 0 0 30 2 * never runs".to_string()));
+    }
+
+    #[test]
+    fn weekdays_count_from_sunday() {
+        // 2026-03-01 is a Sunday
+        let after = Utc.with_ymd_and_hms(2026, 3, 1, 0, 0, 0).unwrap();
+
+        for (weekday, expected) in [("0", Weekday::Sun), ("1", Weekday::Mon), ("6", Weekday::Sat), ("7", Weekday::Sun)] {
+            let next = parse_cron(&format!("0 9 * * {}", weekday)).unwrap().after(&after).next().unwrap();
+
+            assert_eq!(next.weekday(), expected);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use regex::{Regex, RegexBuilder};
+
+use crate::config::{AutoResponderRule, TriggerType};
+use crate::irc_ext::IrcExt;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+
+pub struct AutoResponderPrivMsgEvent {
+    rules: Vec<CompiledRule>,
+    // Last time a rule was triggered, keyed by rule index and target
+    last_triggered: Mutex<HashMap<(usize, String), Instant>>,
+}
+
+struct CompiledRule {
+    rule: AutoResponderRule,
+    regex: Option<Regex>,
+}
+
+impl AutoResponderPrivMsgEvent {
+    pub fn new(rules: &[AutoResponderRule]) -> Self {
+        let mut compiled_rules: Vec<CompiledRule> = vec![];
+
+        for rule in rules {
+            if rule.replies.is_empty() {
+                log::warn!("Auto responder rule without replies: {}", rule.trigger);
+
+                continue;
+            }
+
+            let regex = if rule.trigger_type == TriggerType::Regex {
+                match RegexBuilder::new(&rule.trigger).case_insensitive(!rule.case_sensitive).build() {
+                    Ok(regex) => Some(regex),
+                    Err(e) => {
+                        log::error!("Invalid auto responder regex: {}, error: {}", rule.trigger, e);
+
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+
+            compiled_rules.push(CompiledRule {
+                rule: rule.clone(),
+                regex,
+            });
+        }
+
+        AutoResponderPrivMsgEvent {
+            rules: compiled_rules,
+            last_triggered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // What the removed iai_55chan plugin did, for configs that still enable it
+    pub fn iai_55chan() -> Self {
+        AutoResponderPrivMsgEvent::new(&[AutoResponderRule {
+            trigger: "IAI".to_string(),
+            trigger_type: TriggerType::Exact,
+            case_sensitive: true,
+            replies: vec!["DA HORA?!".to_string()],
+            channels: vec![],
+            cooldown: 0,
+        }])
+    }
+
+    fn is_cooling_down(&self, index: usize, cooldown: u64, target: &str) -> bool {
+        if cooldown == 0 {
+            return false;
+        }
+
+        let mut last_triggered = self.last_triggered.lock().unwrap();
+        let key = (index, target.to_string());
+
+        if let Some(instant) = last_triggered.get(&key) {
+            if instant.elapsed() < Duration::from_secs(cooldown) {
+                return true;
+            }
+        }
+
+        last_triggered.insert(key, Instant::now());
+
+        false
+    }
+}
+
+impl PrivMsgEvent for AutoResponderPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "auto_responder"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        let message = request.message.as_str();
+        let channel = if request.source.is_channel_name() { request.source.as_str() } else { "" };
+
+        for (index, compiled_rule) in self.rules.iter().enumerate() {
+            let rule = &compiled_rule.rule;
+
+            if !rule.channels.is_empty() && !rule.channels.iter().any(|c| request.irc_state.fold(c) == request.irc_state.fold(channel)) {
+                continue;
+            }
+
+            let reply = rule.replies.choose(&mut rand::thread_rng()).unwrap();
+
+            // Variable values are escaped so they aren't expanded as capture groups
+            let reply = reply
+                .replace("$nick", &request.user.nick.replace('$', "$$"))
+                .replace("$channel", &channel.replace('$', "$$"));
+
+            let reply = match &compiled_rule.regex {
+                Some(regex) => match regex.captures(message) {
+                    Some(captures) => {
+                        let mut expanded = String::new();
+
+                        captures.expand(&reply, &mut expanded);
+
+                        expanded
+                    }
+                    None => continue,
+                },
+                None => {
+                    let matches = match (&rule.trigger_type, rule.case_sensitive) {
+                        (TriggerType::Substring, true) => message.contains(&rule.trigger),
+                        (TriggerType::Substring, false) => message.to_lowercase().contains(&rule.trigger.to_lowercase()),
+                        (_, true) => message == rule.trigger,
+                        (_, false) => message.to_lowercase() == rule.trigger.to_lowercase(),
+                    };
+
+                    if !matches {
+                        continue;
+                    }
+
+                    reply.replace("$$", "$")
+                }
+            };
+
+            // Channels can override the cooldown of every rule
+            let cooldown = request.setting("auto_responder", "cooldown")
+                .and_then(|cooldown| cooldown.as_u64())
+                .unwrap_or(rule.cooldown);
+
+            // Another rule may still answer
+            if self.is_cooling_down(index, cooldown, &request.irc_state.fold(request.source)) {
+                log::debug!("Auto responder rule {} is cooling down for {}", rule.trigger, request.source);
+
+                continue;
+            }
+
+            return vec![PrivMsgResponse {
+                target: request.source.clone(),
+                message: reply,
+                response_type: ResponseType::PrivMsg,
+            }];
+        }
+
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privmsg::FakeRequest;
+
+    fn responder(rules: &str) -> AutoResponderPrivMsgEvent {
+        AutoResponderPrivMsgEvent::new(&serde_yaml::from_str::<Vec<AutoResponderRule>>(rules).unwrap())
+    }
+
+    fn replies(responder: &AutoResponderPrivMsgEvent, fake: &FakeRequest) -> Vec<String> {
+        responder.execute(fake.request()).into_iter().map(|response| response.message).collect()
+    }
+
+    fn reply(responder: &AutoResponderPrivMsgEvent, source: &str, nick: &str, message: &str) -> Vec<String> {
+        replies(responder, &FakeRequest::new(source, nick, message))
+    }
+
+    #[test]
+    fn exact_triggers_match_the_whole_message() {
+        let sensitive = responder("[{trigger: IAI, trigger_type: exact, case_sensitive: true, replies: ['DA HORA?!']}]");
+        let insensitive = responder("[{trigger: IAI, trigger_type: exact, replies: ['DA HORA?!']}]");
+
+        assert_eq!(reply(&sensitive, "#chan", "alice", "IAI"), vec!["DA HORA?!"]);
+        assert!(reply(&sensitive, "#chan", "alice", "iai").is_empty());
+        assert!(reply(&sensitive, "#chan", "alice", "IAI!").is_empty());
+        assert_eq!(reply(&insensitive, "#chan", "alice", "iai"), vec!["DA HORA?!"]);
+    }
+
+    #[test]
+    fn substring_triggers_match_anywhere() {
+        let sensitive = responder("[{trigger: hello, trigger_type: substring, case_sensitive: true, replies: [hi]}]");
+        let insensitive = responder("[{trigger: hello, trigger_type: substring, replies: [hi]}]");
+
+        assert_eq!(reply(&sensitive, "#chan", "alice", "oh hello there"), vec!["hi"]);
+        assert!(reply(&sensitive, "#chan", "alice", "oh HELLO there").is_empty());
+        assert_eq!(reply(&insensitive, "#chan", "alice", "oh HELLO there"), vec!["hi"]);
+        assert!(reply(&insensitive, "#chan", "alice", "hell no").is_empty());
+    }
+
+    #[test]
+    fn regex_captures_are_expanded() {
+        let responder = responder(r#"[{trigger: '^good (morning|night),? (\w+)$', trigger_type: regex, replies: ['Good $1 to you too, $nick! ($2 in $channel)']}]"#);
+
+        assert_eq!(reply(&responder, "#chan", "alice", "Good Morning, bob"), vec!["Good Morning to you too, alice! (bob in #chan)"]);
+        assert!(reply(&responder, "#chan", "alice", "good evening, bob").is_empty());
+    }
+
+    #[test]
+    fn variables_are_not_expanded_as_captures() {
+        let regex = responder(r#"[{trigger: '^(hi)$', trigger_type: regex, replies: ['$1 $nick in $channel']}]"#);
+        let exact = responder("[{trigger: hi, trigger_type: exact, replies: ['$nick pays $$5']}]");
+
+        assert_eq!(reply(&regex, "#chan$1", "a$1b", "hi"), vec!["hi a$1b in #chan$1"]);
+        assert_eq!(reply(&exact, "#chan", "a$1b", "hi"), vec!["a$1b pays $5"]);
+    }
+
+    #[test]
+    fn rules_are_scoped_to_their_channels() {
+        let responder = responder("[{trigger: hi, trigger_type: exact, replies: [hello], channels: ['#Chan[1]']}]");
+
+        assert_eq!(reply(&responder, "#chan{1}", "alice", "hi"), vec!["hello"]);
+        assert!(reply(&responder, "#other", "alice", "hi").is_empty());
+        assert!(reply(&responder, "alice", "alice", "hi").is_empty());
+    }
+
+    #[test]
+    fn cooling_down_rules_let_the_next_one_answer() {
+        let responder = responder("[
+            {trigger: hi, trigger_type: exact, replies: [first], cooldown: 60},
+            {trigger: hi, trigger_type: substring, replies: [second], cooldown: 60},
+        ]");
+
+        assert_eq!(reply(&responder, "#chan", "alice", "hi"), vec!["first"]);
+        assert_eq!(reply(&responder, "#chan", "bob", "hi"), vec!["second"]);
+        assert!(reply(&responder, "#chan", "alice", "hi").is_empty());
+        // Each channel has its own cooldown
+        assert_eq!(reply(&responder, "#other", "alice", "hi"), vec!["first"]);
+    }
+
+    #[test]
+    fn channels_can_override_the_cooldown() {
+        let responder = responder("[{trigger: hi, trigger_type: exact, replies: [hello], cooldown: 60}]");
+        let mut fake = FakeRequest::new("#chan", "alice", "hi");
+
+        fake.server.channels[0].settings.insert("auto_responder".to_string(), serde_yaml::from_str("{cooldown: 0}").unwrap());
+
+        assert_eq!(replies(&responder, &fake), vec!["hello"]);
+        assert_eq!(replies(&responder, &fake), vec!["hello"]);
+    }
+}
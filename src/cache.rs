@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+struct CacheEntry<V> {
+    value: V,
+    inserted: Instant,
+    // Position in the usage order, the lowest one is evicted first
+    used: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+// Least recently used entries are evicted once it's full, entries older than the TTL are misses
+pub struct LruCache<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<K, CacheEntry<V>>,
+    usage: BTreeMap<u64, K>,
+    next_use: u64,
+    stats: CacheStats,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    // A capacity of 0 disables the cache
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        LruCache {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            next_use: 0,
+            stats: CacheStats { capacity, ..Default::default() },
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry.inserted.elapsed() >= self.ttl,
+            None => {
+                self.stats.misses += 1;
+
+                return None;
+            }
+        };
+
+        if expired {
+            self.remove(key);
+            self.stats.misses += 1;
+
+            return None;
+        }
+
+        self.next_use += 1;
+        self.stats.hits += 1;
+
+        let entry = self.entries.get_mut(key).unwrap();
+
+        self.usage.remove(&entry.used);
+        self.usage.insert(self.next_use, key.clone());
+        entry.used = self.next_use;
+
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.usage.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+
+            if let Some(key) = self.usage.remove(&oldest) {
+                self.entries.remove(&key);
+                self.stats.evictions += 1;
+            }
+        }
+
+        self.next_use += 1;
+        self.usage.insert(self.next_use, key.clone());
+        self.entries.insert(key, CacheEntry { value, inserted: Instant::now(), used: self.next_use });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.usage.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { entries: self.entries.len(), ..self.stats }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.usage.remove(&entry.used);
+        }
+    }
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = LruCache::new(2, Duration::from_secs(60));
+
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+
+        // a was used after b, so b goes first
+        assert_eq!(cache.get(&"a"), Some(1));
+
+        cache.insert("c", 3);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn inserting_again_replaces_without_evicting() {
+        let mut cache = LruCache::new(2, Duration::from_secs(60));
+
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("a", 3);
+
+        assert_eq!(cache.get(&"a"), Some(3));
+        assert_eq!(cache.get(&"b"), Some(2));
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let mut cache = LruCache::new(2, Duration::ZERO);
+
+        cache.insert("a", 1);
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn zero_capacity_disables_it() {
+        let mut cache = LruCache::new(0, Duration::from_secs(60));
+
+        cache.insert("a", 1);
+
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn hit_rate_is_a_percentage() {
+        let mut cache = LruCache::new(2, Duration::from_secs(60));
+
+        assert_eq!(cache.stats().hit_rate(), 0.0);
+
+        cache.insert("a", 1);
+        cache.get(&"a");
+        cache.get(&"a");
+        cache.get(&"a");
+        cache.get(&"b");
+
+        assert_eq!(cache.stats().hit_rate(), 75.0);
+
+        cache.clear();
+
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().hits, 3);
+    }
+}
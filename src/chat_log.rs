@@ -0,0 +1,326 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use serde_json::{json, Map, Value};
+use simple_irc::{Message, Prefix};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::irc_ext::IrcExt;
+use crate::irc_state::CaseMapping;
+
+pub enum LogEvent<'a> {
+    Message(&'a str),
+    Action(&'a str),
+    Notice(&'a str),
+    Join,
+    Part { reason: Option<&'a str> },
+    Quit { reason: Option<&'a str> },
+    Kick { nick: &'a str, reason: Option<&'a str> },
+    Nick { new_nick: &'a str },
+    Topic(&'a str),
+    Mode(String),
+}
+
+// Something that happened in a channel, or in the private conversation with the nick in target
+pub struct LogEntry<'a> {
+    pub time: DateTime<Utc>,
+    pub target: &'a str,
+    pub user: &'a Prefix,
+    pub event: LogEvent<'a>,
+}
+
+impl LogEvent<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            LogEvent::Message(_) => "message",
+            LogEvent::Action(_) => "action",
+            LogEvent::Notice(_) => "notice",
+            LogEvent::Join => "join",
+            LogEvent::Part { .. } => "part",
+            LogEvent::Quit { .. } => "quit",
+            LogEvent::Kick { .. } => "kick",
+            LogEvent::Nick { .. } => "nick",
+            LogEvent::Topic(_) => "topic",
+            LogEvent::Mode(_) => "mode",
+        }
+    }
+}
+
+// From server-time when the server sent it, otherwise when it was received
+pub fn message_time(message: &Message) -> DateTime<Utc> {
+    message.tags.get("time")
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now)
+}
+
+// PRIVMSG and NOTICE text, CTCPs other than ACTION aren't logged
+pub fn text_event<'a>(command: &str, text: &'a str) -> Option<LogEvent<'a>> {
+    if command == "PRIVMSG" {
+        if let Some(action) = text.strip_prefix("\u{1}ACTION").filter(|action| action.is_empty() || action.starts_with(' ') || action.starts_with('\u{1}')) {
+            return Some(LogEvent::Action(action.trim_end_matches('\u{1}').trim_start()));
+        }
+    }
+
+    if text.is_ctcp() {
+        return None;
+    }
+
+    match command {
+        "PRIVMSG" => Some(LogEvent::Message(text)),
+        "NOTICE" => Some(LogEvent::Notice(text)),
+        _ => None,
+    }
+}
+
+// Returns whether the entry was logged, excluded channels and users aren't. Each target is logged to the directory of its folded name
+pub fn write(config: &LoggingConfig, network: &str, casemapping: CaseMapping, entry: &LogEntry) -> bool {
+    if !config.enabled || (!config.private && !entry.target.is_channel_name()) {
+        return false;
+    }
+
+    let hostmask = entry.user.to_string();
+    let target = casemapping.fold(entry.target);
+
+    if config.exclude_channels.iter().any(|excluded| casemapping.fold(excluded) == target)
+        || config.exclude_users.iter().any(|mask| hostmask.matches_mask(mask)) {
+        return false;
+    }
+
+    let timezone = config.timezone.parse::<Tz>().unwrap_or(Tz::UTC);
+    let time = entry.time.with_timezone(&timezone);
+    let directory = PathBuf::from(&config.directory).join(file_name(network)).join(file_name(&target));
+
+    for format in &config.formats {
+        let (extension, line) = match format {
+            LogFormat::Text => ("log", format!("{} {}", time.format("%H:%M:%S"), text(entry))),
+            LogFormat::Jsonl => ("jsonl", jsonl(entry, time.to_rfc3339_opts(SecondsFormat::Millis, true))),
+            LogFormat::Irccloud => ("txt", format!("[{}] {}", time.format("%Y-%m-%d %H:%M:%S"), irccloud(entry))),
+        };
+
+        let path = directory.join(format!("{}.{}", time.format("%Y-%m-%d"), extension));
+
+        if let Err(e) = append(&path, &line) {
+            log::error!("Couldn't write to {}: {}", path.display(), e);
+        }
+    }
+
+    true
+}
+
+fn append(path: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    writeln!(file, "{}", line)
+}
+
+// Channels and nicks may have characters that aren't allowed in file names
+fn file_name(name: &str) -> String {
+    name.chars().map(|c| if "/\\:*?\"<>|".contains(c) || c.is_control() { '_' } else { c }).collect()
+}
+
+fn userhost(user: &Prefix) -> String {
+    format!("{}@{}", user.user.as_deref().unwrap_or("*"), user.host.as_deref().unwrap_or("*"))
+}
+
+fn text(entry: &LogEntry) -> String {
+    let nick = &entry.user.nick;
+
+    match &entry.event {
+        LogEvent::Message(text) => format!("<{}> {}", nick, text),
+        LogEvent::Action(text) => format!(" * {} {}", nick, text),
+        LogEvent::Notice(text) => format!("-{}:{}- {}", nick, entry.target, text),
+        LogEvent::Join => format!("-!- {} [{}] has joined {}", nick, userhost(entry.user), entry.target),
+        LogEvent::Part { reason } => format!("-!- {} [{}] has left {} [{}]", nick, userhost(entry.user), entry.target, reason.unwrap_or_default()),
+        LogEvent::Quit { reason } => format!("-!- {} [{}] has quit [{}]", nick, userhost(entry.user), reason.unwrap_or_default()),
+        LogEvent::Kick { nick: kicked, reason } => format!("-!- {} was kicked from {} by {} [{}]", kicked, entry.target, nick, reason.unwrap_or_default()),
+        LogEvent::Nick { new_nick } => format!("-!- {} is now known as {}", nick, new_nick),
+        LogEvent::Topic(topic) => format!("-!- {} changed the topic of {} to: {}", nick, entry.target, topic),
+        LogEvent::Mode(modes) => format!("-!- mode/{} [{}] by {}", entry.target, modes, nick),
+    }
+}
+
+fn irccloud(entry: &LogEntry) -> String {
+    let nick = &entry.user.nick;
+    let reason = |reason: &Option<&str>| reason.filter(|reason| !reason.is_empty()).map(|reason| format!(": {}", reason)).unwrap_or_default();
+
+    match &entry.event {
+        LogEvent::Message(text) => format!("<{}> {}", nick, text),
+        LogEvent::Action(text) => format!("— {} {}", nick, text),
+        LogEvent::Notice(text) => format!("-{}- {}", nick, text),
+        LogEvent::Join => format!("→ {} joined ({})", nick, userhost(entry.user)),
+        LogEvent::Part { reason: part_reason } => format!("← {} left ({}){}", nick, userhost(entry.user), reason(part_reason)),
+        LogEvent::Quit { reason: quit_reason } => format!("⇐ {} quit ({}){}", nick, userhost(entry.user), reason(quit_reason)),
+        LogEvent::Kick { nick: kicked, reason: kick_reason } => format!("← {} was kicked by {}{}", kicked, nick, reason(kick_reason)),
+        LogEvent::Nick { new_nick } => format!("{} is now known as {}", nick, new_nick),
+        LogEvent::Topic(topic) => format!("{} set the topic to: {}", nick, topic),
+        LogEvent::Mode(modes) => format!("{} set {}", nick, modes),
+    }
+}
+
+fn jsonl(entry: &LogEntry, time: String) -> String {
+    let mut object = Map::new();
+
+    object.insert("time".to_string(), json!(time));
+    object.insert("type".to_string(), json!(entry.event.name()));
+    object.insert("target".to_string(), json!(entry.target));
+    object.insert("nick".to_string(), json!(entry.user.nick));
+    object.insert("user".to_string(), json!(entry.user.user));
+    object.insert("host".to_string(), json!(entry.user.host));
+
+    let (key, value) = match &entry.event {
+        LogEvent::Message(text) | LogEvent::Action(text) | LogEvent::Notice(text) => ("text", json!(text)),
+        LogEvent::Join => ("", Value::Null),
+        LogEvent::Part { reason } | LogEvent::Quit { reason } => ("reason", json!(reason)),
+        LogEvent::Kick { nick, reason } => {
+            object.insert("kicked".to_string(), json!(nick));
+
+            ("reason", json!(reason))
+        }
+        LogEvent::Nick { new_nick } => ("new_nick", json!(new_nick)),
+        LogEvent::Topic(topic) => ("topic", json!(topic)),
+        LogEvent::Mode(modes) => ("modes", json!(modes)),
+    };
+
+    if !key.is_empty() {
+        object.insert(key.to_string(), value);
+    }
+
+    Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::log_index::{self, Search};
+    use crate::privmsg::FakeRequest;
+    use crate::storage::Storage;
+
+    fn logged(command: &str, text: &str) -> Option<(&'static str, String)> {
+        text_event(command, text).map(|event| {
+            let text = match &event {
+                LogEvent::Message(text) | LogEvent::Action(text) | LogEvent::Notice(text) => text.to_string(),
+                _ => "".to_string(),
+            };
+
+            (event.name(), text)
+        })
+    }
+
+    #[test]
+    fn actions_are_logged_and_other_ctcps_are_not() {
+        assert_eq!(logged("PRIVMSG", "hi there"), Some(("message", "hi there".to_string())));
+        assert_eq!(logged("PRIVMSG", "\u{1}ACTION waves\u{1}"), Some(("action", "waves".to_string())));
+        // Some clients leave out the closing \x01
+        assert_eq!(logged("PRIVMSG", "\u{1}ACTION waves"), Some(("action", "waves".to_string())));
+        assert_eq!(logged("PRIVMSG", "\u{1}ACTION\u{1}"), Some(("action", "".to_string())));
+        assert_eq!(logged("PRIVMSG", "\u{1}ACTIONS\u{1}"), None);
+        assert_eq!(logged("PRIVMSG", "\u{1}VERSION\u{1}"), None);
+        assert_eq!(logged("NOTICE", "\u{1}VERSION irssi\u{1}"), None);
+        assert_eq!(logged("NOTICE", "\u{1}ACTION waves\u{1}"), None);
+        assert_eq!(logged("NOTICE", "hi there"), Some(("notice", "hi there".to_string())));
+        assert_eq!(logged("TOPIC", "hi there"), None);
+    }
+
+    // Written in every format and indexed back from each of them on its own
+    #[test]
+    fn every_format_is_read_back_by_the_index() {
+        let directory = std::env::temp_dir().join(format!("chat-log-{}", std::process::id()));
+        let time = Utc.with_ymd_and_hms(2024, 1, 2, 13, 30, 0).unwrap();
+        let alice = Prefix::new_with_all("alice", Some("a"), Some("alice.example"));
+        let bob = Prefix::new_with_all("Bob[m]", Some("b"), Some("bob.example"));
+        let entries = [
+            LogEntry { time, target: "#Rust", user: &alice, event: LogEvent::Join },
+            LogEntry { time, target: "#Rust", user: &alice, event: LogEvent::Message("hi <there>") },
+            LogEntry { time: time + chrono::Duration::seconds(5), target: "#rust", user: &bob, event: LogEvent::Action("waves at alice") },
+            LogEntry { time, target: "#Rust", user: &alice, event: LogEvent::Part { reason: Some("bye") } },
+        ];
+
+        for (format, extension, lines) in [
+            (LogFormat::Text, "log", vec![
+                "10:30:00 -!- alice [a@alice.example] has joined #Rust",
+                "10:30:00 <alice> hi <there>",
+                "10:30:05  * Bob[m] waves at alice",
+                "10:30:00 -!- alice [a@alice.example] has left #Rust [bye]",
+            ]),
+            (LogFormat::Irccloud, "txt", vec![
+                "[2024-01-02 10:30:00] → alice joined (a@alice.example)",
+                "[2024-01-02 10:30:00] <alice> hi <there>",
+                "[2024-01-02 10:30:05] — Bob[m] waves at alice",
+                "[2024-01-02 10:30:00] ← alice left (a@alice.example): bye",
+            ]),
+            (LogFormat::Jsonl, "jsonl", vec![
+                r##"{"host":"alice.example","nick":"alice","target":"#Rust","time":"2024-01-02T10:30:00.000-03:00","type":"join","user":"a"}"##,
+                r##"{"host":"alice.example","nick":"alice","target":"#Rust","text":"hi <there>","time":"2024-01-02T10:30:00.000-03:00","type":"message","user":"a"}"##,
+                r##"{"host":"bob.example","nick":"Bob[m]","target":"#rust","text":"waves at alice","time":"2024-01-02T10:30:05.000-03:00","type":"action","user":"b"}"##,
+                r##"{"host":"alice.example","nick":"alice","reason":"bye","target":"#Rust","time":"2024-01-02T10:30:00.000-03:00","type":"part","user":"a"}"##,
+            ]),
+        ] {
+            let mut server = FakeRequest::new("#rust", "alice", "").server;
+
+            server.logging = LoggingConfig {
+                enabled: true,
+                directory: directory.join(extension).to_string_lossy().to_string(),
+                formats: vec![format],
+                timezone: "America/Sao_Paulo".to_string(),
+                ..Default::default()
+            };
+
+            for entry in &entries {
+                assert!(write(&server.logging, &server.hostname, CaseMapping::Rfc1459, entry));
+            }
+
+            // Both spellings of the channel end up in the same file
+            let path = directory.join(extension).join("irc.example.net").join("#rust").join(format!("2024-01-02.{}", extension));
+
+            assert_eq!(fs::read_to_string(&path).unwrap().lines().collect::<Vec<_>>(), lines);
+
+            let storage = Storage::open(&crate::config::StorageConfig { path: ":memory:".to_string() }).unwrap();
+
+            storage.migrate("log_index", log_index::MIGRATIONS).unwrap();
+
+            assert_eq!(log_index::rebuild(&storage, &server).unwrap(), 2);
+
+            let search = Search { channel: "#rust".to_string(), oldest_first: true, limit: 10, ..Default::default() };
+            let messages: Vec<(String, bool, String, i64)> = log_index::search(&storage, &server.hostname, &search).unwrap().into_iter()
+                .map(|message| (message.nick, message.action, message.text, message.time))
+                .collect();
+
+            assert_eq!(messages, vec![
+                ("alice".to_string(), false, "hi <there>".to_string(), time.timestamp()),
+                ("Bob[m]".to_string(), true, "waves at alice".to_string(), time.timestamp() + 5),
+            ]);
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn excluded_channels_and_users_are_not_logged() {
+        let alice = Prefix::new_with_all("alice", Some("a"), Some("alice.example"));
+        let config = LoggingConfig {
+            enabled: true,
+            directory: std::env::temp_dir().join(format!("chat-log-excluded-{}", std::process::id())).to_string_lossy().to_string(),
+            exclude_channels: vec!["#Secret[ops]".to_string()],
+            exclude_users: vec!["*!*@alice.example".to_string()],
+            ..Default::default()
+        };
+        let entry = |target, user| LogEntry { time: Utc::now(), target, user, event: LogEvent::Join };
+        let bob = Prefix::new_with_all("bob", Some("b"), Some("bob.example"));
+
+        assert!(!write(&config, "irc.example.net", CaseMapping::Rfc1459, &entry("#secret{OPS}", &bob)));
+        assert!(!write(&config, "irc.example.net", CaseMapping::Rfc1459, &entry("#rust", &alice)));
+        // Private messages are only logged when enabled
+        assert!(!write(&config, "irc.example.net", CaseMapping::Rfc1459, &entry("bob", &bob)));
+        assert!(!Path::new(&config.directory).exists());
+    }
+}
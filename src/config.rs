@@ -1,8 +1,21 @@
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Serialize, Deserialize};
 
+use crate::irc_state::CaseMapping;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IrcConfig {
     pub servers: Vec<Server>,
+    // Shared by every server
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+    // Used to connect to the servers and by plugins
+    #[serde(default)]
+    pub dns: DnsConfig,
+    // Where plugins keep their data
+    #[serde(default)]
+    pub storage: StorageConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -26,6 +39,47 @@ pub struct Server {
     #[serde(default)]
     pub channels: Vec<ChannelConfig>,
     pub privmsg_plugins: Vec<String>,
+    // Plugins allowed to answer private messages, all of them if not set
+    #[serde(default)]
+    pub private_plugins: Option<Vec<String>>,
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    // Hostmasks (nick!user@host, wildcards allowed) allowed to run admin commands
+    #[serde(default)]
+    pub admins: Vec<String>,
+    // Where runtime changes made by admins are stored, defaults to runtime-<hostname>.yml
+    #[serde(default)]
+    pub runtime_config: Option<String>,
+    // Hostmasks whose messages are ignored
+    #[serde(default)]
+    pub ignores: Vec<String>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub dcc: DccConfig,
+    #[serde(default)]
+    pub script: ScriptConfig,
+    #[serde(default)]
+    pub auto_responder: Vec<AutoResponderRule>,
+    #[serde(default)]
+    pub dns: DnsPluginConfig,
+    #[serde(default)]
+    pub tell: TellConfig,
+    #[serde(default)]
+    pub remind: RemindConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub karma: KarmaConfig,
+    // How the server compares nicks and channels until it sends CASEMAPPING, and what channel settings, reindex and quotes use
+    #[serde(default = "default_casemapping")]
+    pub casemapping: CaseMapping,
+    // Changed by admins at runtime and kept in the runtime config, keyed by folded channel name
+    #[serde(skip)]
+    pub channel_overrides: BTreeMap<String, ChannelOverrides>,
+    // The channels with overrides, with them applied over config.yml
+    #[serde(skip)]
+    overridden_channels: Vec<ChannelConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -47,10 +101,601 @@ pub struct CtcpConfig {
     pub enabled: Vec<String>,
     pub version: String,
     pub source: String,
+    // Answer CTCPs sent to channels, not only the ones sent directly to the bot
+    #[serde(default)]
+    pub allow_channel: bool,
+    // CTCP command -> reply template, overrides the built-in replies and adds custom commands
+    #[serde(default)]
+    pub replies: HashMap<String, String>,
+    #[serde(default)]
+    pub rate_limit: CtcpRateLimitConfig,
+}
+
+// Global limit for all CTCPs, anything above it is silently dropped
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CtcpRateLimitConfig {
+    pub requests: usize,
+    pub period: u64,
+}
+
+impl Default for CtcpRateLimitConfig {
+    fn default() -> Self {
+        CtcpRateLimitConfig {
+            requests: 5,
+            period: 10,
+        }
+    }
+}
+
+impl CtcpConfig {
+    pub fn available_commands(&self) -> Vec<String> {
+        let mut commands: Vec<String> = self.enabled.iter().chain(self.replies.keys()).map(|command| command.to_uppercase()).collect();
+
+        commands.sort();
+        commands.dedup();
+
+        commands
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChannelConfig {
     pub name: String,
     pub password: String,
+    #[serde(default)]
+    pub command_prefix: Option<String>,
+    // Only these plugins are enabled in the channel, all of them if not set
+    #[serde(default)]
+    pub plugins_enabled: Option<Vec<String>>,
+    #[serde(default)]
+    pub plugins_disabled: Vec<String>,
+    // Plugin name -> setting name -> value
+    #[serde(default)]
+    pub settings: HashMap<String, HashMap<String, serde_yaml::Value>>,
+    #[serde(default)]
+    pub announcements: Vec<Announcement>,
+}
+
+// Only what admins changed, so later edits to config.yml still apply to everything else
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChannelOverrides {
+    pub command_prefix: Option<String>,
+    // Plugin -> whether it's enabled
+    pub plugins: BTreeMap<String, bool>,
+    // Plugin -> setting name -> value, null removes the setting
+    pub settings: BTreeMap<String, BTreeMap<String, Option<serde_yaml::Value>>>,
+    // Announcement name -> announcement, null removes the announcement
+    pub announcements: BTreeMap<String, Option<Announcement>>,
+}
+
+impl ChannelOverrides {
+    fn apply(&self, channel: &mut ChannelConfig) {
+        if let Some(command_prefix) = &self.command_prefix {
+            channel.command_prefix = Some(command_prefix.clone());
+        }
+
+        for (plugin, enabled) in &self.plugins {
+            channel.plugins_disabled.retain(|p| p != plugin);
+
+            if let Some(plugins_enabled) = &mut channel.plugins_enabled {
+                plugins_enabled.retain(|p| p != plugin);
+
+                if *enabled {
+                    plugins_enabled.push(plugin.clone());
+                }
+            } else if !enabled {
+                channel.plugins_disabled.push(plugin.clone());
+            }
+        }
+
+        for (plugin, settings) in &self.settings {
+            let channel_settings = channel.settings.entry(plugin.clone()).or_default();
+
+            for (key, value) in settings {
+                match value {
+                    Some(value) => channel_settings.insert(key.clone(), value.clone()),
+                    None => channel_settings.remove(key),
+                };
+            }
+        }
+
+        for (name, announcement) in &self.announcements {
+            channel.announcements.retain(|existing| existing.name != *name);
+            channel.announcements.extend(announcement.clone());
+        }
+    }
+}
+
+// Said in the channel on a schedule, only while the bot is in it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announcement {
+    pub name: String,
+    // Standard cron expression (minute hour day month weekday) or @hourly, @daily, @weekly, @monthly or @yearly
+    pub cron: String,
+    #[serde(default)]
+    pub action: AnnouncementAction,
+    // The message, topic or command with its prefix
+    pub text: String,
+    // Of the cron expression, UTC if not set
+    #[serde(default)]
+    pub timezone: Option<String>,
+    // Skipped when nobody talked in the channel for this many minutes, 0 never skips
+    #[serde(default)]
+    pub skip_if_idle: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnouncementAction {
+    #[default]
+    Message,
+    Notice,
+    Action,
+    Topic,
+    // Runs the command as if the bot sent it to the channel
+    Command,
+}
+
+impl AnnouncementAction {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "message" => Some(AnnouncementAction::Message),
+            "notice" => Some(AnnouncementAction::Notice),
+            "action" => Some(AnnouncementAction::Action),
+            "topic" => Some(AnnouncementAction::Topic),
+            "command" => Some(AnnouncementAction::Command),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnnouncementAction::Message => "message",
+            AnnouncementAction::Notice => "notice",
+            AnnouncementAction::Action => "action",
+            AnnouncementAction::Topic => "topic",
+            AnnouncementAction::Command => "command",
+        }
+    }
+}
+
+impl ChannelConfig {
+    pub fn new(name: &str) -> Self {
+        ChannelConfig {
+            name: name.to_string(),
+            password: "".to_string(),
+            command_prefix: None,
+            plugins_enabled: None,
+            plugins_disabled: vec![],
+            settings: HashMap::new(),
+            announcements: vec![],
+        }
+    }
+
+    pub fn is_plugin_enabled(&self, plugin: &str) -> bool {
+        if self.plugins_disabled.iter().any(|p| p == plugin) {
+            return false;
+        }
+
+        match &self.plugins_enabled {
+            Some(plugins) => plugins.iter().any(|p| p == plugin),
+            None => true,
+        }
+    }
+}
+
+impl Server {
+    pub fn channel(&self, name: &str) -> Option<&ChannelConfig> {
+        let name = self.casemapping.fold(name);
+
+        self.overridden_channels.iter().chain(&self.channels).find(|channel| self.casemapping.fold(&channel.name) == name)
+    }
+
+    // Channels of config.yml and the ones only configured at runtime, which aren't joined
+    pub fn all_channels(&self) -> impl Iterator<Item = &ChannelConfig> {
+        let overridden = &self.overridden_channels;
+
+        overridden.iter().chain(self.channels.iter().filter(move |channel| !overridden.iter().any(|other| self.casemapping.fold(&other.name) == self.casemapping.fold(&channel.name))))
+    }
+
+    pub fn override_channel(&mut self, name: &str, change: impl FnOnce(&mut ChannelOverrides)) {
+        change(self.channel_overrides.entry(self.casemapping.fold(name)).or_default());
+
+        self.apply_overrides();
+    }
+
+    pub fn apply_overrides(&mut self) {
+        self.overridden_channels = self.channel_overrides.iter().map(|(name, overrides)| {
+            let mut channel = self.channels.iter()
+                .find(|channel| self.casemapping.fold(&channel.name) == *name)
+                .cloned()
+                .unwrap_or_else(|| ChannelConfig::new(name));
+
+            overrides.apply(&mut channel);
+
+            channel
+        }).collect();
+    }
+
+    pub fn command_prefix<'a>(&'a self, channel: Option<&'a ChannelConfig>) -> &'a str {
+        channel.and_then(|channel| channel.command_prefix.as_deref()).unwrap_or(&self.command_prefix)
+    }
+
+    pub fn is_plugin_enabled(&self, channel: Option<&ChannelConfig>, plugin: &str) -> bool {
+        match channel {
+            Some(channel) => channel.is_plugin_enabled(plugin),
+            None => match &self.private_plugins {
+                Some(plugins) => plugins.iter().any(|p| p == plugin),
+                None => true,
+            },
+        }
+    }
+
+    pub fn runtime_config_path(&self) -> String {
+        self.runtime_config.clone().unwrap_or_else(|| format!("runtime-{}.yml", self.hostname))
+    }
+}
+
+fn default_command_prefix() -> String {
+    ".".to_string()
+}
+
+// What servers not sending CASEMAPPING use
+fn default_casemapping() -> CaseMapping {
+    CaseMapping::Rfc1459
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ScriptConfig {
+    pub directory: String,
+    pub reload_interval: u64,
+    pub max_execution_time: u64,
+    pub max_operations: u64,
+    pub max_call_levels: usize,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+    pub max_map_size: usize,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        ScriptConfig {
+            directory: "plugins".to_string(),
+            reload_interval: 2,
+            max_execution_time: 500,
+            max_operations: 1_000_000,
+            max_call_levels: 32,
+            max_string_size: 4096,
+            max_array_size: 1024,
+            max_map_size: 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // Commands allowed per user every user_period seconds
+    pub user_requests: usize,
+    pub user_period: u64,
+    // Commands allowed per channel every channel_period seconds
+    pub channel_requests: usize,
+    pub channel_period: u64,
+    // Seconds before the same user can use the same command again
+    pub command_cooldown: u64,
+    // Rate limit violations before the user is temporarily ignored
+    pub ignore_threshold: u32,
+    pub ignore_duration: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            user_requests: 5,
+            user_period: 30,
+            channel_requests: 10,
+            channel_period: 30,
+            command_cooldown: 3,
+            ignore_threshold: 5,
+            ignore_duration: 600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DccConfig {
+    pub enabled: bool,
+    // Address sent in DCC offers, defaults to bind_address
+    pub public_address: Option<String>,
+    pub bind_address: String,
+    pub port_start: u16,
+    pub port_end: u16,
+    // Seconds to wait for the other side to connect
+    pub timeout: u64,
+    // Ask the user to listen when the bot starts a DCC, for when the bot is behind NAT
+    pub passive: bool,
+    // Files that can be sent with DCC SEND
+    pub send_directory: String,
+    pub max_transfers: usize,
+    pub max_transfers_per_user: usize,
+    // Asked for when a DCC CHAT connection can't be checked against the host of the admin, like with cloaks
+    pub chat_password: Option<String>,
+}
+
+impl Default for DccConfig {
+    fn default() -> Self {
+        DccConfig {
+            enabled: false,
+            public_address: None,
+            bind_address: "0.0.0.0".to_string(),
+            port_start: 50000,
+            port_end: 50010,
+            timeout: 60,
+            passive: false,
+            send_directory: "files".to_string(),
+            max_transfers: 5,
+            max_transfers_per_user: 1,
+            chat_password: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerType {
+    Exact,
+    Substring,
+    Regex,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AutoResponderRule {
+    pub trigger: String,
+    pub trigger_type: TriggerType,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    pub replies: Vec<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub cooldown: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    pub asn_database: String,
+    pub city_database: String,
+    // Optional GeoIP2/GeoLite2 databases, used when configured
+    pub country_database: Option<String>,
+    pub anonymous_ip_database: Option<String>,
+    pub isp_database: Option<String>,
+    pub connection_type_database: Option<String>,
+    pub domain_database: Option<String>,
+    // Seconds between checks for updated databases, 0 disables reloading
+    pub reload_interval: u64,
+    pub mmap: bool,
+    // Preferred languages for place names, can be changed per channel with the languages setting
+    pub languages: Vec<String>,
+    pub cache: GeoIpCacheConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GeoIpCacheConfig {
+    // Entries kept in each of the hostname, PTR and lookup caches, 0 disables caching
+    pub size: usize,
+    // Seconds hostname and PTR answers are kept
+    pub dns_ttl: u64,
+    // Seconds database lookups are kept, they're also dropped when the databases are reloaded
+    pub lookup_ttl: u64,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        GeoIpConfig {
+            asn_database: "GeoLite2-ASN.mmdb".to_string(),
+            city_database: "GeoLite2-City.mmdb".to_string(),
+            country_database: None,
+            anonymous_ip_database: None,
+            isp_database: None,
+            connection_type_database: None,
+            domain_database: None,
+            reload_interval: 60,
+            mmap: true,
+            languages: vec!["en".to_string()],
+            cache: GeoIpCacheConfig { ..Default::default() },
+        }
+    }
+}
+
+impl Default for GeoIpCacheConfig {
+    fn default() -> Self {
+        GeoIpCacheConfig {
+            size: 1024,
+            dns_ttl: 300,
+            lookup_ttl: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DnsConfig {
+    // Queried directly, as ip or ip:port. The system resolver is used when empty
+    pub nameservers: Vec<String>,
+    // Seconds to wait for each query
+    pub timeout: u64,
+    pub prefer: IpPreference,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            nameservers: vec![],
+            timeout: 5,
+            prefer: IpPreference::Any,
+        }
+    }
+}
+
+// For the dns plugin, the global resolver is used when no nameservers are set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DnsPluginConfig {
+    pub nameservers: Vec<String>,
+    // Asks the nameserver to validate the answers, shown with the records
+    pub dnssec: bool,
+}
+
+impl Default for DnsPluginConfig {
+    fn default() -> Self {
+        DnsPluginConfig {
+            nameservers: vec![],
+            dnssec: true,
+        }
+    }
+}
+
+// Order of the addresses a hostname resolves to, or which ones are used at all
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    Any,
+    Ipv4,
+    Ipv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    // SQLite database, created when missing
+    pub path: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            path: "bot.db".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TellConfig {
+    // Where messages are delivered when the recipient talks or joins a channel, private messages are always answered privately
+    pub delivery: TellDelivery,
+    // Messages waiting for each recipient
+    pub max_messages: usize,
+    // Messages are delivered to the services account the recipient was logged in to, not to whoever uses the nick
+    pub use_account: bool,
+}
+
+impl Default for TellConfig {
+    fn default() -> Self {
+        TellConfig {
+            delivery: TellDelivery::Channel,
+            max_messages: 10,
+            use_account: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TellDelivery {
+    Channel,
+    Private,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RemindConfig {
+    // For users who didn't set theirs with .remind tz
+    pub timezone: String,
+    // Pending reminders each user can create
+    pub max_reminders: usize,
+    // Longest a reminder can be set ahead, in days
+    pub max_days: i64,
+}
+
+impl Default for RemindConfig {
+    fn default() -> Self {
+        RemindConfig {
+            timezone: "UTC".to_string(),
+            max_reminders: 10,
+            max_days: 365,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KarmaConfig {
+    // Seconds before the same user can change the karma of the same thing again
+    pub cooldown: u64,
+    // Karma changes each user can make in the period, in seconds
+    pub max_changes: usize,
+    pub period: u64,
+}
+
+impl Default for KarmaConfig {
+    fn default() -> Self {
+        KarmaConfig {
+            cooldown: 60,
+            max_changes: 5,
+            period: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub enabled: bool,
+    // Files are written to <directory>/<hostname>/<channel or nick>/<YYYY-MM-DD>.<extension>
+    pub directory: String,
+    pub formats: Vec<LogFormat>,
+    // Of the timestamps and of when a new file is started
+    pub timezone: String,
+    // Private messages, in a directory named after the other nick
+    pub private: bool,
+    pub exclude_channels: Vec<String>,
+    // Hostmasks (nick!user@host, wildcards allowed)
+    pub exclude_users: Vec<String>,
+    // Channel messages are also indexed in the storage, so they can be searched with the log_search plugin
+    pub index: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            enabled: false,
+            directory: "logs".to_string(),
+            formats: vec![LogFormat::Text],
+            timezone: "UTC".to_string(),
+            private: false,
+            exclude_channels: vec![],
+            exclude_users: vec![],
+            index: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // irssi and weechat style, <directory>/.../<date>.log
+    Text,
+    // One JSON object per line, <date>.jsonl
+    Jsonl,
+    // Like IRCCloud log exports, <date>.txt
+    Irccloud,
 }
@@ -1,13 +1,11 @@
 use simple_irc::Prefix;
 
 use crate::config::Server;
-use crate::irc_state::IrcState;
 use std::time::SystemTime;
 use chrono::{DateTime, Utc};
 
 pub struct CtcpRequest<'a> {
     pub server: &'a Server,
-    pub irc_state: &'a IrcState,
     pub user: &'a Prefix,
     pub source: &'a String,
     pub command: &'a String,
@@ -39,11 +37,16 @@ pub struct TimeCtcpResponse {}
 
 pub struct UserInfoCtcpResponse {}
 
+pub struct TemplateCtcpResponse {
+    pub command: String,
+    pub template: String,
+}
+
 impl CtcpEvent for VersionCtcpResponse {
     fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
         if request.command.eq("VERSION") {
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("VERSION {}", request.server.ctcp.version),
             });
         }
@@ -56,7 +59,7 @@ impl CtcpEvent for PingCtcpResponse {
     fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
         if request.command.eq("PING") {
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("PING {}", request.message),
             });
         }
@@ -69,7 +72,7 @@ impl CtcpEvent for ClientInfoCtcpResponse {
     fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
         if request.command.eq("CLIENTINFO") {
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("CLIENTINFO {}", self.available_ctcp.join(" ")),
             });
         }
@@ -82,7 +85,7 @@ impl CtcpEvent for FingerCtcpResponse {
     fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
         if request.command.eq("FINGER") {
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("FINGER {}", request.server.ctcp.version),
             });
         }
@@ -95,7 +98,7 @@ impl CtcpEvent for SourceCtcpResponse {
     fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
         if request.command.eq("SOURCE") {
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("SOURCE {}", request.server.ctcp.source),
             });
         }
@@ -110,7 +113,7 @@ impl CtcpEvent for TimeCtcpResponse {
             let datetime: DateTime<Utc> = SystemTime::now().into();
 
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("TIME {}", datetime.format("%c")),
             });
         }
@@ -123,11 +126,35 @@ impl CtcpEvent for UserInfoCtcpResponse {
     fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
         if request.command.eq("USERINFO") {
             return Some(CtcpResponse {
-                target: request.source.clone(),
+                target: request.user.nick.clone(),
                 message: format!("USERINFO {} ({})", request.server.user_data.nickname, request.server.user_data.realname),
             });
         }
 
+        None
+    }
+}
+
+impl CtcpEvent for TemplateCtcpResponse {
+    fn execute(&self, request: CtcpRequest) -> Option<CtcpResponse> {
+        if request.command.eq_ignore_ascii_case(&self.command) {
+            let datetime: DateTime<Utc> = SystemTime::now().into();
+            let reply = self.template
+                .replace("{nick}", &request.user.nick)
+                .replace("{target}", request.source)
+                .replace("{bot_nick}", &request.server.user_data.nickname)
+                .replace("{realname}", &request.server.user_data.realname)
+                .replace("{version}", &request.server.ctcp.version)
+                .replace("{source}", &request.server.ctcp.source)
+                .replace("{time}", &datetime.format("%c").to_string())
+                .replace("{message}", request.message);
+
+            return Some(CtcpResponse {
+                target: request.user.nick.clone(),
+                message: format!("{} {}", self.command.to_uppercase(), reply),
+            });
+        }
+
         None
     }
 }
\ No newline at end of file
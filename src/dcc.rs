@@ -0,0 +1,435 @@
+use std::fs;
+use std::io::{self, SeekFrom};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::fs::File;
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use simple_irc::Prefix;
+
+use crate::config::DccConfig;
+use crate::event::BotEvent;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+pub struct DccChatSession {
+    pub user: Prefix,
+    pub sender: UnboundedSender<String>,
+    pub connected: bool,
+    // Nothing is dispatched until then, sessions whose address couldn't be checked need the chat password first
+    pub authenticated: bool,
+}
+
+// DCC CHAT chat <address> <port> [token]
+pub struct DccChatOffer {
+    pub address: IpAddr,
+    pub port: u16,
+    pub token: Option<String>,
+}
+
+pub struct DccTransfer {
+    pub nick: String,
+    pub file_name: String,
+    pub size: u64,
+    // Port 0 means a passive transfer, where the user listens and the bot connects
+    pub port: u16,
+    pub token: Option<String>,
+    // Where to start sending from, changed by DCC RESUME before the transfer starts
+    pub position: Arc<AtomicU64>,
+    pub path: PathBuf,
+    // Passive transfers start when the user answers, they expire otherwise
+    pub started: bool,
+}
+
+pub struct DccFilesPrivMsgEvent {
+    pub directory: String,
+}
+
+pub fn parse_chat_offer(arguments: &[String]) -> Option<DccChatOffer> {
+    match arguments {
+        [protocol, address, port, token @ ..] if protocol.eq_ignore_ascii_case("chat") => Some(DccChatOffer {
+            address: parse_address(address)?,
+            port: port.parse().ok()?,
+            token: token.first().map(|token| token.to_string()),
+        }),
+        _ => None,
+    }
+}
+
+// Splits DCC arguments, file names with spaces are quoted
+pub fn split_arguments(message: &str) -> Vec<String> {
+    let mut arguments: Vec<String> = vec![];
+    let mut argument = String::new();
+    let mut quoted = false;
+
+    for c in message.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' if !quoted => {
+                if !argument.is_empty() {
+                    arguments.push(std::mem::take(&mut argument));
+                }
+            }
+            _ => argument.push(c),
+        }
+    }
+
+    if !argument.is_empty() {
+        arguments.push(argument);
+    }
+
+    arguments
+}
+
+pub fn quote_file_name(file_name: &str) -> String {
+    if file_name.contains(' ') {
+        format!("\"{}\"", file_name)
+    } else {
+        file_name.to_string()
+    }
+}
+
+// Only regular files directly inside the directory can be sent
+pub fn resolve_file(directory: &str, file_name: &str) -> Option<(PathBuf, u64)> {
+    if file_name.is_empty() || file_name.contains('/') || file_name.contains('\\') || file_name.starts_with('.') {
+        return None;
+    }
+
+    let path = Path::new(directory).join(file_name);
+    let metadata = fs::metadata(&path).ok()?;
+
+    if !metadata.is_file() {
+        return None;
+    }
+
+    Some((path, metadata.len()))
+}
+
+// IPv4 addresses are sent as a single 32 bit integer, IPv6 ones as they are
+pub fn parse_address(address: &str) -> Option<IpAddr> {
+    match address.parse::<u32>() {
+        Ok(address) => Some(IpAddr::V4(Ipv4Addr::from(address))),
+        Err(_) => address.parse().ok(),
+    }
+}
+
+pub fn format_address(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => u32::from(*address).to_string(),
+        IpAddr::V6(address) => address.to_string(),
+    }
+}
+
+// Address told to the other side when the bot is the one listening
+pub fn public_address(config: &DccConfig) -> Option<IpAddr> {
+    let address = config.public_address.as_deref().unwrap_or(&config.bind_address).parse::<IpAddr>().ok()?;
+
+    if address.is_unspecified() {
+        return None;
+    }
+
+    Some(address)
+}
+
+pub async fn listen(config: &DccConfig) -> io::Result<(TcpListener, u16)> {
+    let mut last_error = io::Error::new(io::ErrorKind::AddrNotAvailable, "Empty DCC port range");
+
+    for port in config.port_start..=config.port_end {
+        match TcpListener::bind((config.bind_address.as_str(), port)).await {
+            Ok(listener) => return Ok((listener, port)),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+pub fn spawn_chat_connect(session: u64, address: SocketAddr, timeout: Duration, events: UnboundedSender<BotEvent>, outgoing: UnboundedReceiver<String>) {
+    task::spawn(async move {
+        match async_std::io::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(stream) => run_chat(session, stream, events, outgoing).await,
+            Err(e) => {
+                log::error!("Couldn't connect to DCC CHAT {}: {}", address, e);
+
+                let _ = events.unbounded_send(BotEvent::DccChatClosed { session });
+            }
+        }
+    });
+}
+
+// Connections from other addresses than the allowed ones are closed, any address is allowed if there are none
+pub fn spawn_chat_accept(session: u64, listener: TcpListener, timeout: Duration, allowed: Vec<IpAddr>, events: UnboundedSender<BotEvent>, outgoing: UnboundedReceiver<String>) {
+    task::spawn(async move {
+        let accept = async {
+            loop {
+                let (stream, address) = listener.accept().await?;
+
+                if allowed.is_empty() || allowed.contains(&address.ip().to_canonical()) {
+                    return Ok((stream, address));
+                }
+
+                log::warn!("Rejected DCC CHAT connection from {}, expected {:?}", address, allowed);
+
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        };
+
+        match async_std::io::timeout(timeout, accept).await {
+            Ok((stream, address)) => {
+                log::info!("Accepted DCC CHAT connection from {}", address);
+
+                // Only one connection per offer
+                drop(listener);
+
+                run_chat(session, stream, events, outgoing).await;
+            }
+            Err(e) => {
+                log::error!("DCC CHAT wasn't accepted: {}", e);
+
+                let _ = events.unbounded_send(BotEvent::DccChatClosed { session });
+            }
+        }
+    });
+}
+
+async fn run_chat(session: u64, stream: TcpStream, events: UnboundedSender<BotEvent>, mut outgoing: UnboundedReceiver<String>) {
+    let _ = events.unbounded_send(BotEvent::DccChatConnected { session });
+
+    let mut writer = stream.clone();
+    let read_events = events.clone();
+
+    let read = async move {
+        let mut lines = BufReader::new(stream).lines();
+
+        while let Some(Ok(line)) = lines.next().await {
+            let line = line.trim().to_string();
+
+            if !line.is_empty() && read_events.unbounded_send(BotEvent::DccChatLine { session, line }).is_err() {
+                break;
+            }
+        }
+    };
+
+    let write = async move {
+        while let Some(line) = outgoing.next().await {
+            if writer.write_all(format!("{}\n", line).as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    // Whichever side finishes first closes the session
+    future::select(read.boxed(), write.boxed()).await;
+
+    let _ = events.unbounded_send(BotEvent::DccChatClosed { session });
+}
+
+pub fn spawn_send_accept(transfer: u64, listener: TcpListener, timeout: Duration, path: PathBuf, size: u64, position: Arc<AtomicU64>, events: UnboundedSender<BotEvent>) {
+    task::spawn(async move {
+        let result = match async_std::io::timeout(timeout, listener.accept()).await {
+            Ok((stream, address)) => {
+                log::info!("Accepted DCC SEND connection from {}", address);
+
+                drop(listener);
+
+                send_file(transfer, stream, &path, position.load(Ordering::SeqCst), size, &events).await
+            }
+            Err(e) => Err(e),
+        };
+
+        let _ = events.unbounded_send(BotEvent::DccSendFinished { transfer, result: result.map_err(|e| e.to_string()) });
+    });
+}
+
+pub fn spawn_send_connect(transfer: u64, address: SocketAddr, timeout: Duration, path: PathBuf, size: u64, position: u64, events: UnboundedSender<BotEvent>) {
+    task::spawn(async move {
+        let result = match async_std::io::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(stream) => send_file(transfer, stream, &path, position, size, &events).await,
+            Err(e) => Err(e),
+        };
+
+        let _ = events.unbounded_send(BotEvent::DccSendFinished { transfer, result: result.map_err(|e| e.to_string()) });
+    });
+}
+
+pub fn spawn_send_expiry(transfer: u64, timeout: Duration, events: UnboundedSender<BotEvent>) {
+    task::spawn(async move {
+        task::sleep(timeout).await;
+
+        let _ = events.unbounded_send(BotEvent::DccSendExpired { transfer });
+    });
+}
+
+async fn send_file(transfer: u64, stream: TcpStream, path: &Path, position: u64, size: u64, events: &UnboundedSender<BotEvent>) -> io::Result<u64> {
+    let mut file = File::open(path).await?;
+
+    file.seek(SeekFrom::Start(position)).await?;
+
+    // The receiver acknowledges the bytes received so far, as a 32 bit integer. Acks have to be read
+    // while sending, otherwise they fill the socket buffers and the transfer stalls
+    let mut ack_reader = stream.clone();
+    let acks = task::spawn(async move {
+        let mut ack = [0u8; 4];
+
+        while ack_reader.read_exact(&mut ack).await.is_ok() {
+            let acked = u32::from_be_bytes(ack) as u64;
+
+            if acked == size & 0xffff_ffff || acked == (size - position) & 0xffff_ffff {
+                break;
+            }
+        }
+    });
+
+    let mut writer = stream.clone();
+    let mut buffer = vec![0u8; SEND_BUFFER_SIZE];
+    let mut sent: u64 = 0;
+    let mut last_progress: u64 = 0;
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        writer.write_all(&buffer[..read]).await?;
+
+        sent += read as u64;
+
+        if sent - last_progress >= PROGRESS_INTERVAL {
+            last_progress = sent;
+
+            let _ = events.unbounded_send(BotEvent::DccSendProgress { transfer, sent: position + sent });
+        }
+    }
+
+    writer.flush().await?;
+
+    // Closing right away could make the receiver lose the end of the file
+    if async_std::future::timeout(Duration::from_secs(30), acks).await.is_err() {
+        log::warn!("DCC SEND {} didn't receive the last acknowledgement", transfer);
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+
+    Ok(sent)
+}
+
+impl PrivMsgEvent for DccFilesPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "dcc_files"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        if request.command("files").is_some() {
+            let mut files: Vec<String> = match fs::read_dir(&self.directory) {
+                Ok(entries) => entries.flatten()
+                    .filter(|entry| entry.metadata().map(|metadata| metadata.is_file()).unwrap_or(false))
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .filter(|file_name| !file_name.starts_with('.'))
+                    .collect(),
+                Err(e) => {
+                    log::error!("Couldn't read DCC send directory {}: {}", self.directory, e);
+
+                    vec![]
+                }
+            };
+
+            files.sort();
+
+            let message = if files.is_empty() {
+                "No files available".to_string()
+            } else {
+                format!("Files available with {}get <file>: {}", request.command_prefix, files.join(", "))
+            };
+
+            return vec![PrivMsgResponse {
+                target: request.user.nick.clone(),
+                message,
+                response_type: ResponseType::Notice,
+            }];
+        }
+
+        if let Some(file_name) = request.command("get") {
+            if resolve_file(&self.directory, file_name).is_none() {
+                return vec![PrivMsgResponse {
+                    target: request.user.nick.clone(),
+                    message: format!("No such file: {}", file_name),
+                    response_type: ResponseType::Notice,
+                }];
+            }
+
+            return vec![PrivMsgResponse {
+                target: request.user.nick.clone(),
+                message: file_name.to_string(),
+                response_type: ResponseType::DccSend,
+            }];
+        }
+
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn quoted_file_names_are_one_argument() {
+        assert_eq!(split_arguments("SEND \"my file.txt\" 3232235777 5000 1024"), vec!["SEND", "my file.txt", "3232235777", "5000", "1024"]);
+        assert_eq!(split_arguments("  CHAT  chat   1 2 "), vec!["CHAT", "chat", "1", "2"]);
+        assert!(split_arguments("").is_empty());
+    }
+
+    #[test]
+    fn file_names_with_spaces_are_quoted() {
+        assert_eq!(quote_file_name("my file.txt"), "\"my file.txt\"");
+        assert_eq!(quote_file_name("file.txt"), "file.txt");
+        assert_eq!(split_arguments(&format!("SEND {} 1 2 3", quote_file_name("a b"))), vec!["SEND", "a b", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn addresses_are_parsed_and_formatted() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        let ipv6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        assert_eq!(parse_address("3232235777"), Some(ipv4));
+        assert_eq!(parse_address("::1"), Some(ipv6));
+        assert_eq!(parse_address("not an address"), None);
+        assert_eq!(format_address(&ipv4), "3232235777");
+        assert_eq!(format_address(&ipv6), "::1");
+    }
+
+    #[test]
+    fn chat_offers_are_parsed() {
+        let offer = parse_chat_offer(&split_arguments("chat 3232235777 5000")).unwrap();
+
+        assert_eq!(offer.address, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(offer.port, 5000);
+        assert_eq!(offer.token, None);
+
+        let offer = parse_chat_offer(&split_arguments("CHAT 2130706433 0 42")).unwrap();
+
+        assert_eq!(offer.port, 0);
+        assert_eq!(offer.token.as_deref(), Some("42"));
+    }
+
+    #[test]
+    fn invalid_chat_offers_are_ignored() {
+        assert!(parse_chat_offer(&split_arguments("whiteboard 3232235777 5000")).is_none());
+        assert!(parse_chat_offer(&split_arguments("chat 3232235777 70000")).is_none());
+        assert!(parse_chat_offer(&split_arguments("chat 3232235777")).is_none());
+        assert!(parse_chat_offer(&[]).is_none());
+    }
+}
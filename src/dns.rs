@@ -0,0 +1,206 @@
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use futures::future;
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::{Name, RData, RecordType};
+
+use crate::config::{DnsConfig, DnsPluginConfig};
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+use crate::resolver::{self, Resolver};
+
+const RECORD_TYPES: [RecordType; 10] = [
+    RecordType::A,
+    RecordType::AAAA,
+    RecordType::MX,
+    RecordType::TXT,
+    RecordType::NS,
+    RecordType::CNAME,
+    RecordType::SOA,
+    RecordType::SRV,
+    RecordType::CAA,
+    RecordType::PTR,
+];
+const MAX_RECORDS: usize = 10;
+
+pub struct DnsPrivMsgEvent {
+    // Its own nameservers, otherwise the resolver everything else uses
+    resolver: Option<Arc<dyn Resolver>>,
+    dnssec: bool,
+}
+
+impl DnsPrivMsgEvent {
+    pub fn new(config: &DnsPluginConfig, dns: &DnsConfig) -> Self {
+        let resolver = if config.nameservers.is_empty() {
+            None
+        } else {
+            Some(resolver::from_config(&DnsConfig { nameservers: config.nameservers.clone(), ..dns.clone() }))
+        };
+
+        DnsPrivMsgEvent { resolver, dnssec: config.dnssec }
+    }
+}
+
+// example.com MX: 10 mx1.example.com., 20 mx2.example.com. [DNSSEC]
+fn format_answer(name: &str, record_type: RecordType, answer: io::Result<Message>) -> String {
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(e) => return format!("{} {}: {}", name, record_type, e),
+    };
+
+    match answer.response_code() {
+        ResponseCode::NoError => {}
+        ResponseCode::NXDomain => return format!("{} doesn't exist", name),
+        code => return format!("{} {}: {}", name, record_type, code),
+    }
+
+    // Signatures are only wanted for the validation, CNAMEs are shown in the chain they're followed
+    let records: Vec<String> = answer.answers().iter()
+        .filter(|record| record.record_type() == record_type || record.record_type() == RecordType::CNAME)
+        .filter_map(|record| record.data())
+        .map(format_record)
+        .collect();
+
+    if records.is_empty() {
+        return format!("{} {}: No records", name, record_type);
+    }
+
+    let mut message = format!("{} {}: {}", name, record_type, records.iter().take(MAX_RECORDS).cloned().collect::<Vec<String>>().join(", "));
+
+    if records.len() > MAX_RECORDS {
+        message = format!("{} +{} more", message, records.len() - MAX_RECORDS);
+    }
+
+    if answer.authentic_data() {
+        message = format!("{} [DNSSEC]", message);
+    }
+
+    message
+}
+
+// Every record type is asked at the same time, one answer per type
+async fn lookup(resolver: &dyn Resolver, name: &str, query_name: &str, record_types: &[RecordType], dnssec: bool) -> String {
+    let answers = future::join_all(record_types.iter().map(|record_type| resolver.query(query_name, *record_type, dnssec))).await;
+    let mut lines: Vec<String> = record_types.iter().zip(answers)
+        .map(|(record_type, answer)| format_answer(name, *record_type, answer))
+        .collect();
+
+    // Like when the name doesn't exist
+    lines.dedup();
+
+    lines.join(" / ")
+}
+
+fn format_record(data: &RData) -> String {
+    match data {
+        RData::CNAME(cname) => format!("CNAME {}", cname.0),
+        RData::TXT(txt) => format!("\"{}\"", txt),
+        data => data.to_string(),
+    }
+}
+
+fn parse_record_type(record_type: &str) -> Option<RecordType> {
+    RECORD_TYPES.iter().find(|known| known.to_string().eq_ignore_ascii_case(record_type)).copied()
+}
+
+impl PrivMsgEvent for DnsPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "dns"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        if let Some(arguments) = request.command("dns") {
+            let mut arguments = arguments.split_whitespace();
+            let name = match arguments.next() {
+                Some(name) => name.trim_end_matches('.'),
+                None => return vec![PrivMsgResponse {
+                    target: request.source.clone(),
+                    message: format!("Usage: {}dns <name> [{}]", request.command_prefix, RECORD_TYPES.iter().map(|record_type| record_type.to_string()).collect::<Vec<String>>().join("|")),
+                    response_type: ResponseType::PrivMsg,
+                }],
+            };
+            // IPs are looked up in reverse, hostnames have both kinds of addresses looked up
+            let record_types = match arguments.next() {
+                Some(record_type) => match parse_record_type(record_type) {
+                    Some(record_type) => vec![record_type],
+                    None => return vec![PrivMsgResponse {
+                        target: request.source.clone(),
+                        message: format!("Unknown record type: {}", record_type),
+                        response_type: ResponseType::PrivMsg,
+                    }],
+                },
+                None if name.parse::<IpAddr>().is_ok() => vec![RecordType::PTR],
+                None => vec![RecordType::A, RecordType::AAAA],
+            };
+            let query_name = match name.parse::<IpAddr>() {
+                Ok(ip) if record_types == [RecordType::PTR] => Name::from(ip).to_string(),
+                _ => name.to_string(),
+            };
+
+            let resolver = self.resolver.clone().unwrap_or_else(|| request.resolver.clone());
+            let dnssec = self.dnssec;
+            let name = name.to_string();
+            let target = request.source.clone();
+
+            request.spawn(async move {
+                vec![PrivMsgResponse {
+                    target,
+                    message: lookup(resolver.as_ref(), &name, &query_name, &record_types, dnssec).await,
+                    response_type: ResponseType::PrivMsg,
+                }]
+            });
+
+            return vec![];
+        }
+
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+
+    use crate::resolver::FakeResolver;
+
+    use super::*;
+
+    fn resolver() -> FakeResolver {
+        let mut resolver = FakeResolver::default();
+
+        resolver.hosts.insert("example.com".to_string(), vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()]);
+
+        resolver
+    }
+
+    #[test]
+    fn parses_record_types_in_any_case() {
+        assert_eq!(parse_record_type("mx"), Some(RecordType::MX));
+        assert_eq!(parse_record_type("AAAA"), Some(RecordType::AAAA));
+        assert_eq!(parse_record_type("AXFR"), None);
+    }
+
+    #[test]
+    fn answers_every_record_type() {
+        let resolver = resolver();
+        let message = task::block_on(lookup(&resolver, "example.com", "example.com", &[RecordType::A, RecordType::AAAA], false));
+
+        assert_eq!(message, "example.com A: 192.0.2.1, 192.0.2.2 / example.com AAAA: No records");
+        assert_eq!(resolver.lookup_count(), 2);
+    }
+
+    #[test]
+    fn missing_names_are_answered_once() {
+        let message = task::block_on(lookup(&resolver(), "nope.example", "nope.example", &[RecordType::A, RecordType::AAAA], false));
+
+        assert_eq!(message, "nope.example doesn't exist");
+    }
+
+    #[test]
+    fn errors_are_shown_with_the_record_type() {
+        let error = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+
+        assert_eq!(format_answer("example.com", RecordType::MX, Err(error)), "example.com MX: timed out");
+    }
+}
@@ -0,0 +1,17 @@
+use crate::privmsg::PrivMsgResponse;
+
+// Events sent to the IRC handler by tasks running outside of it
+#[allow(clippy::enum_variant_names)]
+pub enum BotEvent {
+    DccChatConnected { session: u64 },
+    DccChatLine { session: u64, line: String },
+    DccChatClosed { session: u64 },
+    DccSendProgress { transfer: u64, sent: u64 },
+    DccSendFinished { transfer: u64, result: Result<u64, String> },
+    // Passive offers the user didn't answer in time
+    DccSendExpired { transfer: u64 },
+    // Responses of plugin work that ran in its own task, like DNS lookups
+    PluginResponses { responses: Vec<PrivMsgResponse> },
+    // Sent every second while connected, runs the scheduled jobs that are due
+    Tick,
+}
@@ -0,0 +1,187 @@
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_std::task;
+use maxminddb::Reader;
+use memmap2::Mmap;
+
+use crate::config::GeoIpConfig;
+use crate::geoip_response::GeoIpCache;
+
+// Memory mapped databases are shared by the page cache, so every server uses the same copy
+pub enum Source {
+    Mmap(Mmap),
+    Memory(Vec<u8>),
+}
+
+impl AsRef<[u8]> for Source {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Source::Mmap(mmap) => mmap,
+            Source::Memory(buffer) => buffer,
+        }
+    }
+}
+
+pub type GeoIpReader = Reader<Source>;
+
+pub struct GeoIpDatabase {
+    path: String,
+    mmap: bool,
+    reader: RwLock<Option<Arc<GeoIpReader>>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+pub struct GeoIpDatabases {
+    pub asn: GeoIpDatabase,
+    pub city: GeoIpDatabase,
+    pub country: Option<GeoIpDatabase>,
+    pub anonymous_ip: Option<GeoIpDatabase>,
+    pub isp: Option<GeoIpDatabase>,
+    pub connection_type: Option<GeoIpDatabase>,
+    pub domain: Option<GeoIpDatabase>,
+    pub cache: GeoIpCache,
+}
+
+// Readers available for a lookup, databases that aren't configured or couldn't be opened are None
+pub struct GeoIpReaders {
+    pub asn: Option<Arc<GeoIpReader>>,
+    pub city: Option<Arc<GeoIpReader>>,
+    pub country: Option<Arc<GeoIpReader>>,
+    pub anonymous_ip: Option<Arc<GeoIpReader>>,
+    pub isp: Option<Arc<GeoIpReader>>,
+    pub connection_type: Option<Arc<GeoIpReader>>,
+    pub domain: Option<Arc<GeoIpReader>>,
+}
+
+impl GeoIpDatabase {
+    fn open(path: &str, mmap: bool) -> Self {
+        let database = GeoIpDatabase {
+            path: path.to_string(),
+            mmap,
+            reader: RwLock::new(None),
+            modified: Mutex::new(None),
+        };
+
+        if let Err(e) = database.reload() {
+            log::error!("Couldn't open GeoIP database {}: {}, GeoIP lookups are disabled until it's available", path, e);
+        }
+
+        database
+    }
+
+    // Readers in use keep working with the old database until they're dropped
+    pub fn reader(&self) -> Option<Arc<GeoIpReader>> {
+        self.reader.read().unwrap().clone()
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).map_err(|e| e.to_string())?;
+        let source = if self.mmap {
+            let file = File::open(&self.path).map_err(|e| e.to_string())?;
+
+            // The database must be replaced by renaming a new file over it, like geoipupdate does,
+            // writing to the mapped file in place would change it under the reader
+            Source::Mmap(unsafe { Mmap::map(&file) }.map_err(|e| e.to_string())?)
+        } else {
+            Source::Memory(fs::read(&self.path).map_err(|e| e.to_string())?)
+        };
+
+        let reader = Reader::from_source(source).map_err(|e| e.to_string())?;
+
+        *self.reader.write().unwrap() = Some(Arc::new(reader));
+        *self.modified.lock().unwrap() = Some(modified);
+
+        Ok(())
+    }
+
+    // Returns whether the database was reloaded
+    fn reload_if_changed(&self) -> bool {
+        let modified = match fs::metadata(&self.path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return false,
+        };
+
+        if *self.modified.lock().unwrap() == Some(modified) {
+            return false;
+        }
+
+        match self.reload() {
+            Ok(()) => {
+                log::info!("Reloaded GeoIP database {}", self.path);
+
+                true
+            }
+            // The file may still be being written, it's retried on the next check
+            Err(e) => {
+                log::error!("Couldn't reload GeoIP database {}: {}", self.path, e);
+
+                false
+            }
+        }
+    }
+}
+
+impl GeoIpDatabases {
+    pub fn open(config: &GeoIpConfig) -> Arc<Self> {
+        let open = |path: &Option<String>| path.as_ref().map(|path| GeoIpDatabase::open(path, config.mmap));
+        let databases = Arc::new(GeoIpDatabases {
+            asn: GeoIpDatabase::open(&config.asn_database, config.mmap),
+            city: GeoIpDatabase::open(&config.city_database, config.mmap),
+            country: open(&config.country_database),
+            anonymous_ip: open(&config.anonymous_ip_database),
+            isp: open(&config.isp_database),
+            connection_type: open(&config.connection_type_database),
+            domain: open(&config.domain_database),
+            cache: GeoIpCache::new(&config.cache),
+        });
+
+        if config.reload_interval > 0 {
+            let watched = databases.clone();
+            let interval = Duration::from_secs(config.reload_interval);
+
+            task::spawn(async move {
+                loop {
+                    task::sleep(interval).await;
+
+                    // Every database is checked, even after one of them is reloaded
+                    let reloaded = watched.all().iter().filter(|database| database.reload_if_changed()).count();
+
+                    if reloaded > 0 {
+                        watched.cache.clear_lookups();
+                    }
+                }
+            });
+        }
+
+        databases
+    }
+
+    pub fn readers(&self) -> GeoIpReaders {
+        let reader = |database: &Option<GeoIpDatabase>| database.as_ref().and_then(|database| database.reader());
+
+        GeoIpReaders {
+            asn: self.asn.reader(),
+            city: self.city.reader(),
+            country: reader(&self.country),
+            anonymous_ip: reader(&self.anonymous_ip),
+            isp: reader(&self.isp),
+            connection_type: reader(&self.connection_type),
+            domain: reader(&self.domain),
+        }
+    }
+
+    fn all(&self) -> Vec<&GeoIpDatabase> {
+        let optional = [&self.country, &self.anonymous_ip, &self.isp, &self.connection_type, &self.domain];
+
+        vec![&self.asn, &self.city].into_iter().chain(optional.iter().filter_map(|database| database.as_ref())).collect()
+    }
+}
+
+impl GeoIpReaders {
+    // Lookups need at least one database with ASN or location data
+    pub fn is_available(&self) -> bool {
+        self.asn.is_some() || self.city.is_some() || self.country.is_some() || self.isp.is_some()
+    }
+}
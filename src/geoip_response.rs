@@ -1,131 +1,574 @@
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+use async_std::task;
+use ipnetwork::IpNetwork;
+use futures::future;
+use serde::Deserialize;
+
+use crate::cache::{CacheStats, LruCache};
+use crate::config::GeoIpCacheConfig;
+use crate::geoip_database::{GeoIpReader, GeoIpReaders};
+use crate::resolver::Resolver;
+
+#[derive(Debug, Clone)]
 pub struct GeoIpResponse {
     pub ip: GeoIpDataResponse,
     pub city: GeoIpCityResponse,
     pub asn: GeoIpAsnResponse,
+    pub network: GeoIpNetworkResponse,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GeoIpDataResponse {
     pub ip: String,
     pub ptr: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GeoIpCityResponse {
     pub name: String,
     pub state: String,
     pub country: String,
     pub country_iso_code: String,
+    pub postal_code: Option<String>,
+    pub continent: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    // Kilometers around the coordinates where the IP is likely to be
+    pub accuracy_radius: Option<u16>,
+    pub time_zone: Option<String>,
+    // Country the ISP registered the IP in, may differ from where it's used
+    pub registered_country: Option<String>,
+    // Country represented by users of the IP, like a military base abroad
+    pub represented_country: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GeoIpAsnResponse {
     pub number: String,
     pub name: String,
+    // Network the ASN database has the IP in
+    pub prefix: Option<IpNetwork>,
+}
+
+// From the optional ISP, Connection-Type, Domain and Anonymous-IP databases
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GeoIpNetworkResponse {
+    pub isp: Option<String>,
+    pub organization: Option<String>,
+    pub connection_type: Option<String>,
+    pub domain: Option<String>,
+    // Like VPN, Tor exit node or hosting provider
+    pub anonymous: Vec<String>,
+}
+
+// Summary of what the databases have inside a network given in CIDR notation
+#[derive(Debug, Clone)]
+pub struct GeoIpCidrResponse {
+    pub network: IpNetwork,
+    // Set when the whole network is inside a single prefix of the ASN database
+    pub prefix: Option<IpNetwork>,
+    // ASN, name and how many prefixes inside the network it has, most prefixes first
+    pub asns: Vec<(String, String, usize)>,
+    // Country and how many networks inside the network are in it, most networks first
+    pub countries: Vec<(String, usize)>,
+}
+
+#[derive(Debug)]
+pub struct GeoIpQueryResponse {
+    pub query: String,
+    // Every address the query resolved to, or why it couldn't be resolved
+    pub result: Result<Vec<GeoIpResponse>, std::io::Error>,
+}
+
+// Organization name and prefixes of an AS
+pub type AsnPrefixes = (Option<String>, Vec<IpNetwork>);
+
+// Shared by every server, lookups are cached per language since place names depend on it
+pub struct GeoIpCache {
+    hosts: Mutex<LruCache<String, Vec<IpAddr>>>,
+    ptrs: Mutex<LruCache<IpAddr, String>>,
+    lookups: Mutex<LruCache<(IpAddr, Vec<String>), GeoIpResponse>>,
+    // Walking the databases is slow, .asn pages and repeated summaries use what the first walk found
+    asn_prefixes: Mutex<LruCache<u32, AsnPrefixes>>,
+    summaries: Mutex<LruCache<(IpNetwork, Vec<String>), GeoIpCidrResponse>>,
+}
+
+impl GeoIpCache {
+    pub fn new(config: &GeoIpCacheConfig) -> Self {
+        let dns_ttl = Duration::from_secs(config.dns_ttl);
+        let lookup_ttl = Duration::from_secs(config.lookup_ttl);
+
+        GeoIpCache {
+            hosts: Mutex::new(LruCache::new(config.size, dns_ttl)),
+            ptrs: Mutex::new(LruCache::new(config.size, dns_ttl)),
+            lookups: Mutex::new(LruCache::new(config.size, lookup_ttl)),
+            asn_prefixes: Mutex::new(LruCache::new(config.size, lookup_ttl)),
+            summaries: Mutex::new(LruCache::new(config.size, lookup_ttl)),
+        }
+    }
+
+    // Lookups made with the old databases are outdated
+    pub fn clear_lookups(&self) {
+        self.lookups.lock().unwrap().clear();
+        self.asn_prefixes.lock().unwrap().clear();
+        self.summaries.lock().unwrap().clear();
+    }
+
+    // Hostnames, PTRs, lookups, ASN prefixes and network summaries
+    pub fn stats(&self) -> [(&'static str, CacheStats); 5] {
+        [
+            ("hosts", self.hosts.lock().unwrap().stats()),
+            ("PTRs", self.ptrs.lock().unwrap().stats()),
+            ("lookups", self.lookups.lock().unwrap().stats()),
+            ("ASN prefixes", self.asn_prefixes.lock().unwrap().stats()),
+            ("summaries", self.summaries.lock().unwrap().stats()),
+        ]
+    }
 }
 
-pub fn ip_to_geoip(ips: Vec<&str>, reader_asn: &maxminddb::Reader<Vec<u8>>, reader_city: &maxminddb::Reader<Vec<u8>>) -> Result<Vec<GeoIpResponse>, std::io::Error> {
-    let mut array_geoip: Vec<GeoIpResponse> = vec![];
+pub async fn ip_to_geoip(ips: Vec<&str>, readers: &GeoIpReaders, cache: &GeoIpCache, resolver: &dyn Resolver, languages: &[String]) -> Vec<GeoIpQueryResponse> {
+    let mut array_geoip: Vec<GeoIpQueryResponse> = vec![];
 
     for ip_addr in ips.iter() {
         let now = Instant::now();
 
         log::info!("Geolocating IP {}", ip_addr);
 
-        let ip_result = dns_lookup::lookup_host(ip_addr);
+        let result = match lookup_host(ip_addr, cache, resolver).await {
+            // PTRs of every address are resolved at the same time
+            Ok(addresses) => Ok(future::join_all(addresses.into_iter().map(|ip| geolocate_ip(ip, readers, cache, resolver, languages))).await),
+            Err(e) => Err(e),
+        };
+
+        array_geoip.push(GeoIpQueryResponse {
+            query: ip_addr.to_string(),
+            result,
+        });
+
+        log::info!("Done geolocalization of IP: {}. Elapsed time: {:?}", ip_addr, now.elapsed());
+    }
 
-        if let Err(e) = ip_result {
+    array_geoip
+}
+
+async fn lookup_host(ip_addr: &str, cache: &GeoIpCache, resolver: &dyn Resolver) -> Result<Vec<IpAddr>, std::io::Error> {
+    if let Ok(ip) = ip_addr.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    if let Some(addresses) = cache.hosts.lock().unwrap().get(&ip_addr.to_lowercase()) {
+        return Ok(addresses);
+    }
+
+    let addresses = match resolver.lookup_ip(ip_addr).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
             log::info!("Cannot resolve IP for domain: {}, error: {}", ip_addr, e);
 
             return Err(e);
         }
+    };
+
+    log::info!("Resolved DNS {} to IPs {:?}", ip_addr, addresses);
 
-        let ip = *ip_result.unwrap().first().unwrap();
+    cache.hosts.lock().unwrap().insert(ip_addr.to_lowercase(), addresses.clone());
+
+    Ok(addresses)
+}
 
-        if ip.to_string().ne(ip_addr) {
-            log::info!("Resolved DNS {} to IP {}", ip_addr, ip.to_string())
+async fn geolocate_ip(ip: IpAddr, readers: &GeoIpReaders, cache: &GeoIpCache, resolver: &dyn Resolver, languages: &[String]) -> GeoIpResponse {
+    let key = (ip, languages.to_vec());
+    let cached = cache.lookups.lock().unwrap().get(&key);
+    let mut response = match cached {
+        Some(response) => response,
+        None => {
+            let response = lookup_ip(ip, readers, languages);
+
+            cache.lookups.lock().unwrap().insert(key, response.clone());
+
+            response
+        }
+    };
+
+    response.ip.ptr = lookup_ptr(ip, cache, resolver).await;
+
+    response
+}
+
+async fn lookup_ptr(ip: IpAddr, cache: &GeoIpCache, resolver: &dyn Resolver) -> String {
+    if let Some(ptr) = cache.ptrs.lock().unwrap().get(&ip) {
+        return ptr;
+    }
+
+    let ptr = match resolver.lookup_ptr(ip).await {
+        Ok(ptr) => ptr.unwrap_or_else(|| "No PTR".to_string()),
+        // Not cached, it may work next time
+        Err(e) => {
+            log::error!("Couldn't resolve PTR of IP {}. Error: {}", &ip, e);
+
+            return "No PTR".to_string();
         }
-        let ptr_dns = match dns_lookup::lookup_addr(&ip) {
-            Ok(ptr) => ptr,
-            Err(e) => {
-                log::error!("Couldn't resolve PTR of IP {}. Error: {}", &ip, e);
+    };
 
-                ip.to_string()
+    cache.ptrs.lock().unwrap().insert(ip, ptr.clone());
+
+    ptr
+}
+
+// Everything the databases have about the IP, the PTR is filled by geolocate_ip
+fn lookup_ip(ip: IpAddr, readers: &GeoIpReaders, languages: &[String]) -> GeoIpResponse {
+    // The ISP database has the ASN too, and the Country database is a subset of the City one
+    let asn_option: Result<(maxminddb::geoip2::Asn, IpNetwork), maxminddb::MaxMindDBError> = match lookup_prefix(readers.asn.as_ref(), ip) {
+        Err(_) if readers.isp.is_some() => lookup_prefix(readers.isp.as_ref(), ip),
+        asn => asn,
+    };
+    let city_option: Result<maxminddb::geoip2::City, maxminddb::MaxMindDBError> = match lookup(readers.city.as_ref(), ip) {
+        Err(_) if readers.country.is_some() => lookup(readers.country.as_ref(), ip),
+        city => city,
+    };
+
+    let mut city_name: String = "No City".to_string();
+    let mut state_name: String = "No State".to_string();
+    let mut country_name: String = "No Country".to_string();
+    let mut country_iso_code: String = "No Country".to_string();
+    let mut postal_code: Option<String> = None;
+    let mut continent: Option<String> = None;
+    let mut latitude: Option<f64> = None;
+    let mut longitude: Option<f64> = None;
+    let mut accuracy_radius: Option<u16> = None;
+    let mut time_zone: Option<String> = None;
+    let mut registered_country: Option<String> = None;
+    let mut represented_country: Option<String> = None;
+
+    let mut asn_number: String = "No ASN".to_string();
+    let mut asn_name: String = "No ASN".to_string();
+    let mut asn_prefix: Option<IpNetwork> = None;
+
+    match city_option {
+        Ok(city) => {
+            match city.city.as_ref().and_then(|i| localized_name(&i.names, languages)) {
+                Some(name) => city_name = name,
+                None => log::error!("No City found for IP: {}", ip),
             }
-        };
-        let ptr = if ptr_dns.eq(&ip.to_string()) { "No PTR".to_string() } else { ptr_dns };
-        let asn_option: Result<maxminddb::geoip2::Asn, maxminddb::MaxMindDBError> = reader_asn.lookup(ip);
-        let city_option: Result<maxminddb::geoip2::City, maxminddb::MaxMindDBError> = reader_city.lookup(ip);
-
-        let mut city_name: String = "No City".to_string();
-        let mut state_name: String = "No State".to_string();
-        let mut country_name: String = "No Country".to_string();
-        let mut country_iso_code: String = "No Country".to_string();
-
-        let mut asn_number: String = "No ASN".to_string();
-        let mut asn_name: String = "No ASN".to_string();
-
-        match city_option {
-            Ok(city) => {
-                if let Some(i) = city.city {
-                    city_name = i.names.as_ref().unwrap().get("en").unwrap().to_string()
-                } else {
-                    log::error!("No City found for IP: {}", ip);
-                }
-
-                if let Some(i) = &city.subdivisions {
-                    state_name = i.first().unwrap().names.as_ref().unwrap().get("en").unwrap().to_string()
-                } else {
-                    log::error!("No State found for IP: {}", ip);
-                }
-
-                if let Some(i) = &city.country {
-                    country_name = i.names.as_ref().unwrap().get("en").unwrap().to_string()
-                } else {
-                    log::error!("No Country found for IP: {}", ip);
-                }
-
-                if let Some(i) = &city.country {
-                    country_iso_code = i.iso_code.unwrap().to_owned()
-                } else {
-                    log::error!("No Country ISO code found for IP: {}", ip);
-                }
+
+            match city.subdivisions.as_ref().and_then(|i| i.first()).and_then(|i| localized_name(&i.names, languages)) {
+                Some(name) => state_name = name,
+                None => log::error!("No State found for IP: {}", ip),
             }
-            Err(err) => log::error!("An error happened while searching City for IP: {}, {}", ip, err),
+
+            match city.country.as_ref().and_then(|i| localized_name(&i.names, languages)) {
+                Some(name) => country_name = name,
+                None => log::error!("No Country found for IP: {}", ip),
+            }
+
+            match city.country.as_ref().and_then(|i| i.iso_code) {
+                Some(iso_code) => country_iso_code = iso_code.to_string(),
+                None => log::error!("No Country ISO code found for IP: {}", ip),
+            }
+
+            if let Some(location) = &city.location {
+                latitude = location.latitude;
+                longitude = location.longitude;
+                accuracy_radius = location.accuracy_radius;
+                time_zone = location.time_zone.map(|time_zone| time_zone.to_string());
+            }
+
+            postal_code = city.postal.and_then(|i| i.code).map(|code| code.to_string());
+            continent = city.continent.and_then(|i| localized_name(&i.names, languages));
+            registered_country = city.registered_country.and_then(|i| localized_name(&i.names, languages));
+            represented_country = city.represented_country.and_then(|i| localized_name(&i.names, languages));
+        }
+        Err(err) => log::error!("An error happened while searching City for IP: {}, {}", ip, err),
+    }
+
+    match asn_option {
+        Ok((asn, prefix)) => {
+            asn_number = format!("AS{}", asn.autonomous_system_number.unwrap_or(0));
+            asn_name = asn.autonomous_system_organization.unwrap_or("No ASN name").to_string();
+            asn_prefix = Some(prefix);
+        }
+        Err(err) => log::error!("An error happened while searching ASN for IP: {}, {}", ip, err),
+    }
+
+    GeoIpResponse {
+        ip: GeoIpDataResponse {
+            ip: ip.to_string(),
+            ptr: "No PTR".to_string(),
+        },
+        city: GeoIpCityResponse {
+            name: city_name,
+            state: state_name,
+            country: country_name,
+            country_iso_code,
+            postal_code,
+            continent,
+            latitude,
+            longitude,
+            accuracy_radius,
+            time_zone,
+            registered_country,
+            represented_country,
+        },
+        asn: GeoIpAsnResponse {
+            number: asn_number,
+            name: asn_name,
+            prefix: asn_prefix,
+        },
+        network: geolocate_network(ip, readers),
+    }
+}
+
+fn geolocate_network(ip: IpAddr, readers: &GeoIpReaders) -> GeoIpNetworkResponse {
+    let mut network = GeoIpNetworkResponse { ..Default::default() };
+
+    if let Ok(isp) = lookup::<maxminddb::geoip2::Isp>(readers.isp.as_ref(), ip) {
+        network.isp = isp.isp.map(|isp| isp.to_string());
+        network.organization = isp.organization.filter(|organization| Some(*organization) != isp.isp).map(|organization| organization.to_string());
+    }
+
+    if let Ok(connection_type) = lookup::<maxminddb::geoip2::ConnectionType>(readers.connection_type.as_ref(), ip) {
+        network.connection_type = connection_type.connection_type.map(|connection_type| connection_type.to_string());
+    }
+
+    if let Ok(domain) = lookup::<maxminddb::geoip2::Domain>(readers.domain.as_ref(), ip) {
+        network.domain = domain.domain.map(|domain| domain.to_string());
+    }
+
+    if let Ok(anonymous_ip) = lookup::<maxminddb::geoip2::AnonymousIp>(readers.anonymous_ip.as_ref(), ip) {
+        let flags = [
+            (anonymous_ip.is_anonymous_vpn, "VPN"),
+            (anonymous_ip.is_tor_exit_node, "Tor exit node"),
+            (anonymous_ip.is_public_proxy, "Public proxy"),
+            (anonymous_ip.is_residential_proxy, "Residential proxy"),
+            (anonymous_ip.is_hosting_provider, "Hosting provider"),
+        ];
+
+        network.anonymous = flags.iter().filter(|(flag, _)| *flag == Some(true)).map(|(_, name)| name.to_string()).collect();
+
+        if network.anonymous.is_empty() && anonymous_ip.is_anonymous == Some(true) {
+            network.anonymous.push("Anonymous".to_string());
         }
+    }
+
+    network
+}
+
+// The databases are walked in the blocking pool, the summary is cached
+pub async fn summarize_network(network: IpNetwork, readers: GeoIpReaders, cache: &GeoIpCache, languages: &[String]) -> Result<GeoIpCidrResponse, maxminddb::MaxMindDBError> {
+    let key = (network, languages.to_vec());
+
+    if let Some(summary) = cache.summaries.lock().unwrap().get(&key) {
+        return Ok(summary);
+    }
+
+    let languages = languages.to_vec();
+    let summary = task::spawn_blocking(move || cidr_to_geoip(network, &readers, &languages)).await?;
+
+    cache.summaries.lock().unwrap().insert(key, summary.clone());
+
+    Ok(summary)
+}
+
+// Like asn_prefixes, walking the tree in the blocking pool only when the AS isn't cached
+pub async fn cached_asn_prefixes(reader: Arc<GeoIpReader>, number: u32, cache: &GeoIpCache) -> Result<AsnPrefixes, maxminddb::MaxMindDBError> {
+    if let Some(prefixes) = cache.asn_prefixes.lock().unwrap().get(&number) {
+        return Ok(prefixes);
+    }
+
+    let prefixes = task::spawn_blocking(move || asn_prefixes(&reader, number)).await?;
 
-        match asn_option {
-            Ok(asn) => {
-                asn_number = format!("AS{}", asn.autonomous_system_number.unwrap_or(0).to_string());
-                asn_name = asn.autonomous_system_organization.unwrap_or("No ASN name").to_string();
+    cache.asn_prefixes.lock().unwrap().insert(number, prefixes.clone());
+
+    Ok(prefixes)
+}
+
+fn cidr_to_geoip(network: IpNetwork, readers: &GeoIpReaders, languages: &[String]) -> Result<GeoIpCidrResponse, maxminddb::MaxMindDBError> {
+    let now = Instant::now();
+    let network = IpNetwork::new(network.network(), network.prefix()).map_err(|e| maxminddb::MaxMindDBError::InvalidNetworkError(e.to_string()))?;
+
+    log::info!("Summarizing network {}", network);
+
+    let asn_reader = readers.asn.as_ref().or(readers.isp.as_ref()).ok_or_else(unavailable)?;
+    let asns = count_within(asn_reader, network, |asn: maxminddb::geoip2::Asn| {
+        (format!("AS{}", asn.autonomous_system_number.unwrap_or(0)), asn.autonomous_system_organization.unwrap_or("No ASN name").to_string())
+    })?;
+    let prefix = match lookup_prefix::<maxminddb::geoip2::Asn>(Some(asn_reader), network.network()) {
+        Ok((_, prefix)) if prefix.prefix() <= network.prefix() => Some(prefix),
+        _ => None,
+    };
+
+    // City databases have the country too
+    let countries = match readers.city.as_ref().or(readers.country.as_ref()) {
+        Some(reader) => count_within(reader, network, |country: maxminddb::geoip2::Country| {
+            country.country.and_then(|country| localized_name(&country.names, languages)).unwrap_or_else(|| "No Country".to_string())
+        })?,
+        None => vec![],
+    };
+
+    log::info!("Done summary of network: {}. Elapsed time: {:?}", network, now.elapsed());
+
+    Ok(GeoIpCidrResponse {
+        network,
+        prefix,
+        asns: asns.into_iter().map(|((number, name), count)| (number, name, count)).collect(),
+        countries,
+    })
+}
+
+// Organization name and every prefix the ASN database has for the given AS number, walking the whole tree
+fn asn_prefixes(reader: &GeoIpReader, number: u32) -> Result<AsnPrefixes, maxminddb::MaxMindDBError> {
+    // IPv4 networks are inside ::/96 of IPv6 databases, and are returned as IPv4 ones
+    let network = if reader.metadata.ip_version == 6 {
+        IpNetwork::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+    } else {
+        IpNetwork::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    }.map_err(|e| maxminddb::MaxMindDBError::InvalidNetworkError(e.to_string()))?;
+
+    let mut name: Option<String> = None;
+    let mut prefixes: Vec<IpNetwork> = vec![];
+
+    for item in reader.within::<maxminddb::geoip2::Asn>(network)? {
+        let item = item?;
+
+        if item.info.autonomous_system_number == Some(number) {
+            if name.is_none() {
+                name = item.info.autonomous_system_organization.map(|name| name.to_string());
             }
-            Err(err) => log::error!("An error happened while searching ASN for IP: {}, {}", ip, err),
+
+            prefixes.push(item.ip_net);
         }
+    }
 
-        let response = GeoIpResponse {
-            ip: GeoIpDataResponse {
-                ip: (ip.to_string()).parse().unwrap(),
-                ptr: ptr.to_string(),
-            },
-            city: GeoIpCityResponse {
-                name: city_name,
-                state: state_name,
-                country: country_name,
-                country_iso_code,
-            },
-            asn: GeoIpAsnResponse {
-                number: asn_number,
-                name: asn_name,
-            },
-        };
+    Ok((name, prefixes))
+}
 
-        array_geoip.push(response);
+// How many networks of the database inside the network have each key, most common first
+fn count_within<'a, T: Deserialize<'a> + 'a, K: PartialEq>(reader: &'a GeoIpReader, network: IpNetwork, key: impl Fn(T) -> K) -> Result<Vec<(K, usize)>, maxminddb::MaxMindDBError> {
+    let mut counts: Vec<(K, usize)> = vec![];
 
-        log::info!("Done geolocalization of IP: {}. Elapsed time: {:?}", ip, now.elapsed());
+    // Nothing to walk when the whole network is inside a single network of the database
+    match reader.lookup_prefix::<T>(network.network()) {
+        Ok((info, prefix_len)) if prefix_len <= network.prefix() as usize => return Ok(vec![(key(info), 1)]),
+        _ => {}
     }
 
-    return Ok(array_geoip);
-}
\ No newline at end of file
+    for item in reader.within::<T>(network)? {
+        let key = key(item?.info);
+
+        match counts.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((key, 1)),
+        }
+    }
+
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    Ok(counts)
+}
+
+fn lookup<'a, T: Deserialize<'a>>(reader: Option<&'a Arc<GeoIpReader>>, ip: IpAddr) -> Result<T, maxminddb::MaxMindDBError> {
+    match reader {
+        Some(reader) => reader.lookup(ip),
+        None => Err(unavailable()),
+    }
+}
+
+// Like lookup, with the network of the database the IP is in
+fn lookup_prefix<'a, T: Deserialize<'a>>(reader: Option<&'a Arc<GeoIpReader>>, ip: IpAddr) -> Result<(T, IpNetwork), maxminddb::MaxMindDBError> {
+    let (info, prefix_len) = reader.ok_or_else(unavailable)?.lookup_prefix(ip)?;
+    let network = IpNetwork::new(ip, prefix_len as u8)
+        .and_then(|network| IpNetwork::new(network.network(), network.prefix()))
+        .map_err(|e| maxminddb::MaxMindDBError::InvalidNetworkError(e.to_string()))?;
+
+    Ok((info, network))
+}
+
+fn unavailable() -> maxminddb::MaxMindDBError {
+    maxminddb::MaxMindDBError::InvalidDatabaseError("Database isn't available".to_string())
+}
+
+// First name found in the preferred languages, then English, then any other
+fn localized_name(names: &Option<BTreeMap<&str, &str>>, languages: &[String]) -> Option<String> {
+    let names = names.as_ref()?;
+
+    languages.iter().map(|language| language.as_str())
+        .chain(std::iter::once("en"))
+        .find_map(|language| names.get(language))
+        .or_else(|| names.values().next())
+        .map(|name| name.to_string())
+}
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+
+    use crate::resolver::FakeResolver;
+
+    use super::*;
+
+    fn cache() -> GeoIpCache {
+        GeoIpCache::new(&GeoIpCacheConfig { ..Default::default() })
+    }
+
+    fn no_readers() -> GeoIpReaders {
+        GeoIpReaders { asn: None, city: None, country: None, anonymous_ip: None, isp: None, connection_type: None, domain: None }
+    }
+
+    #[test]
+    fn hosts_are_resolved_once() {
+        let mut resolver = FakeResolver::default();
+        let cache = cache();
+
+        resolver.hosts.insert("example.com".to_string(), vec!["192.0.2.1".parse().unwrap()]);
+
+        task::block_on(async {
+            assert_eq!(lookup_host("example.com", &cache, &resolver).await.unwrap(), vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+            assert_eq!(lookup_host("EXAMPLE.com", &cache, &resolver).await.unwrap(), vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+            assert_eq!(resolver.lookup_count(), 1);
+
+            // IPs aren't resolved, failures aren't cached
+            assert!(lookup_host("192.0.2.2", &cache, &resolver).await.is_ok());
+            assert!(lookup_host("nope.example", &cache, &resolver).await.is_err());
+            assert!(lookup_host("nope.example", &cache, &resolver).await.is_err());
+            assert_eq!(resolver.lookup_count(), 3);
+        });
+    }
+
+    #[test]
+    fn ptrs_are_cached() {
+        let mut resolver = FakeResolver::default();
+        let cache = cache();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        resolver.ptrs.insert(ip, "host.example.com".to_string());
+
+        task::block_on(async {
+            assert_eq!(lookup_ptr(ip, &cache, &resolver).await, "host.example.com");
+            assert_eq!(lookup_ptr(ip, &cache, &resolver).await, "host.example.com");
+            assert_eq!(lookup_ptr("192.0.2.2".parse().unwrap(), &cache, &resolver).await, "No PTR");
+        });
+
+        assert_eq!(resolver.lookup_count(), 2);
+    }
+
+    #[test]
+    fn every_address_of_a_host_is_geolocated() {
+        let mut resolver = FakeResolver::default();
+        let cache = cache();
+
+        resolver.hosts.insert("example.com".to_string(), vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()]);
+        resolver.ptrs.insert("192.0.2.1".parse().unwrap(), "host.example.com".to_string());
+
+        let mut queries = task::block_on(ip_to_geoip(vec!["example.com", "nope.example"], &no_readers(), &cache, &resolver, &[]));
+        let unresolved = queries.pop().unwrap();
+        let responses = queries.pop().unwrap().result.unwrap();
+
+        assert!(unresolved.result.is_err());
+        assert_eq!(responses.iter().map(|response| (response.ip.ip.as_str(), response.ip.ptr.as_str())).collect::<Vec<_>>(), vec![("192.0.2.1", "host.example.com"), ("2001:db8::1", "No PTR")]);
+        assert_eq!(responses[0].asn.number, "No ASN");
+        assert_eq!(responses[0].city.country, "No Country");
+    }
+}
@@ -1,4 +1,5 @@
 use regex::Regex;
+use simple_irc::Prefix;
 
 pub trait IrcExt {
     fn is_channel_name(&self) -> bool;
@@ -6,39 +7,109 @@ pub trait IrcExt {
     fn is_ctcp(&self) -> bool;
 
     fn remove_colorization(&self) -> String;
+
+    fn matches_mask(&self, mask: &str) -> bool;
 }
 
-impl<'a> IrcExt for &'a str {
+impl IrcExt for &str {
     fn is_channel_name(&self) -> bool {
-        return self.starts_with('#')
+        self.starts_with('#')
             || self.starts_with('&')
             || self.starts_with('+')
-            || self.starts_with('!');
+            || self.starts_with('!')
     }
 
     fn is_ctcp(&self) -> bool {
-        return self.starts_with('\u{1}');
+        self.starts_with('\u{1}')
     }
 
     fn remove_colorization(&self) -> String {
         // https://stackoverflow.com/a/3504063
         let re = Regex::new(r"\x1f|\x02|\x12|\x0f|\x16|\x03(?:\d{1,2}(?:,\d{1,2})?)?").unwrap();
-        let result = re.replace_all(self, "").to_string();
+        re.replace_all(self, "").to_string()
+    }
+
+    fn matches_mask(&self, mask: &str) -> bool {
+        // Case insensitive glob match, where * matches any sequence and ? matches a single character
+        let text: Vec<char> = self.to_lowercase().chars().collect();
+        let mask: Vec<char> = mask.to_lowercase().chars().collect();
+        let (mut t, mut m) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
 
-        return result;
+        while t < text.len() {
+            if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+                t += 1;
+                m += 1;
+            } else if m < mask.len() && mask[m] == '*' {
+                backtrack = Some((m, t));
+                m += 1;
+            } else if let Some((star, matched)) = backtrack {
+                backtrack = Some((star, matched + 1));
+                m = star + 1;
+                t = matched + 1;
+            } else {
+                return false;
+            }
+        }
+
+        mask[m..].iter().all(|c| *c == '*')
     }
 }
 
 impl IrcExt for String {
     fn is_channel_name(&self) -> bool {
-        return (&self[..]).is_channel_name();
+        (&self[..]).is_channel_name()
     }
 
     fn is_ctcp(&self) -> bool {
-        return (&self[..]).is_ctcp();
+        (&self[..]).is_ctcp()
     }
 
     fn remove_colorization(&self) -> String {
-        return (&self[..]).remove_colorization();
+        (&self[..]).remove_colorization()
+    }
+
+    fn matches_mask(&self, mask: &str) -> bool {
+        (&self[..]).matches_mask(mask)
+    }
+}
+
+// user@host, which stays the same across nick changes, or the nick when the server didn't send them
+pub fn user_key(user: &Prefix) -> String {
+    match (&user.user, &user.host) {
+        (Some(username), Some(host)) => format!("{}@{}", username, host),
+        _ => user.nick.clone(),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_match_with_wildcards() {
+        assert!("jomp16!~jomp@host.example".matches_mask("jomp16!*@*"));
+        assert!("jomp16!~jomp@host.example".matches_mask("*!*@HOST.example"));
+        assert!("jomp16!~jomp@host.example".matches_mask("jomp1?!*"));
+        assert!("a*b".matches_mask("a*b"));
+        assert!("abcbd".matches_mask("a*b*d"));
+        assert!("anything".matches_mask("*"));
+        assert!("".matches_mask("*"));
+    }
+
+    #[test]
+    fn masks_match_the_whole_text() {
+        assert!(!"jomp16!~jomp@host.example".matches_mask("jomp16"));
+        assert!(!"jomp16!~jomp@host.example".matches_mask("*@other.example"));
+        assert!(!"jomp1!~jomp@host.example".matches_mask("jomp1?!*"));
+        assert!(!"abc".matches_mask("abc?"));
+        assert!(!"".matches_mask("?"));
+    }
+
+    #[test]
+    fn user_key_falls_back_to_the_nick() {
+        assert_eq!(user_key(&Prefix::new_with_all("alice", Some("a"), Some("host.example"))), "a@host.example");
+        assert_eq!(user_key(&Prefix::new_with_all("alice", None, Some("host.example"))), "alice");
+        assert_eq!(user_key(&Prefix::new("alice")), "alice");
+    }
+}
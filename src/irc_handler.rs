@@ -1,75 +1,413 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::io::BufReader;
 use futures::prelude::*;
-use simple_irc::Message;
+use futures::stream;
+use simple_irc::{Message, Prefix};
 
-use crate::config::Server;
+use crate::admin;
+use crate::announcement;
+use crate::chat_log::{self, LogEntry, LogEvent};
+use crate::log_index;
+use crate::config::{AnnouncementAction, Server};
 use crate::ctcp::{CtcpEvent, CtcpRequest};
+use crate::dcc::{self, DccChatSession, DccTransfer};
+use crate::event::BotEvent;
 use crate::irc_ext::IrcExt;
-use crate::irc_state::IrcState;
-use crate::privmsg::{PrivMsgEvent, PrivMsgRequest};
+use crate::irc_state::{CaseMapping, IrcState, PendingCommand};
+use crate::rate_limit::RateLimitResult;
+use crate::scheduler;
+use crate::resolver::Resolver;
+use crate::storage::Storage;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType, UserEvent};
+
+const WHOIS_EXPIRY: Duration = Duration::from_secs(60);
 
 pub struct IrcHandler<'a> {
     pub server: &'a mut Server,
     pub irc_state: &'a mut IrcState,
     pub ctcp_event: &'a Vec<Box<dyn CtcpEvent>>,
     pub privmsg_event: &'a Vec<Box<dyn PrivMsgEvent>>,
+    pub event_sender: UnboundedSender<BotEvent>,
+    pub resolver: Arc<dyn Resolver>,
+    pub storage: Option<&'a Storage>,
 }
 
-impl IrcHandler<'_> {
-    pub async fn handle(&mut self, reader: impl AsyncRead + Unpin, writer: &mut (impl AsyncWrite + Unpin)) {
-        let mut buf_reader = BufReader::new(reader);
-        let mut buf = vec![];
+enum Input {
+    Line(String),
+    Event(BotEvent),
+    Disconnected,
+}
 
+impl IrcHandler<'_> {
+    pub async fn handle(&mut self, reader: impl AsyncRead + Unpin, writer: &mut (impl AsyncWrite + Unpin), events: UnboundedReceiver<BotEvent>) {
         if self.irc_state.initial_connection {
             self.handle_initial_connection(writer).await;
         }
 
-        while let Ok(_) = buf_reader.read_until(b'\n', &mut buf).await {
-            let line = String::from_utf8_lossy(&buf);
-            let line = line.trim();
-
-            let message = &line.parse::<simple_irc::Message>().unwrap();
-
-            log::debug!("{}", message);
-
-            match message.command.as_str() {
-                "CAP" => self.handle_cap(message, writer).await,
-                "AUTHENTICATE" => self.handle_authenticate(message, writer).await,
-                "904" => self.handle_authenticate_fail(writer).await,
-                "900" => (),
-                "903" => self.handle_authenticate_success(writer).await,
-                "NOTICE" => (),
-                "001" => (),
-                "002" => (),
-                "003" => (),
-                "004" => (),
-                "005" => (),
-                "251" => (),
-                "252" => (),
-                "253" => (),
-                "254" => (),
-                "255" => (),
-                "265" => (),
-                "266" => (),
-                "375" => (),
-                "372" => (),
-                "JOIN" => (),
-                "353" => (),
-                "366" => (),
-                "333" => (),
-                "332" => (),
-                "354" => (),
-                "315" => (),
-                "376" => self.handle_end_motd(message, writer).await,
-                "MODE" => self.handle_mode(message, writer).await,
-                "PRIVMSG" => self.handle_privmsg(message, writer).await,
-                "PING" => self.handle_ping(message, writer).await,
-                _ => {
-                    log::warn!("Unknown command. {}", message.command)
+        let lines = stream::unfold((BufReader::new(reader), vec![]), |(mut buf_reader, mut buf)| async move {
+            buf.clear();
+
+            match buf_reader.read_until(b'\n', &mut buf).await {
+                Ok(0) | Err(_) => None,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf).trim().to_string();
+
+                    Some((Input::Line(line), (buf_reader, buf)))
                 }
             }
+        });
 
-            buf.clear();
+        // Events can still arrive after the connection is closed, so it has to be signaled
+        let lines = lines.chain(stream::once(future::ready(Input::Disconnected)));
+        let inputs = stream::select(lines, events.map(Input::Event));
+
+        futures::pin_mut!(inputs);
+
+        while let Some(input) = inputs.next().await {
+            match input {
+                Input::Line(line) => self.handle_line(&line, writer).await,
+                Input::Event(event) => self.handle_event(event, writer).await,
+                Input::Disconnected => break,
+            }
+        }
+
+        log::info!("Disconnected from {}", self.server.hostname);
+    }
+
+    async fn handle_line(&mut self, line: &str, writer: &mut (impl AsyncWrite + Unpin)) {
+        if line.is_empty() {
+            return;
+        }
+
+        let message = &line.parse::<simple_irc::Message>().unwrap();
+
+        log::debug!("{}", message);
+
+        self.log_incoming(message);
+
+        match message.command.as_str() {
+            "CAP" => self.handle_cap(message, writer).await,
+            "AUTHENTICATE" => self.handle_authenticate(message, writer).await,
+            "904" => self.handle_authenticate_fail(writer).await,
+            "900" => (),
+            "903" => self.handle_authenticate_success(writer).await,
+            "NOTICE" => (),
+            "TOPIC" => (),
+            "001" => self.irc_state.registered = true,
+            "002" => (),
+            "003" => (),
+            "004" => (),
+            "005" => self.handle_isupport(message),
+            "251" => (),
+            "252" => (),
+            "253" => (),
+            "254" => (),
+            "255" => (),
+            "265" => (),
+            "266" => (),
+            "375" => (),
+            "372" => (),
+            "JOIN" => self.handle_join(message, writer).await,
+            "PART" => self.handle_part(message, writer).await,
+            "KICK" => self.handle_kick(message, writer).await,
+            "QUIT" => self.handle_quit(message, writer).await,
+            "NICK" => self.handle_nick(message, writer).await,
+            "ACCOUNT" => self.handle_account(message),
+            "353" => self.handle_names(message),
+            "311" => self.handle_whois_user(message),
+            "330" => self.handle_whois_account(message),
+            "318" => self.handle_end_whois(message, writer).await,
+            "401" => self.handle_no_such_nick(message, writer).await,
+            "366" => (),
+            "333" => (),
+            "332" => (),
+            "354" => (),
+            "315" => (),
+            "376" => self.handle_end_motd(message, writer).await,
+            "MODE" => self.handle_mode(message, writer).await,
+            "PRIVMSG" => self.handle_privmsg(message, writer).await,
+            "PING" => self.handle_ping(message, writer).await,
+            _ => {
+                log::warn!("Unknown command. {}", message.command)
+            }
+        }
+    }
+
+    async fn handle_event(&mut self, event: BotEvent, writer: &mut (impl AsyncWrite + Unpin)) {
+        match event {
+            BotEvent::DccChatConnected { session } => {
+                let command_prefix = self.server.command_prefix.clone();
+
+                if let Some(dcc_session) = self.irc_state.dcc_sessions.get_mut(&session) {
+                    log::info!("DCC CHAT session {} with {} connected", session, dcc_session.user);
+
+                    dcc_session.connected = true;
+
+                    let greeting = if dcc_session.authenticated {
+                        format!("Connected to {}, use {}quit to close the session", self.server.user_data.nickname, command_prefix)
+                    } else {
+                        "Password:".to_string()
+                    };
+
+                    let _ = dcc_session.sender.unbounded_send(greeting);
+                }
+            }
+            BotEvent::DccChatLine { session, line } => {
+                let (user, authenticated) = match self.irc_state.dcc_sessions.get(&session) {
+                    Some(dcc_session) => (dcc_session.user.clone(), dcc_session.authenticated),
+                    None => return,
+                };
+
+                if !authenticated {
+                    self.authenticate_dcc_chat(session, &line);
+
+                    return;
+                }
+
+                log::info!("[DCC {}] <{}> {}", session, user.nick, line);
+
+                if line == format!("{}quit", self.server.command_prefix) {
+                    // Dropping the sender closes the connection
+                    self.irc_state.dcc_sessions.remove(&session);
+
+                    return;
+                }
+
+                for response in self.dispatch_privmsg(&user, &user.nick, &line, false) {
+                    let is_message = matches!(response.response_type, ResponseType::PrivMsg | ResponseType::Notice | ResponseType::Action);
+
+                    if !is_message || !response.target.eq_ignore_ascii_case(&user.nick) {
+                        self.send_response(response, writer).await;
+
+                        continue;
+                    }
+
+                    if let Some(dcc_session) = self.irc_state.dcc_sessions.get(&session) {
+                        for line in response.message.lines().filter(|line| !line.is_empty()) {
+                            let line = match response.response_type {
+                                ResponseType::Action => format!("* {} {}", self.server.user_data.nickname, line),
+                                _ => line.to_string(),
+                            };
+
+                            let _ = dcc_session.sender.unbounded_send(line);
+                        }
+                    }
+                }
+            }
+            BotEvent::DccChatClosed { session } => {
+                if let Some(dcc_session) = self.irc_state.dcc_sessions.remove(&session) {
+                    log::info!("DCC CHAT session {} with {} closed", session, dcc_session.user);
+                }
+            }
+            BotEvent::DccSendProgress { transfer, sent } => {
+                if let Some(dcc_transfer) = self.irc_state.dcc_transfers.get(&transfer) {
+                    log::debug!("DCC SEND of {} to {}: {}/{} bytes", dcc_transfer.file_name, dcc_transfer.nick, sent, dcc_transfer.size);
+
+                    let (nick, file_name, size) = (dcc_transfer.nick.clone(), dcc_transfer.file_name.clone(), dcc_transfer.size);
+
+                    self.dispatch_dcc_event(&nick, &UserEvent::DccSendProgress { transfer, file_name: &file_name, sent, size }, writer).await;
+                }
+            }
+            BotEvent::DccSendFinished { transfer, result } => {
+                if let Some(dcc_transfer) = self.irc_state.dcc_transfers.remove(&transfer) {
+                    self.dispatch_dcc_event(&dcc_transfer.nick, &UserEvent::DccSendFinished {
+                        transfer,
+                        file_name: &dcc_transfer.file_name,
+                        size: dcc_transfer.size,
+                        result: &result,
+                    }, writer).await;
+
+                    let message = match result {
+                        Ok(sent) => {
+                            log::info!("DCC SEND of {} to {} finished, {} bytes sent", dcc_transfer.file_name, dcc_transfer.nick, sent);
+
+                            format!("Finished sending {}", dcc_transfer.file_name)
+                        }
+                        Err(e) => {
+                            log::error!("DCC SEND of {} to {} failed: {}", dcc_transfer.file_name, dcc_transfer.nick, e);
+
+                            format!("Couldn't send {}: {}", dcc_transfer.file_name, e)
+                        }
+                    };
+
+                    self.send_notice(dcc_transfer.nick, message, writer).await;
+                }
+            }
+            BotEvent::DccSendExpired { transfer } => {
+                if self.irc_state.dcc_transfers.get(&transfer).is_none_or(|dcc_transfer| dcc_transfer.started) {
+                    return;
+                }
+
+                if let Some(dcc_transfer) = self.irc_state.dcc_transfers.remove(&transfer) {
+                    log::info!("DCC SEND offer of {} to {} expired", dcc_transfer.file_name, dcc_transfer.nick);
+
+                    self.dispatch_dcc_event(&dcc_transfer.nick, &UserEvent::DccSendFinished {
+                        transfer,
+                        file_name: &dcc_transfer.file_name,
+                        size: dcc_transfer.size,
+                        result: &Err("The offer expired".to_string()),
+                    }, writer).await;
+
+                    self.send_notice(dcc_transfer.nick, format!("The offer of {} expired", dcc_transfer.file_name), writer).await;
+                }
+            }
+            BotEvent::PluginResponses { responses } => {
+                for response in responses {
+                    self.send_response(response, writer).await;
+                }
+            }
+            BotEvent::Tick => {
+                self.expire_whois();
+                self.run_scheduled(writer).await;
+                self.run_announcements(writer).await;
+            }
+        }
+    }
+
+    // The first line of a session that has to be authenticated is the chat password, anything else closes it
+    fn authenticate_dcc_chat(&mut self, session: u64, line: &str) {
+        let password = self.server.dcc.chat_password.as_deref().filter(|password| !password.is_empty());
+
+        if password != Some(line) {
+            if let Some(dcc_session) = self.irc_state.dcc_sessions.remove(&session) {
+                log::warn!("Wrong password in DCC CHAT session {} with {}, closing it", session, dcc_session.user);
+            }
+
+            return;
+        }
+
+        if let Some(dcc_session) = self.irc_state.dcc_sessions.get_mut(&session) {
+            log::info!("DCC CHAT session {} with {} authenticated", session, dcc_session.user);
+
+            dcc_session.authenticated = true;
+
+            let _ = dcc_session.sender.unbounded_send(format!("Connected to {}, use {}quit to close the session", self.server.user_data.nickname, self.server.command_prefix));
+        }
+    }
+
+    // Answers are forgotten after a while, along with the users only known through them. Commands waiting for
+    // a WHOIS that was never answered, like when the connection was lost, are dropped
+    fn expire_whois(&mut self) {
+        let now = Instant::now();
+        let whois_completed = &mut self.irc_state.whois_completed;
+
+        whois_completed.retain(|_, completed| now.duration_since(*completed) < WHOIS_EXPIRY);
+        self.irc_state.users.retain(|key, user| !user.channels.is_empty() || whois_completed.contains_key(key));
+        self.irc_state.pending_whois.retain(|nick, (requested, _)| {
+            let expired = now.duration_since(*requested) >= WHOIS_EXPIRY;
+
+            if expired {
+                log::warn!("WHOIS of {} wasn't answered, dropping the commands waiting for it", nick);
+            }
+
+            !expired
+        });
+    }
+
+    async fn run_announcements(&mut self, writer: &mut (impl AsyncWrite + Unpin)) {
+        for (channel, announcement) in announcement::due(self.server, self.irc_state) {
+            log::info!("Running announcement {} in {}", announcement.name, channel);
+
+            let response_type = match announcement.action {
+                AnnouncementAction::Message => ResponseType::PrivMsg,
+                AnnouncementAction::Notice => ResponseType::Notice,
+                AnnouncementAction::Action => ResponseType::Action,
+                AnnouncementAction::Topic => ResponseType::Topic,
+                AnnouncementAction::Command => {
+                    let bot = Prefix::new(&self.server.user_data.nickname);
+
+                    for response in self.dispatch_privmsg(&bot, &channel, &announcement.text, false) {
+                        self.send_response(response, writer).await;
+                    }
+
+                    continue;
+                }
+            };
+
+            self.send_response(PrivMsgResponse { target: channel, message: announcement.text, response_type }, writer).await;
+        }
+    }
+
+    // Jobs are given to the plugin that scheduled them, they're dropped if it isn't loaded anymore. They wait
+    // until the bot is registered and has joined their channel, and are only removed once they were delivered
+    async fn run_scheduled(&mut self, writer: &mut (impl AsyncWrite + Unpin)) {
+        let storage = match self.storage {
+            Some(storage) => storage,
+            None => return,
+        };
+
+        if !self.irc_state.registered {
+            return;
+        }
+
+        let jobs = match scheduler::due(storage, &self.server.hostname) {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                log::error!("Couldn't read scheduled jobs: {}", e);
+
+                return;
+            }
+        };
+
+        let joined = self.irc_state.user(&self.server.user_data.nickname).map(|bot| bot.channels.clone()).unwrap_or_default();
+
+        for job in jobs {
+            if job.target.is_channel_name() && !joined.contains(&self.irc_state.fold(&job.target)) {
+                if self.server.channel(&job.target).is_some() {
+                    continue;
+                }
+
+                log::warn!("Dropping job {} of {}, the bot doesn't join {} anymore", job.id, job.plugin, job.target);
+
+                self.finish_job(storage, &job);
+
+                continue;
+            }
+
+            let plugin = match self.privmsg_event.iter().find(|plugin| plugin.name() == job.plugin) {
+                Some(plugin) => plugin,
+                None => {
+                    log::warn!("Dropping job {} of unknown plugin {}", job.id, job.plugin);
+
+                    self.finish_job(storage, &job);
+
+                    continue;
+                }
+            };
+
+            let user = self.irc_state.prefix(&job.nick);
+            let channel = self.server.channel(&job.target);
+            let responses = plugin.on_scheduled(PrivMsgRequest {
+                server: self.server,
+                irc_state: self.irc_state,
+                user: &user,
+                source: &job.target,
+                channel,
+                command_prefix: self.server.command_prefix(channel),
+                message: &String::new(),
+                is_action: false,
+                resolver: &self.resolver,
+                storage: self.storage,
+                events: &self.event_sender,
+            }, &job);
+
+            for response in responses {
+                self.send_response(response, writer).await;
+            }
+
+            self.finish_job(storage, &job);
+        }
+    }
+
+    fn finish_job(&self, storage: &Storage, job: &scheduler::Job) {
+        if let Err(e) = scheduler::finish(storage, job.id) {
+            log::error!("Couldn't remove job {} of {}: {}", job.id, job.plugin, e);
         }
     }
 
@@ -213,24 +551,355 @@ impl IrcHandler<'_> {
         ]), writer).await;
     }
 
+    // RPL_ISUPPORT <me> <token>... :are supported by this server
+    fn handle_isupport(&mut self, message: &Message) {
+        for token in message.params.iter().skip(1) {
+            if let Some(casemapping) = token.strip_prefix("CASEMAPPING=") {
+                match CaseMapping::parse(casemapping) {
+                    Some(casemapping) => {
+                        // Offline commands like reindex only know the configured one
+                        if casemapping != self.server.casemapping {
+                            log::warn!("{} uses the {:?} casemapping, set casemapping in config.yml so reindex and quotes fold the same way", self.server.hostname, casemapping);
+                        }
+
+                        self.irc_state.set_casemapping(casemapping)
+                    }
+                    None => log::warn!("Unknown casemapping {}, using {:?}", casemapping, self.irc_state.casemapping),
+                }
+            }
+        }
+    }
+
+    async fn handle_join(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        let user = message.prefix.as_ref().unwrap();
+        let channel = &message.params[0];
+        let key = self.irc_state.fold(channel);
+
+        self.irc_state.update_user(user).channels.insert(key);
+
+        // With extended-join the account and realname come after the channel
+        if let Some(account) = message.params.get(1) {
+            self.irc_state.set_account(&user.nick, account);
+        }
+
+        for response in self.dispatch_event(user, channel, &UserEvent::Join) {
+            self.send_response(response, writer).await;
+        }
+    }
+
+    async fn handle_part(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        let user = message.prefix.as_ref().unwrap();
+        let reason = message.params.get(1).map(|reason| reason.as_str());
+
+        for channel in message.params[0].split(',') {
+            if user.nick.eq_ignore_ascii_case(&self.server.user_data.nickname) {
+                self.irc_state.part_channel(channel);
+            } else {
+                self.irc_state.part_user(&user.nick, channel);
+            }
+
+            for response in self.dispatch_event(user, &channel.to_string(), &UserEvent::Part { reason }) {
+                self.send_response(response, writer).await;
+            }
+        }
+    }
+
+    async fn handle_kick(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        let channel = &message.params[0];
+        let nick = &message.params[1];
+        let reason = message.params.get(2).map(|reason| reason.as_str());
+
+        if nick.eq_ignore_ascii_case(&self.server.user_data.nickname) {
+            log::warn!("Kicked from {} by {}", channel, message.prefix.as_ref().map(|prefix| prefix.nick.as_str()).unwrap_or("server"));
+
+            self.irc_state.part_channel(channel);
+        } else {
+            self.irc_state.part_user(nick, channel);
+        }
+
+        if let Some(user) = &message.prefix {
+            for response in self.dispatch_event(user, channel, &UserEvent::Kick { nick, reason }) {
+                self.send_response(response, writer).await;
+            }
+        }
+    }
+
+    async fn handle_quit(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        if let Some(user) = &message.prefix {
+            let reason = message.params.first().map(|reason| reason.as_str());
+
+            for channel in self.user_channels(&user.nick) {
+                for response in self.dispatch_event(user, &channel, &UserEvent::Quit { reason }) {
+                    self.send_response(response, writer).await;
+                }
+            }
+
+            self.irc_state.remove_user(&user.nick);
+        }
+    }
+
+    async fn handle_nick(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        if let Some(user) = &message.prefix {
+            let new_nick = &message.params[0];
+
+            self.irc_state.rename_user(&user.nick, new_nick);
+
+            for channel in self.user_channels(new_nick) {
+                for response in self.dispatch_event(user, &channel, &UserEvent::Nick { new_nick }) {
+                    self.send_response(response, writer).await;
+                }
+            }
+        }
+    }
+
+    // Before the state is updated, so quits and nick changes are logged to the channels the user was in
+    fn log_incoming(&self, message: &Message) {
+        if !self.server.logging.enabled {
+            return;
+        }
+
+        let user = match &message.prefix {
+            Some(user) => user,
+            None => return,
+        };
+
+        let params = &message.params;
+        let mut entries: Vec<(String, LogEvent)> = vec![];
+
+        match (message.command.as_str(), params.len()) {
+            // Notices from the server itself aren't part of any conversation
+            ("PRIVMSG", 2..) | ("NOTICE", 2..) if user.user.is_some() => {
+                let target = if params[0].is_channel_name() { &params[0] } else { &user.nick };
+
+                entries.extend(chat_log::text_event(&message.command, &params[1]).map(|event| (target.clone(), event)));
+            }
+            ("JOIN", 1..) => entries.push((params[0].clone(), LogEvent::Join)),
+            ("PART", 1..) => {
+                for channel in params[0].split(',') {
+                    entries.push((channel.to_string(), LogEvent::Part { reason: params.get(1).map(|reason| reason.as_str()) }));
+                }
+            }
+            ("KICK", 2..) => entries.push((params[0].clone(), LogEvent::Kick { nick: &params[1], reason: params.get(2).map(|reason| reason.as_str()) })),
+            ("TOPIC", 2..) => entries.push((params[0].clone(), LogEvent::Topic(&params[1]))),
+            ("MODE", 2..) if params[0].is_channel_name() => entries.push((params[0].clone(), LogEvent::Mode(params[1..].join(" ")))),
+            ("QUIT", _) => {
+                for channel in self.user_channels(&user.nick) {
+                    entries.push((channel, LogEvent::Quit { reason: params.first().map(|reason| reason.as_str()) }));
+                }
+            }
+            ("NICK", 1..) => {
+                for channel in self.user_channels(&user.nick) {
+                    entries.push((channel, LogEvent::Nick { new_nick: &params[0] }));
+                }
+            }
+            _ => return,
+        }
+
+        let time = chat_log::message_time(message);
+
+        for (target, event) in entries {
+            self.log(&LogEntry { time, target: &target, user, event });
+        }
+    }
+
+    // Servers don't send back what the bot says
+    fn log_outgoing(&self, message: &Message) {
+        if !self.server.logging.enabled || message.params.len() < 2 {
+            return;
+        }
+
+        let nick = &self.server.user_data.nickname;
+        let user = self.irc_state.prefix(nick);
+
+        if let Some(event) = chat_log::text_event(&message.command, &message.params[1]) {
+            self.log(&LogEntry { time: Utc::now(), target: &message.params[0], user: &user, event });
+        }
+    }
+
+    fn log(&self, entry: &LogEntry) {
+        if !chat_log::write(&self.server.logging, &self.server.hostname, self.irc_state.casemapping, entry) || !self.server.logging.index {
+            return;
+        }
+
+        if let Some(storage) = self.storage {
+            log_index::insert(storage, self.server, self.irc_state.casemapping, entry);
+        }
+    }
+
+    // account-notify, sent when a user logs in or out of services
+    fn handle_account(&mut self, message: &Message) {
+        if let (Some(user), Some(account)) = (&message.prefix, message.params.first()) {
+            self.irc_state.set_account(&user.nick, account);
+        }
+    }
+
+    fn user_channels(&self, nick: &str) -> Vec<String> {
+        let mut channels: Vec<String> = self.irc_state.user(nick).map(|user| user.channels.iter().cloned().collect()).unwrap_or_default();
+
+        channels.sort();
+
+        channels
+    }
+
+    // Plugins aren't told about what the bot itself does
+    fn dispatch_event(&mut self, user: &Prefix, channel: &String, event: &UserEvent) -> Vec<PrivMsgResponse> {
+        if user.nick.eq_ignore_ascii_case(&self.server.user_data.nickname) {
+            return vec![];
+        }
+
+        let server_channel = self.server.channel(channel);
+        let mut responses: Vec<PrivMsgResponse> = vec![];
+
+        for plugin in self.privmsg_event {
+            if !self.server.is_plugin_enabled(server_channel, plugin.name()) {
+                continue;
+            }
+
+            responses.extend(plugin.on_event(PrivMsgRequest {
+                server: self.server,
+                irc_state: self.irc_state,
+                user,
+                source: channel,
+                channel: server_channel,
+                command_prefix: self.server.command_prefix(server_channel),
+                message: &String::new(),
+                is_action: false,
+                resolver: &self.resolver,
+                storage: self.storage,
+                events: &self.event_sender,
+            }, event));
+        }
+
+        responses
+    }
+
+    // Transfers are events of the user who gets the file, in private
+    async fn dispatch_dcc_event(&mut self, nick: &str, event: &UserEvent<'_>, writer: &mut (impl AsyncWrite + Unpin)) {
+        let user = self.irc_state.prefix(nick);
+
+        for response in self.dispatch_event(&user, &nick.to_string(), event) {
+            self.send_response(response, writer).await;
+        }
+    }
+
+    // RPL_NAMREPLY, with userhost-in-names each name is a full nick!user@host
+    fn handle_names(&mut self, message: &Message) {
+        let channel = self.irc_state.fold(&message.params[2]);
+
+        for name in message.params[3].split_whitespace() {
+            let name = name.trim_start_matches(|c| "~&@%+".contains(c));
+
+            if let Ok(prefix) = name.parse::<Prefix>() {
+                self.irc_state.update_user(&prefix).channels.insert(channel.clone());
+            }
+        }
+    }
+
+    // RPL_WHOISUSER <me> <nick> <user> <host> * :<realname>
+    fn handle_whois_user(&mut self, message: &Message) {
+        if message.params.len() < 4 {
+            return;
+        }
+
+        self.irc_state.update_user(&Prefix::new_with_all(&message.params[1], Some(&message.params[2]), Some(&message.params[3])));
+    }
+
+    // RPL_WHOISACCOUNT <me> <nick> <account> :is logged in as
+    fn handle_whois_account(&mut self, message: &Message) {
+        if message.params.len() < 3 {
+            return;
+        }
+
+        self.irc_state.set_account(&message.params[1], &message.params[2]);
+    }
+
+    // ERR_NOSUCHNICK <me> <nick> :No such nick/channel, ends the WHOIS of a nick nobody uses
+    async fn handle_no_such_nick(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        if message.params.len() >= 2 && self.irc_state.pending_whois.contains_key(&self.irc_state.fold(&message.params[1])) {
+            self.handle_end_whois(message, writer).await;
+        }
+    }
+
+    // RPL_ENDOFWHOIS, commands waiting for this nick are dispatched again to the plugin that asked for it
+    async fn handle_end_whois(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        let key = self.irc_state.fold(&message.params[1]);
+        let pending = self.irc_state.pending_whois.remove(&key).map(|(_, commands)| commands).unwrap_or_default();
+
+        self.irc_state.whois_completed.insert(key, Instant::now());
+        self.expire_whois();
+
+        for command in pending {
+            let responses = self.dispatch_plugins(&command.user, &command.source, &command.message, false, Some(&command.plugin));
+
+            for response in responses {
+                self.send_response(response, writer).await;
+            }
+        }
+    }
+
     async fn handle_privmsg(&mut self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
         let mut source = &message.params[0];
         let msg = &message.params[1];
+        let user = message.prefix.as_ref().unwrap();
 
         if !source.is_channel_name() {
-            source = &message.prefix.as_ref().unwrap().nick;
+            source = &user.nick;
+        } else {
+            let channel = self.irc_state.fold(source);
+
+            self.irc_state.channel_activity.insert(channel, Instant::now());
+        }
+
+        // account-tag
+        if let Some(account) = message.tags.get("account") {
+            self.irc_state.set_account(&user.nick, account);
+        }
+
+        let is_admin = admin::is_admin(self.server, user);
+
+        if !is_admin && admin::is_ignored(self.server, self.irc_state, user) {
+            log::debug!("Ignoring message from {}", user);
+
+            return;
         }
 
-        if msg.is_ctcp() {
+        // ACTION (/me) is a regular message, only flagged as an action
+        let action = msg.strip_prefix("\u{1}ACTION")
+            .filter(|action| action.is_empty() || action.starts_with(' ') || action.starts_with('\u{1}'))
+            .map(|action| action.trim_end_matches('\u{1}').trim());
+
+        if msg.is_ctcp() && action.is_none() {
             let msg = msg.replace("\u{1}", "");
             let command: &str = msg.split(" ").next().unwrap();
             let msg = msg[command.len()..].trim();
 
+            if source.is_channel_name() && !self.server.ctcp.allow_channel {
+                log::debug!("Ignoring CTCP {} sent to channel {}", command, source);
+
+                return;
+            }
+
+            // Silently dropped, any reply would help a CTCP flood to flood the bot off the network
+            if !self.irc_state.rate_limiter.check_ctcp(&self.server.ctcp.rate_limit) {
+                log::warn!("CTCP rate limit exceeded, dropping CTCP {} from {}", command, user);
+
+                return;
+            }
+
+            if command == "DCC" {
+                self.handle_dcc(user, msg, is_admin, writer).await;
+
+                return;
+            }
+
+            if !is_admin && !self.check_rate_limit(user, source, &format!("CTCP {}", command), false, writer).await {
+                return;
+            }
+
             for event in self.ctcp_event {
                 if let Some(response) = event.execute(CtcpRequest {
                     server: self.server,
-                    irc_state: self.irc_state,
-                    user: message.prefix.as_ref().unwrap(),
+                    user,
                     source,
                     command: &command.to_string(),
                     message: &msg.to_string(),
@@ -240,18 +909,417 @@ impl IrcHandler<'_> {
                     break;
                 }
             }
+        } else if let Some(action) = action {
+            log::info!("[{}] * {} {}", source, user.nick, action);
+
+            for response in self.dispatch_privmsg(user, source, &action.remove_colorization(), true) {
+                self.send_response(response, writer).await;
+            }
         } else {
-            for event in self.privmsg_event {
-                if let Some(response) = event.execute(PrivMsgRequest {
-                    server: self.server,
-                    irc_state: self.irc_state,
-                    user: message.prefix.as_ref().unwrap(),
-                    source,
-                    message: &msg.remove_colorization().to_string(),
-                }) {
-                    self.send_privmsg(response.target, response.message, writer).await;
+            let msg = msg.remove_colorization();
+            let command_prefix = self.server.command_prefix(self.server.channel(source));
+
+            if is_admin && msg == format!("{}dcc", command_prefix) {
+                self.offer_dcc_chat(user, None, writer).await;
+
+                return;
+            }
+
+            // Only commands are rate limited, plain messages don't make the bot do any work
+            let command = msg.strip_prefix(command_prefix)
+                .and_then(|command| command.split_whitespace().next())
+                .map(|command| command.to_string());
+
+            if let Some(command) = command {
+                if !is_admin && !self.check_rate_limit(user, source, &command, true, writer).await {
+                    return;
                 }
             }
+
+            for response in self.dispatch_privmsg(user, source, &msg, false) {
+                self.send_response(response, writer).await;
+            }
+        }
+    }
+
+    fn dispatch_privmsg(&mut self, user: &Prefix, source: &String, msg: &String, is_action: bool) -> Vec<PrivMsgResponse> {
+        if !is_action {
+            if let Some(responses) = admin::execute(self.server, self.irc_state, user, source, msg) {
+                return responses;
+            }
+        }
+
+        self.dispatch_plugins(user, source, msg, is_action, None)
+    }
+
+    fn dispatch_plugins(&mut self, user: &Prefix, source: &String, msg: &String, is_action: bool, only_plugin: Option<&str>) -> Vec<PrivMsgResponse> {
+        let channel = self.server.channel(source);
+        let mut responses: Vec<PrivMsgResponse> = vec![];
+
+        for event in self.privmsg_event {
+            if !self.server.is_plugin_enabled(channel, event.name()) || only_plugin.is_some_and(|plugin| plugin != event.name()) {
+                continue;
+            }
+
+            for response in event.execute(PrivMsgRequest {
+                server: self.server,
+                irc_state: self.irc_state,
+                user,
+                source,
+                channel,
+                command_prefix: self.server.command_prefix(channel),
+                message: msg,
+                is_action,
+                resolver: &self.resolver,
+                storage: self.storage,
+                events: &self.event_sender,
+            }) {
+                if let ResponseType::Whois = response.response_type {
+                    let command = PendingCommand {
+                        plugin: event.name().to_string(),
+                        user: user.clone(),
+                        source: source.clone(),
+                        message: msg.clone(),
+                    };
+
+                    // Dispatched again to this plugin once the WHOIS reply arrives, only once for commands waiting for several nicks
+                    if !self.irc_state.pending_whois.values().flat_map(|(_, commands)| commands).any(|pending| *pending == command) {
+                        let key = self.irc_state.fold(&response.target);
+
+                        self.irc_state.pending_whois.entry(key).or_insert_with(|| (Instant::now(), vec![])).1.push(command);
+                    }
+                }
+
+                responses.push(response);
+            }
+        }
+
+        responses
+    }
+
+    async fn handle_dcc(&mut self, user: &Prefix, msg: &str, is_admin: bool, writer: &mut (impl AsyncWrite + Unpin)) {
+        if !self.server.dcc.enabled {
+            log::warn!("Ignoring DCC from {}: {}", user, msg);
+
+            return;
+        }
+
+        let arguments = dcc::split_arguments(msg);
+        let kind = arguments.first().map(|kind| kind.to_uppercase()).unwrap_or_default();
+
+        match kind.as_str() {
+            // DCC CHAT sessions give access to admin commands, so only admins can open them
+            "CHAT" if is_admin => {
+                let offer = match dcc::parse_chat_offer(&arguments[1..]) {
+                    Some(offer) => offer,
+                    None => {
+                        log::warn!("Invalid DCC CHAT from {}: {}", user, msg);
+
+                        return;
+                    }
+                };
+
+                if offer.port == 0 {
+                    // Passive DCC, the user can't listen so the bot does
+                    self.offer_dcc_chat(user, Some(offer.token.unwrap_or_default()), writer).await;
+                } else {
+                    // Either a regular offer or the answer to a passive offer made by the bot, which has its token.
+                    // The bot only connects to where the admin is, not wherever the offer points to
+                    let nick = self.irc_state.fold(&user.nick);
+                    let timeout = Duration::from_secs(self.server.dcc.timeout);
+                    let answered = match &offer.token {
+                        Some(token) => self.irc_state.dcc_chat_offers.get(token).is_some_and(|(offered_to, offered)| *offered_to == nick && offered.elapsed() < timeout),
+                        None => true,
+                    };
+                    let allowed = self.user_addresses(user).await;
+
+                    if !answered {
+                        log::warn!("Ignoring DCC CHAT from {} with an unknown or expired token: {}", user, msg);
+
+                        return;
+                    }
+
+                    if !allowed.contains(&offer.address.to_canonical()) {
+                        log::warn!("Ignoring DCC CHAT from {} to {}, which isn't where it connects from: {:?}", user, offer.address, allowed);
+
+                        self.send_notice(user.nick.clone(), "The DCC CHAT address isn't where you connect from".to_string(), writer).await;
+
+                        return;
+                    }
+
+                    if let Some(token) = &offer.token {
+                        self.irc_state.dcc_chat_offers.remove(token);
+                    }
+
+                    let (session, outgoing) = self.new_dcc_session(user, true);
+                    let address = SocketAddr::new(offer.address, offer.port);
+
+                    log::info!("Connecting to DCC CHAT {} from {}", address, user);
+
+                    dcc::spawn_chat_connect(session, address, timeout, self.event_sender.clone(), outgoing);
+                }
+            }
+            // DCC RESUME <file> <port> <position> [token]
+            "RESUME" if arguments.len() >= 4 => {
+                let port: u16 = arguments[2].parse().unwrap_or(0);
+                let position: u64 = arguments[3].parse().unwrap_or(0);
+                let token = arguments.get(4);
+
+                let transfer = self.irc_state.dcc_transfers.values().find(|transfer| {
+                    transfer.nick.eq_ignore_ascii_case(&user.nick)
+                        && transfer.port == port
+                        && (port != 0 || transfer.token.as_ref() == token)
+                });
+
+                match transfer {
+                    Some(transfer) if position < transfer.size => {
+                        log::info!("Resuming DCC SEND of {} to {} from {}", transfer.file_name, user, position);
+
+                        transfer.position.store(position, std::sync::atomic::Ordering::SeqCst);
+
+                        let mut accept = format!("DCC ACCEPT {} {} {}", dcc::quote_file_name(&transfer.file_name), port, position);
+
+                        if let Some(token) = token {
+                            accept = format!("{} {}", accept, token);
+                        }
+
+                        self.send_privmsg(user.nick.clone(), format!("\u{1}{}\u{1}", accept), writer).await;
+                    }
+                    _ => log::warn!("Invalid DCC RESUME from {}: {}", user, msg),
+                }
+            }
+            // Answer to a passive DCC SEND: DCC SEND <file> <address> <port> <size> <token>
+            "SEND" if arguments.len() >= 6 => {
+                let address = dcc::parse_address(&arguments[2]);
+                let port: u16 = arguments[3].parse().unwrap_or(0);
+                let token = &arguments[5];
+
+                // The bot only connects to where the user is, not wherever the answer points to
+                let allowed = self.user_addresses(user).await;
+                let transfer = self.irc_state.dcc_transfers.iter_mut().find(|(_, transfer)| {
+                    transfer.nick.eq_ignore_ascii_case(&user.nick) && transfer.port == 0 && !transfer.started && transfer.token.as_ref() == Some(token)
+                });
+
+                match (transfer, address) {
+                    (Some((id, transfer)), Some(address)) if port != 0 && allowed.contains(&address.to_canonical()) => {
+                        let address = SocketAddr::new(address, port);
+
+                        log::info!("Connecting to {} to send {} to {}", address, transfer.file_name, user);
+
+                        transfer.started = true;
+
+                        dcc::spawn_send_connect(*id, address, Duration::from_secs(self.server.dcc.timeout), transfer.path.clone(), transfer.size,
+                                                transfer.position.load(std::sync::atomic::Ordering::SeqCst), self.event_sender.clone());
+                    }
+                    (Some(_), Some(address)) if port != 0 => {
+                        log::warn!("Ignoring DCC SEND from {} to {}, which isn't where it connects from: {:?}", user, address, allowed);
+                    }
+                    _ => log::warn!("Ignoring DCC SEND from {}: {}", user, msg),
+                }
+            }
+            _ => log::warn!("Unsupported DCC from {}: {}", user, msg),
+        }
+    }
+
+    async fn offer_dcc_send(&mut self, nick: &str, file_name: &str, writer: &mut (impl AsyncWrite + Unpin)) {
+        if !self.server.dcc.enabled {
+            return;
+        }
+
+        let (path, size) = match dcc::resolve_file(&self.server.dcc.send_directory, file_name) {
+            Some(file) => file,
+            None => {
+                self.send_notice(nick.to_string(), format!("No such file: {}", file_name), writer).await;
+
+                return;
+            }
+        };
+
+        let user_transfers = self.irc_state.dcc_transfers.values().filter(|transfer| transfer.nick.eq_ignore_ascii_case(nick)).count();
+
+        if user_transfers >= self.server.dcc.max_transfers_per_user {
+            self.send_notice(nick.to_string(), "You already have a transfer in progress, wait for it to finish".to_string(), writer).await;
+
+            return;
+        }
+
+        if self.irc_state.dcc_transfers.len() >= self.server.dcc.max_transfers {
+            self.send_notice(nick.to_string(), "Too many transfers in progress, try again later".to_string(), writer).await;
+
+            return;
+        }
+
+        self.irc_state.dcc_next_id += 1;
+
+        let id = self.irc_state.dcc_next_id;
+        let public_address = dcc::public_address(&self.server.dcc);
+        let position = Arc::new(AtomicU64::new(0));
+
+        let (port, token, address) = if self.server.dcc.passive {
+            let address = public_address.map(|address| dcc::format_address(&address)).unwrap_or_else(|| "0".to_string());
+
+            // Nothing times out a passive offer the user never answers, so it'd keep counting towards the limits
+            dcc::spawn_send_expiry(id, Duration::from_secs(self.server.dcc.timeout), self.event_sender.clone());
+
+            (0, Some(rand::random::<u32>().to_string()), address)
+        } else {
+            let public_address = match public_address {
+                Some(address) => address,
+                None => {
+                    log::error!("DCC public_address isn't configured, can't offer DCC SEND");
+
+                    return;
+                }
+            };
+
+            match dcc::listen(&self.server.dcc).await {
+                Ok((listener, port)) => {
+                    dcc::spawn_send_accept(id, listener, Duration::from_secs(self.server.dcc.timeout), path.clone(), size, position.clone(), self.event_sender.clone());
+
+                    (port, None, dcc::format_address(&public_address))
+                }
+                Err(e) => {
+                    log::error!("Couldn't listen for DCC SEND: {}", e);
+
+                    self.send_notice(nick.to_string(), "Couldn't open a port to send the file".to_string(), writer).await;
+
+                    return;
+                }
+            }
+        };
+
+        let mut offer = format!("DCC SEND {} {} {} {}", dcc::quote_file_name(file_name), address, port, size);
+
+        if let Some(token) = &token {
+            offer = format!("{} {}", offer, token);
+        }
+
+        log::info!("Offering {} ({} bytes) to {}", file_name, size, nick);
+
+        self.irc_state.dcc_transfers.insert(id, DccTransfer {
+            nick: nick.to_string(),
+            file_name: file_name.to_string(),
+            size,
+            port,
+            token,
+            position,
+            path,
+            started: false,
+        });
+
+        self.send_privmsg(nick.to_string(), format!("\u{1}{}\u{1}", offer), writer).await;
+    }
+
+    async fn offer_dcc_chat(&mut self, user: &Prefix, token: Option<String>, writer: &mut (impl AsyncWrite + Unpin)) {
+        if !self.server.dcc.enabled {
+            return;
+        }
+
+        let public_address = dcc::public_address(&self.server.dcc);
+
+        // Reverse DCC, the user listens and answers with its address
+        if self.server.dcc.passive && token.is_none() {
+            let address = public_address.map(|address| dcc::format_address(&address)).unwrap_or_else(|| "0".to_string());
+            let token = rand::random::<u32>().to_string();
+            let timeout = Duration::from_secs(self.server.dcc.timeout);
+
+            // The answer has to come with the token before the offer expires
+            self.irc_state.dcc_chat_offers.retain(|_, (_, offered)| offered.elapsed() < timeout);
+            self.irc_state.dcc_chat_offers.insert(token.clone(), (self.irc_state.fold(&user.nick), Instant::now()));
+
+            self.send_privmsg(user.nick.clone(), format!("\u{1}DCC CHAT chat {} 0 {}\u{1}", address, token), writer).await;
+
+            return;
+        }
+
+        let public_address = match public_address {
+            Some(address) => address,
+            None => {
+                log::error!("DCC public_address isn't configured, can't offer DCC CHAT");
+
+                self.send_notice(user.nick.clone(), "DCC isn't configured to listen for connections".to_string(), writer).await;
+
+                return;
+            }
+        };
+
+        // Anyone could connect to the port, so only the admin's addresses are accepted. When they aren't known the
+        // session needs the chat password
+        let allowed = self.user_addresses(user).await;
+
+        if allowed.is_empty() && self.server.dcc.chat_password.as_deref().is_none_or(|password| password.is_empty()) {
+            log::warn!("Couldn't resolve the host of {} and there's no DCC chat_password, not offering DCC CHAT", user);
+
+            self.send_notice(user.nick.clone(), "Your host couldn't be resolved to check where the DCC CHAT comes from".to_string(), writer).await;
+
+            return;
+        }
+
+        match dcc::listen(&self.server.dcc).await {
+            Ok((listener, port)) => {
+                let (session, outgoing) = self.new_dcc_session(user, !allowed.is_empty());
+                let mut offer = format!("DCC CHAT chat {} {}", dcc::format_address(&public_address), port);
+
+                if let Some(token) = token {
+                    offer = format!("{} {}", offer, token);
+                }
+
+                dcc::spawn_chat_accept(session, listener, Duration::from_secs(self.server.dcc.timeout), allowed, self.event_sender.clone(), outgoing);
+
+                self.send_privmsg(user.nick.clone(), format!("\u{1}{}\u{1}", offer), writer).await;
+            }
+            Err(e) => {
+                log::error!("Couldn't listen for DCC CHAT: {}", e);
+
+                self.send_notice(user.nick.clone(), "Couldn't open a port for DCC CHAT".to_string(), writer).await;
+            }
+        }
+    }
+
+    // Where the user connects from as far as the host tells, nothing for cloaks and hosts that don't resolve
+    async fn user_addresses(&self, user: &Prefix) -> Vec<IpAddr> {
+        match &user.host {
+            Some(host) => self.resolver.lookup_ip(host).await.unwrap_or_default().iter().map(|ip| ip.to_canonical()).collect(),
+            None => vec![],
+        }
+    }
+
+    fn new_dcc_session(&mut self, user: &Prefix, authenticated: bool) -> (u64, UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded();
+
+        self.irc_state.dcc_next_id += 1;
+
+        let session = self.irc_state.dcc_next_id;
+
+        self.irc_state.dcc_sessions.insert(session, DccChatSession {
+            user: user.clone(),
+            sender,
+            connected: false,
+            authenticated,
+        });
+
+        (session, receiver)
+    }
+
+    async fn check_rate_limit(&mut self, user: &Prefix, source: &str, command: &str, notify: bool, writer: &mut (impl AsyncWrite + Unpin)) -> bool {
+        let channel = if source.is_channel_name() { Some(self.irc_state.fold(source)) } else { None };
+
+        match self.irc_state.rate_limiter.check(&self.server.rate_limit, user, channel.as_deref(), command) {
+            RateLimitResult::Allowed => true,
+            RateLimitResult::Limited => {
+                log::debug!("Rate limited {} using {}", user, command);
+
+                false
+            }
+            RateLimitResult::Ignored(duration) => {
+                log::warn!("Temporarily ignoring {} for {:?} because of flooding", user, duration);
+
+                if notify {
+                    self.send_notice(user.nick.clone(), format!("You're sending commands too fast, ignoring you for {} seconds", duration.as_secs()), writer).await;
+                }
+
+                false
+            }
         }
     }
 
@@ -281,6 +1349,42 @@ impl IrcHandler<'_> {
         self.write_message(&Message::new("PONG".to_string(), message.params.clone()), writer).await;
     }
 
+    async fn send_response(&mut self, response: PrivMsgResponse, writer: &mut (impl AsyncWrite + Unpin)) {
+        match response.response_type {
+            ResponseType::DccSend => {
+                self.offer_dcc_send(&response.target, &response.message, writer).await;
+
+                return;
+            }
+            ResponseType::Whois => {
+                self.write_message(&Message::new("WHOIS".to_string(), vec![
+                    response.target,
+                ]), writer).await;
+
+                return;
+            }
+            ResponseType::Topic => {
+                self.write_message(&Message::new("TOPIC".to_string(), vec![
+                    response.target,
+                    response.message,
+                ]), writer).await;
+
+                return;
+            }
+            _ => (),
+        }
+
+        // Responses may span multiple lines, IRC doesn't allow line breaks inside a message
+        for line in response.message.lines().filter(|line| !line.is_empty()) {
+            match response.response_type {
+                ResponseType::PrivMsg => self.send_privmsg(response.target.clone(), line.to_string(), writer).await,
+                ResponseType::Notice => self.send_notice(response.target.clone(), line.to_string(), writer).await,
+                ResponseType::Action => self.send_privmsg(response.target.clone(), format!("\u{1}ACTION {}\u{1}", line), writer).await,
+                ResponseType::DccSend | ResponseType::Whois | ResponseType::Topic => (),
+            }
+        }
+    }
+
     async fn send_privmsg(&self, target: String, message: String, writer: &mut (impl AsyncWrite + Unpin)) {
         self.write_message(&Message::new("PRIVMSG".to_string(), vec![
             target,
@@ -296,6 +1400,10 @@ impl IrcHandler<'_> {
     }
 
     async fn write_message(&self, message: &Message, writer: &mut (impl AsyncWrite + Unpin)) {
+        if message.command == "PRIVMSG" || message.command == "NOTICE" {
+            self.log_outgoing(message);
+        }
+
         let message = message.to_string();
 
         log::debug!("{}", message);
@@ -1,11 +1,67 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use simple_irc::Prefix;
+
+use crate::dcc::{DccChatSession, DccTransfer};
+use crate::rate_limit::RateLimiter;
+
 pub struct IrcState {
     pub initial_connection: bool,
     pub negotiating_cap: bool,
     pub negotiating_sasl: bool,
-    pub sent_user: bool,
+    // Set once the server welcomed the bot, nothing can be sent to channels or users before it
+    pub registered: bool,
     pub cap_requested: Vec<String>,
     pub cap_negotiated: Vec<String>,
     pub cap_accepted: Vec<String>,
+    pub rate_limiter: RateLimiter,
+    pub dcc_sessions: HashMap<u64, DccChatSession>,
+    pub dcc_transfers: HashMap<u64, DccTransfer>,
+    pub dcc_next_id: u64,
+    // Passive DCC CHAT offers waiting for the admin to answer, keyed by token: folded nick and when it was offered
+    pub dcc_chat_offers: HashMap<String, (String, Instant)>,
+    // Users sharing a channel with the bot, keyed by folded nick
+    pub users: HashMap<String, User>,
+    // Commands waiting for a WHOIS reply and when it was asked for, keyed by folded nick
+    pub pending_whois: HashMap<String, (Instant, Vec<PendingCommand>)>,
+    // Nicks whose WHOIS was answered recently, so commands waiting for them don't ask again, keyed by folded nick
+    pub whois_completed: HashMap<String, Instant>,
+    pub casemapping: CaseMapping,
+    // Last time someone talked in each channel, keyed by folded channel
+    pub channel_activity: HashMap<String, Instant>,
+    // Next time each announcement is due, keyed by folded channel and announcement name
+    pub announcements: HashMap<(String, String), DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    // Services account the user is logged in to, when the server tells it
+    pub account: Option<String>,
+    // Folded channel names
+    pub channels: HashSet<String>,
+}
+
+// How the server compares nicks and channels, from CASEMAPPING in RPL_ISUPPORT
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseMapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459,
+}
+
+#[derive(PartialEq)]
+pub struct PendingCommand {
+    pub plugin: String,
+    pub user: Prefix,
+    pub source: String,
+    pub message: String,
 }
 
 impl Default for IrcState {
@@ -14,13 +70,146 @@ impl Default for IrcState {
             initial_connection: true,
             negotiating_cap: false,
             negotiating_sasl: false,
-            sent_user: false,
+            registered: false,
             cap_requested: vec![
                 "multi-prefix".to_string(), // https://ircv3.net/specs/extensions/multi-prefix-3.1.html
                 "userhost-in-names".to_string(), // https://ircv3.net/specs/extensions/userhost-in-names-3.2
             ],
             cap_negotiated: vec![],
             cap_accepted: vec![],
+            rate_limiter: RateLimiter::default(),
+            dcc_sessions: HashMap::new(),
+            dcc_transfers: HashMap::new(),
+            dcc_next_id: 0,
+            dcc_chat_offers: HashMap::new(),
+            users: HashMap::new(),
+            pending_whois: HashMap::new(),
+            whois_completed: HashMap::new(),
+            // What servers not sending CASEMAPPING use
+            casemapping: CaseMapping::Rfc1459,
+            channel_activity: HashMap::new(),
+            announcements: HashMap::new(),
+        }
+    }
+}
+
+impl CaseMapping {
+    pub fn parse(casemapping: &str) -> Option<Self> {
+        match casemapping.to_lowercase().as_str() {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    // Lowercase form of a nick or channel, equal for names the server considers the same
+    pub fn fold(&self, name: &str) -> String {
+        name.chars().map(|c| match (self, c) {
+            (CaseMapping::Rfc1459, '^') => '~',
+            (CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459, '[') => '{',
+            (CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459, ']') => '}',
+            (CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459, '\\') => '|',
+            _ => c.to_ascii_lowercase(),
+        }).collect()
+    }
+}
+
+impl IrcState {
+    pub fn fold(&self, name: &str) -> String {
+        self.casemapping.fold(name)
+    }
+
+    // Nicks known before the server told its casemapping are folded again
+    pub fn set_casemapping(&mut self, casemapping: CaseMapping) {
+        self.casemapping = casemapping;
+        self.users = self.users.drain().map(|(_, user)| (casemapping.fold(&user.nick), user)).collect();
+    }
+
+    pub fn user(&self, nick: &str) -> Option<&User> {
+        self.users.get(&self.fold(nick))
+    }
+
+    // The full hostmask when the user is known, only the nick otherwise
+    pub fn prefix(&self, nick: &str) -> Prefix {
+        self.user(nick).map(|user| Prefix::new_with_all(&user.nick, user.user.as_deref(), user.host.as_deref())).unwrap_or_else(|| Prefix::new(nick))
+    }
+
+    pub fn is_whois_completed(&self, nick: &str) -> bool {
+        self.whois_completed.contains_key(&self.fold(nick))
+    }
+
+    // Adds the user if it isn't known yet, user and host are only replaced when known
+    pub fn update_user(&mut self, prefix: &Prefix) -> &mut User {
+        let key = self.fold(&prefix.nick);
+        let user = self.users.entry(key).or_insert_with(|| User {
+            nick: prefix.nick.clone(),
+            user: None,
+            host: None,
+            account: None,
+            channels: HashSet::new(),
+        });
+
+        user.nick = prefix.nick.clone();
+
+        if prefix.user.is_some() {
+            user.user = prefix.user.clone();
+        }
+
+        if prefix.host.is_some() {
+            user.host = prefix.host.clone();
+        }
+
+        user
+    }
+
+    // Only for users already known, "*" means logged out
+    pub fn set_account(&mut self, nick: &str, account: &str) {
+        let key = self.fold(nick);
+
+        if let Some(user) = self.users.get_mut(&key) {
+            user.account = Some(account.to_string()).filter(|account| account != "*");
+        }
+    }
+
+    pub fn rename_user(&mut self, old_nick: &str, new_nick: &str) {
+        let key = self.fold(old_nick);
+
+        if let Some(mut user) = self.users.remove(&key) {
+            user.nick = new_nick.to_string();
+
+            self.users.insert(self.fold(new_nick), user);
+        }
+    }
+
+    pub fn remove_user(&mut self, nick: &str) {
+        let key = self.fold(nick);
+
+        self.users.remove(&key);
+    }
+
+    // Users are forgotten once they don't share any channel with the bot
+    pub fn part_user(&mut self, nick: &str, channel: &str) {
+        let key = self.fold(nick);
+
+        let channel = self.fold(channel);
+
+        if let Some(user) = self.users.get_mut(&key) {
+            user.channels.remove(&channel);
+
+            if user.channels.is_empty() {
+                self.users.remove(&key);
+            }
         }
     }
+
+    pub fn part_channel(&mut self, channel: &str) {
+        let channel = self.fold(channel);
+
+        self.users.retain(|_, user| {
+            user.channels.remove(&channel);
+
+            !user.channels.is_empty()
+        });
+    }
 }
\ No newline at end of file
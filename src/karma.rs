@@ -0,0 +1,330 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rusqlite::types::Value;
+
+use crate::config::KarmaConfig;
+use crate::irc_ext::IrcExt;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+use crate::storage::{self, StorageScope};
+
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE karma_things (
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        thing TEXT NOT NULL,
+        display_thing TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        ups INTEGER NOT NULL,
+        downs INTEGER NOT NULL,
+        PRIMARY KEY (server, channel, thing)
+    );
+    CREATE INDEX karma_things_score ON karma_things (server, channel, score);
+    CREATE TABLE karma_changes (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        thing TEXT NOT NULL,
+        nick TEXT NOT NULL,
+        change INTEGER NOT NULL,
+        reason TEXT NOT NULL,
+        time INTEGER NOT NULL
+    );
+    CREATE INDEX karma_changes_thing ON karma_changes (server, channel, thing, time);
+"];
+// Things shown by .karma top and .karma bottom
+const MAX_LISTED: usize = 5;
+// Reasons shown by .karma <thing>
+const MAX_REASONS: usize = 3;
+
+pub struct KarmaPrivMsgEvent {
+    config: KarmaConfig,
+    // Recent changes of each user, keyed by user@host so changing nicks doesn't help
+    changes: Mutex<HashMap<String, VecDeque<(Instant, String)>>>,
+}
+
+impl KarmaPrivMsgEvent {
+    pub fn new(config: &KarmaConfig) -> Self {
+        KarmaPrivMsgEvent {
+            config: config.clone(),
+            changes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Records the change if the user didn't change the same thing recently and is within the limit
+    fn is_allowed(&self, user: &str, key: &str) -> bool {
+        let mut changes = self.changes.lock().unwrap();
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(self.config.cooldown);
+        let period = Duration::from_secs(self.config.period);
+        let recent = changes.entry(user.to_string()).or_default();
+
+        while recent.front().is_some_and(|(time, _)| now.duration_since(*time) >= cooldown.max(period)) {
+            recent.pop_front();
+        }
+
+        let too_soon = recent.iter().any(|(time, changed)| changed == key && now.duration_since(*time) < cooldown);
+        let in_period = recent.iter().filter(|(time, _)| now.duration_since(*time) < period).count();
+
+        if too_soon || (self.config.max_changes > 0 && in_period >= self.config.max_changes) {
+            return false;
+        }
+
+        recent.push_back((now, key.to_string()));
+
+        true
+    }
+
+    fn change(&self, request: &PrivMsgRequest, storage: &StorageScope, thing: &str, change: i64, reason: Option<&str>) -> Result<String> {
+        let key = request.irc_state.fold(thing);
+        let filter = [("thing", Value::from(key.clone()))];
+        let current = storage.select("things", &filter, "", 1)?.into_iter().next();
+        let (score, ups, downs) = current.as_ref()
+            .map(|row| (storage::integer(row, "score"), storage::integer(row, "ups"), storage::integer(row, "downs")))
+            .unwrap_or_default();
+        let score = score + change;
+
+        storage.replace("things", &[
+            ("thing", Value::from(key.clone())),
+            ("display_thing", Value::from(thing.to_string())),
+            ("score", Value::from(score)),
+            ("ups", Value::from(ups + change.max(0))),
+            ("downs", Value::from(downs - change.min(0))),
+        ])?;
+        storage.insert("changes", &[
+            ("thing", Value::from(key)),
+            ("nick", Value::from(request.user.nick.clone())),
+            ("change", Value::from(change)),
+            ("reason", Value::from(reason.unwrap_or_default().to_string())),
+            ("time", Value::from(storage::now())),
+        ])?;
+
+        Ok(format!("{} now has {} karma", thing, score))
+    }
+
+    // alice has 3 karma (+4/-1), recent reasons: for helping (bob)
+    fn karma(&self, request: &PrivMsgRequest, storage: &StorageScope, thing: &str) -> Result<String> {
+        let key = request.irc_state.fold(thing);
+        let filter = [("thing", Value::from(key))];
+        let row = match storage.select("things", &filter, "", 1)?.into_iter().next() {
+            Some(row) => row,
+            None => return Ok(format!("{} doesn't have any karma", thing)),
+        };
+
+        let reasons: Vec<String> = storage.select("changes", &filter, "time DESC", 0)?.iter()
+            .filter(|change| !storage::text(change, "reason").is_empty())
+            .take(MAX_REASONS)
+            .map(|change| format!("{} {} ({})", if storage::integer(change, "change") > 0 { "+" } else { "-" }, storage::text(change, "reason"), storage::text(change, "nick")))
+            .collect();
+        let reasons = if reasons.is_empty() { "".to_string() } else { format!(", recent reasons: {}", reasons.join(", ")) };
+
+        Ok(format!("{} has {} karma (+{}/-{}){}",
+                   storage::text(&row, "display_thing"),
+                   storage::integer(&row, "score"),
+                   storage::integer(&row, "ups"),
+                   storage::integer(&row, "downs"),
+                   reasons))
+    }
+
+    fn leaderboard(&self, request: &PrivMsgRequest, storage: &StorageScope, top: bool) -> Result<String> {
+        let things = storage.select("things", &[], if top { "score DESC" } else { "score ASC" }, MAX_LISTED)?;
+
+        if things.is_empty() {
+            return Ok(format!("Nothing has karma in {} yet", request.source));
+        }
+
+        let things: Vec<String> = things.iter().map(|thing| format!("{} ({})", storage::text(thing, "display_thing"), storage::integer(thing, "score"))).collect();
+
+        Ok(format!("{} karma in {}: {}", if top { "Top" } else { "Bottom" }, request.source, things.join(", ")))
+    }
+}
+
+// thing++, thing--, (multi word)++ and (multi word)--, a # after them starts the reason
+fn parse_changes(message: &str) -> (Vec<(String, i64)>, Option<String>) {
+    let mut changes: Vec<(String, i64)> = vec![];
+    let mut rest = message.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(reason) = rest.strip_prefix('#').filter(|_| !changes.is_empty()) {
+            return (changes, Some(reason.trim().to_string()).filter(|reason| !reason.is_empty()));
+        }
+
+        let (token, after) = next_token(rest);
+
+        changes.extend(parse_change(token));
+        rest = after.trim_start();
+    }
+
+    (changes, None)
+}
+
+// Words, and things between parentheses when they're followed by ++ or --
+fn next_token(text: &str) -> (&str, &str) {
+    if let Some(close) = text.strip_prefix('(').and_then(|_| text.find(')')) {
+        let end = close + 3;
+        let is_change = text[close + 1..].starts_with("++") || text[close + 1..].starts_with("--");
+
+        if is_change && text[end..].chars().next().is_none_or(char::is_whitespace) {
+            return text.split_at(end);
+        }
+    }
+
+    text.split_at(text.find(char::is_whitespace).unwrap_or(text.len()))
+}
+
+fn parse_change(token: &str) -> Option<(String, i64)> {
+    let (thing, change) = match (token.strip_suffix("++"), token.strip_suffix("--")) {
+        (Some(thing), _) => (thing, 1),
+        (_, Some(thing)) => (thing, -1),
+        _ => return None,
+    };
+
+    let thing = thing.strip_prefix('(').and_then(|thing| thing.strip_suffix(')')).unwrap_or(thing);
+    let thing = thing.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    // Arrows like <-- and --> aren't karma
+    if !thing.chars().any(char::is_alphanumeric) {
+        return None;
+    }
+
+    Some((thing, change))
+}
+
+impl PrivMsgEvent for KarmaPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "karma"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        // Karma is kept for each channel
+        if !request.source.is_channel_name() {
+            return vec![];
+        }
+
+        let storage = match request.storage(self.name()) {
+            Some(storage) => storage,
+            None => return vec![],
+        };
+
+        let reply = |message: String| PrivMsgResponse {
+            target: request.source.clone(),
+            message,
+            response_type: ResponseType::PrivMsg,
+        };
+
+        let result = if let Some(arguments) = request.command("karma") {
+            match arguments {
+                "top" => self.leaderboard(&request, &storage, true),
+                "bottom" => self.leaderboard(&request, &storage, false),
+                "" => self.karma(&request, &storage, &request.user.nick),
+                thing => self.karma(&request, &storage, thing.trim_start_matches('(').trim_end_matches(')')),
+            }.map(|message| vec![reply(message)])
+        } else if request.message.starts_with(request.command_prefix) {
+            // Other commands may have ++ or -- in their arguments
+            return vec![];
+        } else {
+            let (changes, reason) = parse_changes(request.message);
+            let user = format!("{}@{}", request.user.user.as_deref().unwrap_or_default(), request.user.host.as_deref().unwrap_or(&request.user.nick));
+            let mut messages: Vec<String> = vec![];
+            let mut limited = false;
+            let mut result = Ok(());
+
+            for (thing, change) in changes {
+                if request.irc_state.fold(&thing) == request.irc_state.fold(&request.user.nick) {
+                    messages.push("You can't change your own karma".to_string());
+
+                    continue;
+                }
+
+                if !self.is_allowed(&user, &format!("{} {}", request.irc_state.fold(request.source), request.irc_state.fold(&thing))) {
+                    limited = true;
+
+                    continue;
+                }
+
+                match self.change(&request, &storage, &thing, change, reason.as_deref()) {
+                    Ok(message) => messages.push(message),
+                    Err(e) => {
+                        result = Err(e);
+
+                        break;
+                    }
+                }
+            }
+
+            result.map(|_| {
+                let mut responses: Vec<PrivMsgResponse> = vec![];
+
+                if !messages.is_empty() {
+                    responses.push(reply(messages.join(", ")));
+                }
+
+                if limited {
+                    responses.push(PrivMsgResponse {
+                        target: request.user.nick.clone(),
+                        message: "You're changing karma too often, try again later".to_string(),
+                        response_type: ResponseType::Notice,
+                    });
+                }
+
+                responses
+            })
+        };
+
+        result.unwrap_or_else(|e| {
+            log::error!("Couldn't access the karma of {}: {}", request.source, e);
+
+            vec![reply("Couldn't access the karma".to_string())]
+        })
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(message: &str) -> Vec<(String, i64)> {
+        parse_changes(message).0
+    }
+
+    #[test]
+    fn words_are_changed() {
+        assert_eq!(changes("rust++ and c--"), vec![("rust".to_string(), 1), ("c".to_string(), -1)]);
+        assert_eq!(changes("nothing to see here"), vec![]);
+    }
+
+    #[test]
+    fn things_between_parentheses_are_one_thing() {
+        assert_eq!(changes("(async   rust)++ is nice"), vec![("async rust".to_string(), 1)]);
+        assert_eq!(changes("(not a change) rust--"), vec![("rust".to_string(), -1)]);
+    }
+
+    #[test]
+    fn arrows_are_not_karma() {
+        assert_eq!(changes("go here <-- or there -->"), vec![]);
+        assert_eq!(parse_change("++"), None);
+        assert_eq!(parse_change("()--"), None);
+    }
+
+    #[test]
+    fn reasons_come_after_a_hash() {
+        assert_eq!(parse_changes("rust++ # fearless concurrency"), (vec![("rust".to_string(), 1)], Some("fearless concurrency".to_string())));
+        assert_eq!(parse_changes("rust++ #"), (vec![("rust".to_string(), 1)], None));
+        // Before any change it's just a word, like a channel name
+        assert_eq!(parse_changes("#rust++ is great"), (vec![("#rust".to_string(), 1)], None));
+    }
+
+    #[test]
+    fn tokens_are_split() {
+        assert_eq!(next_token("(a b)++ c"), ("(a b)++", " c"));
+        assert_eq!(next_token("(a b)++c"), ("(a", " b)++c"));
+        assert_eq!(next_token("(a b) c"), ("(a", " b) c"));
+        assert_eq!(next_token("word"), ("word", ""));
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+
+use crate::chat_log::{LogEntry, LogEvent};
+use crate::config::Server;
+use crate::irc_ext::IrcExt;
+use crate::irc_state::CaseMapping;
+use crate::storage::Storage;
+
+// Channel messages and actions, searched through an FTS5 index kept in sync by triggers
+pub const MIGRATIONS: &[&str] = &["
+    CREATE TABLE log_index_messages (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        nick TEXT NOT NULL,
+        display_nick TEXT NOT NULL,
+        action INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        time INTEGER NOT NULL
+    );
+    CREATE INDEX log_index_messages_time ON log_index_messages (server, channel, time);
+    CREATE INDEX log_index_messages_nick ON log_index_messages (server, channel, nick, time);
+    CREATE VIRTUAL TABLE log_index_search USING fts5 (text, content = 'log_index_messages', content_rowid = 'id');
+    CREATE TRIGGER log_index_messages_insert AFTER INSERT ON log_index_messages BEGIN
+        INSERT INTO log_index_search (rowid, text) VALUES (new.id, new.text);
+    END;
+    CREATE TRIGGER log_index_messages_delete AFTER DELETE ON log_index_messages BEGIN
+        INSERT INTO log_index_search (log_index_search, rowid, text) VALUES ('delete', old.id, old.text);
+    END;
+"];
+
+// Messages read from the log files before they're inserted while rebuilding
+const REBUILD_BATCH: usize = 1000;
+
+pub struct IndexedMessage {
+    pub nick: String,
+    pub action: bool,
+    pub text: String,
+    // Seconds since the epoch
+    pub time: i64,
+}
+
+#[derive(Default)]
+pub struct Search {
+    // Folded
+    pub channel: String,
+    // Folded
+    pub nick: Option<String>,
+    // Every word has to be in the message
+    pub words: Vec<String>,
+    // Seconds since the epoch, from inclusive and to exclusive
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub oldest_first: bool,
+    pub limit: usize,
+}
+
+// Private messages aren't indexed, anyone in the channel could search them otherwise
+pub fn insert(storage: &Storage, server: &Server, casemapping: CaseMapping, entry: &LogEntry) {
+    let (text, action) = match entry.event {
+        LogEvent::Message(text) => (text, false),
+        LogEvent::Action(text) => (text, true),
+        _ => return,
+    };
+
+    if !entry.target.is_channel_name() || is_command(server, entry.target, text, action) {
+        return;
+    }
+
+    let result = storage.with_connection(|connection| {
+        insert_message(connection, &server.hostname, &casemapping.fold(entry.target), &casemapping.fold(&entry.user.nick), &entry.user.nick, action, text, entry.time.timestamp())
+    });
+
+    if let Err(e) = result {
+        log::error!("Couldn't index message in {}: {}", entry.target, e);
+    }
+}
+
+// Commands would mostly find the searches themselves
+fn is_command(server: &Server, channel: &str, text: &str, action: bool) -> bool {
+    !action && text.starts_with(server.command_prefix(server.channel(channel)))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn insert_message(connection: &Connection, server: &str, channel: &str, nick: &str, display_nick: &str, action: bool, text: &str, time: i64) -> rusqlite::Result<usize> {
+    connection.execute("INSERT INTO log_index_messages (server, channel, nick, display_nick, action, text, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                       params![server, channel, nick, display_nick, action, text, time])
+}
+
+pub fn search(storage: &Storage, server: &str, search: &Search) -> Result<Vec<IndexedMessage>> {
+    let mut sql = "SELECT display_nick, action, text, time FROM log_index_messages WHERE server = ? AND channel = ?".to_string();
+    let mut values = vec![Value::from(server.to_string()), Value::from(search.channel.clone())];
+
+    if let Some(nick) = &search.nick {
+        sql.push_str(" AND nick = ?");
+        values.push(Value::from(nick.clone()));
+    }
+
+    if let Some(from) = search.from {
+        sql.push_str(" AND time >= ?");
+        values.push(Value::from(from));
+    }
+
+    if let Some(to) = search.to {
+        sql.push_str(" AND time < ?");
+        values.push(Value::from(to));
+    }
+
+    if !search.words.is_empty() {
+        // Quoted, so words are never taken as FTS5 operators
+        let query: Vec<String> = search.words.iter().map(|word| format!("\"{}\"", word.replace('"', "\"\""))).collect();
+
+        sql.push_str(" AND id IN (SELECT rowid FROM log_index_search WHERE log_index_search MATCH ?)");
+        values.push(Value::from(query.join(" ")));
+    }
+
+    let order = if search.oldest_first { "ASC" } else { "DESC" };
+
+    sql = format!("{} ORDER BY time {1}, id {1} LIMIT {2}", sql, order, search.limit);
+
+    storage.with_connection(|connection| {
+        let mut statement = connection.prepare(&sql)?;
+        let messages = statement.query_map(params_from_iter(values), |row| {
+            Ok(IndexedMessage {
+                nick: row.get(0)?,
+                action: row.get(1)?,
+                text: row.get(2)?,
+                time: row.get(3)?,
+            })
+        })?;
+
+        messages.collect()
+    })
+}
+
+// Replaces everything indexed for the server with what is in its log files, returns how many messages were indexed.
+// Channels and nicks are folded with the configured casemapping, the one the bot uses until the server tells its own
+pub fn rebuild(storage: &Storage, server: &Server) -> Result<usize> {
+    let config = &server.logging;
+    let timezone = config.timezone.parse::<Tz>().unwrap_or(Tz::UTC);
+    let directory = Path::new(&config.directory).join(&server.hostname);
+    let mut batch: Vec<(String, IndexedMessage)> = vec![];
+    let mut indexed = 0;
+
+    storage.with_connection(|connection| connection.execute("DELETE FROM log_index_messages WHERE server = ?1", [&server.hostname]))?;
+
+    for channel in fs::read_dir(&directory)? {
+        let channel = channel?;
+        let channel_name = server.casemapping.fold(&channel.file_name().to_string_lossy());
+
+        if !channel.file_type()?.is_dir() || !channel_name.as_str().is_channel_name() {
+            continue;
+        }
+
+        // Days logged in more than one format are read from the one that keeps the most
+        let mut days: BTreeMap<String, (usize, String)> = BTreeMap::new();
+
+        for file in fs::read_dir(channel.path())? {
+            let path = file?.path();
+            let (day, extension) = match (path.file_stem(), path.extension()) {
+                (Some(day), Some(extension)) => (day.to_string_lossy().to_string(), extension.to_string_lossy().to_string()),
+                _ => continue,
+            };
+            let preference = match extension.as_str() {
+                "jsonl" => 0,
+                "txt" => 1,
+                "log" => 2,
+                _ => continue,
+            };
+
+            if days.get(&day).is_none_or(|(current, _)| preference < *current) {
+                days.insert(day, (preference, extension));
+            }
+        }
+
+        for (day, (_, extension)) in days {
+            let path = channel.path().join(format!("{}.{}", day, extension));
+            let date = match NaiveDate::parse_from_str(&day, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => continue,
+            };
+
+            for line in fs::read_to_string(&path)?.lines() {
+                let message = match extension.as_str() {
+                    "jsonl" => parse_jsonl(line),
+                    "txt" => parse_irccloud(line, timezone),
+                    _ => parse_text(line, date, timezone),
+                };
+
+                batch.extend(message
+                    .filter(|message| !is_command(server, &channel_name, &message.text, message.action))
+                    .map(|message| (channel_name.clone(), message)));
+
+                if batch.len() >= REBUILD_BATCH {
+                    indexed += insert_batch(storage, server, &mut batch)?;
+                }
+            }
+        }
+    }
+
+    indexed += insert_batch(storage, server, &mut batch)?;
+
+    Ok(indexed)
+}
+
+// Each batch is inserted in its own transaction, returns how many messages were inserted
+fn insert_batch(storage: &Storage, server: &Server, batch: &mut Vec<(String, IndexedMessage)>) -> Result<usize> {
+    storage.with_connection(|connection| {
+        let transaction = connection.unchecked_transaction()?;
+
+        for (channel, message) in batch.iter() {
+            insert_message(&transaction, &server.hostname, channel, &server.casemapping.fold(&message.nick), &message.nick, message.action, &message.text, message.time)?;
+        }
+
+        transaction.commit()
+    })?;
+
+    Ok(batch.drain(..).count())
+}
+
+fn parse_jsonl(line: &str) -> Option<IndexedMessage> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    let action = match value["type"].as_str()? {
+        "message" => false,
+        "action" => true,
+        _ => return None,
+    };
+
+    Some(IndexedMessage {
+        nick: value["nick"].as_str()?.to_string(),
+        action,
+        text: value["text"].as_str()?.to_string(),
+        time: DateTime::parse_from_rfc3339(value["time"].as_str()?).ok()?.timestamp(),
+    })
+}
+
+// [2024-01-02 13:30:00] <nick> text or [2024-01-02 13:30:00] — nick text
+fn parse_irccloud(line: &str, timezone: Tz) -> Option<IndexedMessage> {
+    let (time, rest) = line.strip_prefix('[')?.split_once("] ")?;
+    let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").ok()?;
+    let (nick, action, text) = parse_line(rest, "— ")?;
+
+    Some(IndexedMessage { nick, action, text, time: timezone.from_local_datetime(&time).earliest()?.timestamp() })
+}
+
+// 13:30:00 <nick> text or 13:30:00  * nick text, the date comes from the file name
+fn parse_text(line: &str, date: NaiveDate, timezone: Tz) -> Option<IndexedMessage> {
+    let (time, rest) = line.split_once(' ')?;
+    let time = date.and_time(NaiveTime::parse_from_str(time, "%H:%M:%S").ok()?);
+    let (nick, action, text) = parse_line(rest, " * ")?;
+
+    Some(IndexedMessage { nick, action, text, time: timezone.from_local_datetime(&time).earliest()?.timestamp() })
+}
+
+fn parse_line(line: &str, action_prefix: &str) -> Option<(String, bool, String)> {
+    if let Some(message) = line.strip_prefix('<') {
+        let (nick, text) = message.split_once("> ")?;
+
+        return Some((nick.to_string(), false, text.to_string()));
+    }
+
+    let (nick, text) = line.strip_prefix(action_prefix)?.split_once(' ')?;
+
+    Some((nick.to_string(), true, text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn fields(message: Option<IndexedMessage>) -> Option<(String, bool, String, i64)> {
+        message.map(|message| (message.nick, message.action, message.text, message.time))
+    }
+
+    fn message(nick: &str, action: bool, text: &str, time: i64) -> Option<(String, bool, String, i64)> {
+        Some((nick.to_string(), action, text.to_string(), time))
+    }
+
+    fn timestamp(hour: u32, minute: u32, second: u32) -> i64 {
+        Utc.with_ymd_and_hms(2024, 1, 2, hour, minute, second).unwrap().timestamp()
+    }
+
+    #[test]
+    fn jsonl_messages_and_actions_are_parsed() {
+        let line = r##"{"time":"2024-01-02T13:30:00.000Z","type":"message","target":"#rust","nick":"alice","text":"hi there"}"##;
+
+        assert_eq!(fields(parse_jsonl(line)), message("alice", false, "hi there", timestamp(13, 30, 0)));
+
+        let line = r#"{"time":"2024-01-02T10:30:00.000-03:00","type":"action","nick":"bob","text":"waves"}"#;
+
+        assert_eq!(fields(parse_jsonl(line)), message("bob", true, "waves", timestamp(13, 30, 0)));
+    }
+
+    #[test]
+    fn other_jsonl_lines_are_skipped() {
+        assert!(parse_jsonl(r#"{"time":"2024-01-02T13:30:00.000Z","type":"join","nick":"alice"}"#).is_none());
+        assert!(parse_jsonl(r#"{"type":"message","nick":"alice","text":"no time"}"#).is_none());
+        assert!(parse_jsonl("not json").is_none());
+    }
+
+    #[test]
+    fn irccloud_lines_are_parsed() {
+        assert_eq!(fields(parse_irccloud("[2024-01-02 13:30:00] <alice> hi > there", Tz::UTC)), message("alice", false, "hi > there", timestamp(13, 30, 0)));
+        assert_eq!(fields(parse_irccloud("[2024-01-02 10:30:00] — bob waves", Tz::America__Sao_Paulo)), message("bob", true, "waves", timestamp(13, 30, 0)));
+        assert!(parse_irccloud("[2024-01-02 13:30:00] → alice joined (a@b)", Tz::UTC).is_none());
+        assert!(parse_irccloud("2024-01-02 13:30:00 <alice> hi", Tz::UTC).is_none());
+    }
+
+    #[test]
+    fn text_lines_are_parsed() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+
+        assert_eq!(fields(parse_text("13:30:00 <alice> hi there", date, Tz::UTC)), message("alice", false, "hi there", timestamp(13, 30, 0)));
+        assert_eq!(fields(parse_text("13:30:05  * bob waves at alice", date, Tz::UTC)), message("bob", true, "waves at alice", timestamp(13, 30, 5)));
+        assert!(parse_text("13:30:00 -!- alice [a@b] has joined #rust", date, Tz::UTC).is_none());
+        assert!(parse_text("later <alice> hi", date, Tz::UTC).is_none());
+    }
+
+    #[test]
+    fn lines_need_a_nick_and_text() {
+        assert_eq!(parse_line("<alice> hi", " * "), Some(("alice".to_string(), false, "hi".to_string())));
+        assert_eq!(parse_line(" * bob waves", " * "), Some(("bob".to_string(), true, "waves".to_string())));
+        assert_eq!(parse_line("<alice>", " * "), None);
+        assert_eq!(parse_line(" * bob", " * "), None);
+    }
+}
@@ -0,0 +1,134 @@
+use chrono::{Days, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+
+use crate::irc_ext::IrcExt;
+use crate::log_index::{self, IndexedMessage, Search};
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+
+const MAX_RESULTS: usize = 5;
+
+// Searches the messages indexed from the channel logs, see LoggingConfig::index
+pub struct LogSearchPrivMsgEvent {}
+
+impl LogSearchPrivMsgEvent {
+    // in:#channel, from:YYYY-MM-DD and to:YYYY-MM-DD can be anywhere in the arguments, both dates are inclusive
+    fn search(&self, request: &PrivMsgRequest, arguments: &[&str], nick: Option<&str>, oldest_first: bool) -> Vec<String> {
+        let storage = match request.storage {
+            Some(storage) => storage,
+            None => return vec!["Storage isn't available".to_string()],
+        };
+
+        let timezone = request.server.logging.timezone.parse::<Tz>().unwrap_or(Tz::UTC);
+        let mut search = Search {
+            channel: request.irc_state.fold(request.source),
+            nick: nick.map(|nick| request.irc_state.fold(nick)),
+            oldest_first,
+            // One more, to know whether there are more
+            limit: if nick.is_some() { 1 } else { MAX_RESULTS + 1 },
+            ..Default::default()
+        };
+
+        for argument in arguments {
+            if let Some(channel) = argument.strip_prefix("in:") {
+                search.channel = request.irc_state.fold(channel);
+            } else if let Some(date) = argument.strip_prefix("from:") {
+                match parse_date(date, timezone, 0) {
+                    Some(from) => search.from = Some(from),
+                    None => return vec![format!("Invalid date: {}, use YYYY-MM-DD", date)],
+                }
+            } else if let Some(date) = argument.strip_prefix("to:") {
+                match parse_date(date, timezone, 1) {
+                    Some(to) => search.to = Some(to),
+                    None => return vec![format!("Invalid date: {}, use YYYY-MM-DD", date)],
+                }
+            } else {
+                search.words.push(argument.to_string());
+            }
+        }
+
+        if !search.channel.as_str().is_channel_name() {
+            return vec!["Which channel? Add in:#channel".to_string()];
+        }
+
+        // Only who can see a channel can search it
+        if !request.irc_state.user(&request.user.nick).is_some_and(|user| user.channels.contains(&search.channel)) {
+            return vec![format!("You have to be in {} to search it", search.channel)];
+        }
+
+        let messages = match log_index::search(storage, &request.server.hostname, &search) {
+            Ok(messages) => messages,
+            Err(e) => {
+                log::error!("Couldn't search the logs of {}: {}", search.channel, e);
+
+                return vec!["Couldn't search the logs".to_string()];
+            }
+        };
+
+        if messages.is_empty() {
+            return match nick {
+                Some(nick) => vec![format!("{} didn't say anything like that in {}", nick, search.channel)],
+                None => vec![format!("Nothing found in {}", search.channel)],
+            };
+        }
+
+        let mut lines: Vec<String> = messages.iter().take(MAX_RESULTS).map(|message| describe(&search.channel, message, timezone)).collect();
+
+        if messages.len() > MAX_RESULTS {
+            lines.push("There are more, narrow the search down with more words, from: or to:".to_string());
+        }
+
+        lines
+    }
+}
+
+// Start of the day, or of the next one for the end of a range
+fn parse_date(date: &str, timezone: Tz, days: u64) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?.checked_add_days(Days::new(days))?;
+
+    Some(timezone.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest()?.timestamp())
+}
+
+// #channel [2024-01-02 13:30] <nick> text
+fn describe(channel: &str, message: &IndexedMessage, timezone: Tz) -> String {
+    let time = timezone.timestamp_opt(message.time, 0).single().map(|time| time.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default();
+
+    if message.action {
+        format!("{} [{}] * {} {}", channel, time, message.nick, message.text)
+    } else {
+        format!("{} [{}] <{}> {}", channel, time, message.nick, message.text)
+    }
+}
+
+impl PrivMsgEvent for LogSearchPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "log_search"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        let (arguments, by_nick, oldest_first) = if let Some(arguments) = request.command("grep") {
+            (arguments, false, false)
+        } else if let Some(arguments) = request.command("last") {
+            (arguments, true, false)
+        } else if let Some(arguments) = request.command("first") {
+            (arguments, true, true)
+        } else {
+            return vec![];
+        };
+
+        let mut arguments: Vec<&str> = arguments.split_whitespace().collect();
+        let nick = if by_nick && !arguments.is_empty() { Some(arguments.remove(0)) } else { None };
+
+        let lines = if (by_nick && nick.is_none()) || (!by_nick && arguments.is_empty()) {
+            vec![format!("Usage: {0}grep <words>, {0}last <nick> [words] or {0}first <nick> [words], with in:#channel, from:YYYY-MM-DD and to:YYYY-MM-DD to filter", request.command_prefix)]
+        } else {
+            self.search(&request, &arguments, nick, oldest_first)
+        };
+
+        // Privately, search results would flood the channel
+        vec![PrivMsgResponse {
+            target: request.user.nick.clone(),
+            message: lines.join("\n"),
+            response_type: ResponseType::Notice,
+        }]
+    }
+}
@@ -1,27 +1,64 @@
 extern crate pretty_env_logger;
 
 use std::env;
-use std::fs::File;
-use std::net::ToSocketAddrs;
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use async_dup::Mutex;
 use async_std::net::TcpStream;
 use async_std::task;
 
+use crate::auto_responder::AutoResponderPrivMsgEvent;
 use crate::config::IrcConfig;
-use crate::ctcp::{ClientInfoCtcpResponse, CtcpEvent, FingerCtcpResponse, PingCtcpResponse, SourceCtcpResponse, TimeCtcpResponse, UserInfoCtcpResponse, VersionCtcpResponse};
+use crate::ctcp::{ClientInfoCtcpResponse, CtcpEvent, FingerCtcpResponse, PingCtcpResponse, SourceCtcpResponse, TemplateCtcpResponse, TimeCtcpResponse, UserInfoCtcpResponse, VersionCtcpResponse};
+use crate::dcc::DccFilesPrivMsgEvent;
+use crate::dns::DnsPrivMsgEvent;
+use crate::event::BotEvent;
+use crate::geoip_database::GeoIpDatabases;
 use crate::irc_handler::IrcHandler;
 use crate::irc_state::IrcState;
-use crate::privmsg::{GeoIpPrivMsgEvent, Iai55Chan, PrivMsgEvent};
+use crate::log_search::LogSearchPrivMsgEvent;
+use crate::quote::QuotePrivMsgEvent;
+use crate::karma::KarmaPrivMsgEvent;
+use crate::privmsg::{GeoIpPrivMsgEvent, PrivMsgEvent};
+use crate::remind::RemindPrivMsgEvent;
+use crate::script::ScriptPrivMsgEvent;
+use crate::seen::SeenPrivMsgEvent;
+use crate::storage::Storage;
+use crate::tell::TellPrivMsgEvent;
 
 mod ctcp;
 mod irc_ext;
 mod geoip_response;
+mod geoip_database;
 mod privmsg;
 mod irc_handler;
 mod irc_state;
 mod config;
+mod script;
+mod auto_responder;
+mod admin;
+mod runtime_config;
+mod rate_limit;
+mod event;
+mod dcc;
+mod cache;
+mod resolver;
+mod dns;
+mod storage;
+mod seen;
+mod tell;
+mod scheduler;
+mod remind;
+mod announcement;
+mod chat_log;
+mod log_index;
+mod log_search;
+mod quote;
+mod karma;
 
 fn main() -> Result<()> {
     task::block_on(async {
@@ -33,39 +70,141 @@ fn main() -> Result<()> {
 
         let config: IrcConfig = serde_yaml::from_reader(File::open("config.yml")?)?;
 
-        if config.servers.len() == 0 {
+        if config.servers.is_empty() {
             return Err(anyhow!("No servers!"));
         }
 
+        for server in &config.servers {
+            for channel in &server.channels {
+                for announcement in &channel.announcements {
+                    announcement::validate(announcement).map_err(|e| anyhow!("Invalid announcement {} in {} of {}: {}", announcement.name, channel.name, server.hostname, e))?;
+                }
+            }
+        }
+
+        // Opened once, every server shares the same databases
+        let geoip_databases = if config.servers.iter().any(|server| server.privmsg_plugins.iter().any(|plugin| plugin == "geoip")) {
+            Some(GeoIpDatabases::open(&config.geoip))
+        } else {
+            None
+        };
+
+        let resolver = resolver::from_config(&config.dns);
+
+        let storage = Storage::open(&config.storage).and_then(|storage| {
+            storage.migrate("scheduler", scheduler::MIGRATIONS)?;
+            storage.migrate("log_index", log_index::MIGRATIONS)?;
+
+            Ok(storage)
+        });
+
+        let arguments: Vec<String> = env::args().skip(1).collect();
+
+        match arguments.first().map(|command| command.as_str()) {
+            Some("reindex") => return reindex(&config, storage?),
+            Some("quotes") => return quotes(&config, storage?, &arguments[1..]),
+            Some(command) => return Err(anyhow!("Unknown command: {}, use reindex or quotes", command)),
+            None => {}
+        }
+
+        // Plugins keep working without it, they just can't remember anything
+        let storage = match storage {
+            Ok(storage) => Some(storage),
+            Err(e) => {
+                log::error!("Couldn't open storage {}: {}", config.storage.path, e);
+
+                None
+            }
+        };
+
         let mut futures = vec![];
 
         for server in config.servers {
+            let geoip_databases = geoip_databases.clone();
+            let geoip_languages = config.geoip.languages.clone();
+            let resolver = resolver.clone();
+            let dns_config = config.dns.clone();
+            let storage = storage.clone();
+
             futures.push(task::spawn(async move {
-                for socket_addr in format!("{}:{}", &server.hostname, server.port).to_socket_addrs().unwrap() {
+                let addresses = match resolver.lookup_ip(&server.hostname).await {
+                    Ok(addresses) => addresses,
+                    Err(e) => {
+                        log::error!("Couldn't resolve {}: {}", server.hostname, e);
+
+                        return;
+                    }
+                };
+
+                for ip in addresses {
+                    let socket_addr = SocketAddr::new(ip, server.port);
                     let stream_result = TcpStream::connect(socket_addr).await;
 
                     match stream_result {
                         Ok(stream) => {
-                            let irc_state = &mut IrcState { ..Default::default() };
+                            let irc_state = &mut IrcState { casemapping: server.casemapping, ..Default::default() };
 
                             if server.sasl.enabled {
                                 irc_state.cap_requested.push("sasl".to_string());
                             }
 
+                            // Keeps track of who is logged in to which services account
+                            if server.tell.use_account && server.privmsg_plugins.iter().any(|plugin| plugin == "tell") {
+                                irc_state.cap_requested.extend(["account-notify", "extended-join", "account-tag"].iter().map(|cap| cap.to_string()));
+                            }
+
+                            // Logs are timestamped with when the server got the message, not when the bot did
+                            if server.logging.enabled {
+                                irc_state.cap_requested.push("server-time".to_string());
+                            }
+
                             let mut privmsg_plugins: Vec<Box<dyn PrivMsgEvent>> = vec![];
                             let mut ctcp_plugins: Vec<Box<dyn CtcpEvent>> = vec![];
 
                             for plugin in &server.privmsg_plugins {
                                 match plugin.as_str() {
-                                    "geoip" => privmsg_plugins.push(Box::new(GeoIpPrivMsgEvent { ..Default::default() })),
-                                    "iai_55chan" => privmsg_plugins.push(Box::new(Iai55Chan {})),
+                                    "geoip" => if let Some(databases) = &geoip_databases {
+                                        privmsg_plugins.push(Box::new(GeoIpPrivMsgEvent { databases: databases.clone(), languages: geoip_languages.clone() }))
+                                    },
+                                    "auto_responder" => privmsg_plugins.push(Box::new(AutoResponderPrivMsgEvent::new(&server.auto_responder))),
+                                    "iai_55chan" => {
+                                        log::warn!("The iai_55chan plugin is deprecated, replace it with an auto_responder rule for IAI");
+
+                                        privmsg_plugins.push(Box::new(AutoResponderPrivMsgEvent::iai_55chan()))
+                                    }
+                                    "dcc_files" => privmsg_plugins.push(Box::new(DccFilesPrivMsgEvent { directory: server.dcc.send_directory.clone() })),
+                                    "dns" => privmsg_plugins.push(Box::new(DnsPrivMsgEvent::new(&server.dns, &dns_config))),
+                                    "seen" => privmsg_plugins.push(Box::new(SeenPrivMsgEvent {})),
+                                    "tell" => privmsg_plugins.push(Box::new(TellPrivMsgEvent { config: server.tell.clone() })),
+                                    "remind" => privmsg_plugins.push(Box::new(RemindPrivMsgEvent { config: server.remind.clone() })),
+                                    "log_search" => privmsg_plugins.push(Box::new(LogSearchPrivMsgEvent {})),
+                                    "quote" => privmsg_plugins.push(Box::new(QuotePrivMsgEvent {})),
+                                    "karma" => privmsg_plugins.push(Box::new(KarmaPrivMsgEvent::new(&server.karma))),
+                                    "script" => privmsg_plugins.push(Box::new(ScriptPrivMsgEvent::new(&server.script, storage.clone()))),
                                     _ => log::warn!("Unknown plugin: {}", plugin),
                                 }
                             }
 
+                            if let Some(storage) = &storage {
+                                for plugin in &privmsg_plugins {
+                                    if let Err(e) = storage.migrate(plugin.name(), plugin.migrations()) {
+                                        log::error!("Couldn't migrate the storage of {}: {}", plugin.name(), e);
+                                    }
+                                }
+                            }
+
+                            // Templates from config take precedence over the built-in replies
+                            for (command, template) in &server.ctcp.replies {
+                                ctcp_plugins.push(Box::new(TemplateCtcpResponse { command: command.clone(), template: template.clone() }));
+                            }
+
                             for plugin in &server.ctcp.enabled {
+                                if server.ctcp.replies.keys().any(|command| command.eq_ignore_ascii_case(plugin)) {
+                                    continue;
+                                }
+
                                 match plugin.as_str() {
-                                    "CLIENTINFO" => ctcp_plugins.push(Box::new(ClientInfoCtcpResponse { available_ctcp: server.ctcp.enabled.clone() })),
+                                    "CLIENTINFO" => ctcp_plugins.push(Box::new(ClientInfoCtcpResponse { available_ctcp: server.ctcp.available_commands() })),
                                     "FINGER" => ctcp_plugins.push(Box::new(FingerCtcpResponse {})),
                                     "PING" => ctcp_plugins.push(Box::new(PingCtcpResponse {})),
                                     "SOURCE" => ctcp_plugins.push(Box::new(SourceCtcpResponse {})),
@@ -76,22 +215,41 @@ fn main() -> Result<()> {
                                 }
                             }
 
+                            let mut server_config = server.clone();
+
+                            if let Err(e) = runtime_config::load(&mut server_config) {
+                                log::error!("Couldn't load runtime config {}: {}", server_config.runtime_config_path(), e);
+                            }
+
+                            let (event_sender, event_receiver) = futures::channel::mpsc::unbounded();
+                            let ticks = event_sender.clone();
+
+                            // Stops once the handler is gone
+                            task::spawn(async move {
+                                while ticks.unbounded_send(BotEvent::Tick).is_ok() {
+                                    task::sleep(Duration::from_secs(1)).await;
+                                }
+                            });
+
                             let mut handler = IrcHandler {
-                                server: &mut server.clone(),
+                                server: &mut server_config,
                                 irc_state,
                                 ctcp_event: &ctcp_plugins,
                                 privmsg_event: &privmsg_plugins,
+                                event_sender,
+                                resolver: resolver.clone(),
+                                storage: storage.as_deref(),
                             };
 
                             if server.use_tls {
                                 let stream = async_native_tls::connect(&server.hostname, stream).await.unwrap();
                                 let mut stream = &Mutex::new(stream);
 
-                                handler.handle(stream, &mut stream).await;
+                                handler.handle(stream, &mut stream, event_receiver).await;
                             } else {
                                 let mut stream = &Mutex::new(stream);
 
-                                handler.handle(stream, &mut stream).await;
+                                handler.handle(stream, &mut stream, event_receiver).await;
                             }
                         }
                         Err(e) => {
@@ -109,3 +267,47 @@ fn main() -> Result<()> {
     })
 }
 
+// jomp16-bot-own reindex, indexes again the logs of every server that has indexing enabled
+fn reindex(config: &IrcConfig, storage: Arc<Storage>) -> Result<()> {
+    for server in config.servers.iter().filter(|server| server.logging.enabled && server.logging.index) {
+        let indexed = log_index::rebuild(&storage, server)?;
+
+        log::info!("Indexed {} messages of {}", indexed, server.hostname);
+    }
+
+    Ok(())
+}
+
+// jomp16-bot-own quotes <import|export> <hostname> <#channel> <file>, channels and nicks are folded with the server casemapping
+fn quotes(config: &IrcConfig, storage: Arc<Storage>, arguments: &[String]) -> Result<()> {
+    let (command, hostname, channel, path) = match arguments {
+        [command, hostname, channel, path] => (command.as_str(), hostname, channel, path),
+        _ => return Err(anyhow!("Usage: quotes <import|export> <hostname> <#channel> <file>")),
+    };
+
+    let server = match config.servers.iter().find(|server| server.hostname == *hostname) {
+        Some(server) => server,
+        None => return Err(anyhow!("Unknown server: {}", hostname)),
+    };
+
+    storage.migrate("quote", quote::MIGRATIONS)?;
+
+    let channel = server.casemapping.fold(channel);
+    let scope = storage.scope("quote", hostname, Some(&channel));
+
+    match command {
+        "import" => {
+            let json = fs::read_to_string(path).map_err(|e| anyhow!("Couldn't read {}: {}", path, e))?;
+
+            log::info!("Imported {} quotes to {}", quote::import(&scope, server.casemapping, &json)?, channel);
+        }
+        "export" => {
+            fs::write(path, quote::export(&scope)?)?;
+
+            log::info!("Exported the quotes of {} to {}", channel, path);
+        }
+        _ => return Err(anyhow!("Unknown quotes command: {}, use import or export", command)),
+    }
+
+    Ok(())
+}
@@ -1,99 +1,698 @@
-use maxminddb::Reader;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_std::task;
+use chrono::Utc;
+use chrono_tz::Tz;
+use futures::channel::mpsc::UnboundedSender;
+use futures::Future;
+use ipnetwork::IpNetwork;
 use simple_irc::Prefix;
 
-use crate::config::Server;
-use crate::geoip_response;
-use crate::irc_state::IrcState;
+use crate::config::{ChannelConfig, Server};
+use crate::event::BotEvent;
+use crate::geoip_database::{GeoIpDatabases, GeoIpReaders};
+use crate::geoip_response::{self, GeoIpCidrResponse, GeoIpNetworkResponse, GeoIpResponse};
+use crate::irc_ext::IrcExt;
+use crate::irc_state::{IrcState, User};
+use crate::resolver::Resolver;
+use crate::scheduler::{Job, Scheduler};
+use crate::storage::{Storage, StorageScope};
+
+const MAX_QUERIES: usize = 5;
+const MAX_GROUPED_ADDRESSES: usize = 4;
+const MAX_LINES: usize = 8;
+// ASNs and countries listed in a network summary
+const MAX_SUMMARY_ENTRIES: usize = 3;
+// Larger networks take too long to summarize
+const MIN_IPV4_PREFIX: u8 = 8;
+const MIN_IPV6_PREFIX: u8 = 32;
+const ASN_PAGE_SIZE: usize = 20;
 
 pub struct PrivMsgRequest<'a> {
     pub server: &'a Server,
     pub irc_state: &'a IrcState,
     pub user: &'a Prefix,
     pub source: &'a String,
+    pub channel: Option<&'a ChannelConfig>,
+    pub command_prefix: &'a str,
     pub message: &'a String,
+    pub is_action: bool,
+    pub resolver: &'a Arc<dyn Resolver>,
+    // None when the database couldn't be opened
+    pub storage: Option<&'a Storage>,
+    pub events: &'a UnboundedSender<BotEvent>,
+}
+
+impl PrivMsgRequest<'_> {
+    // Returns the arguments if the message is the given command, using the channel command prefix
+    pub fn command(&self, name: &str) -> Option<&str> {
+        if self.is_action {
+            return None;
+        }
+
+        let arguments = self.message.strip_prefix(self.command_prefix)?.strip_prefix(name)?;
+
+        if arguments.is_empty() || arguments.starts_with(' ') {
+            return Some(arguments.trim());
+        }
+
+        None
+    }
+
+    pub fn setting(&self, plugin: &str, key: &str) -> Option<&serde_yaml::Value> {
+        self.channel?.settings.get(plugin)?.get(key)
+    }
+
+    // Data of the plugin in this server and channel, private messages share the server wide data
+    pub fn storage(&self, plugin: &str) -> Option<StorageScope<'_>> {
+        let channel = if self.source.is_channel_name() { Some(self.irc_state.fold(self.source)) } else { None };
+
+        Some(self.storage?.scope(plugin, &self.server.hostname, channel.as_deref()))
+    }
+
+    // Data of the plugin shared by every channel of the server
+    pub fn server_storage(&self, plugin: &str) -> Option<StorageScope<'_>> {
+        Some(self.storage?.scope(plugin, &self.server.hostname, None))
+    }
+
+    pub fn scheduler(&self, plugin: &str) -> Option<Scheduler<'_>> {
+        Some(Scheduler::new(self.storage?, plugin, &self.server.hostname))
+    }
+
+    // Runs work that has to wait, like DNS lookups, in its own task so the handler isn't blocked.
+    // Its responses are sent once it finishes
+    pub fn spawn(&self, responses: impl Future<Output = Vec<PrivMsgResponse>> + Send + 'static) {
+        let events = self.events.clone();
+
+        task::spawn(async move {
+            let _ = events.unbounded_send(BotEvent::PluginResponses { responses: responses.await });
+        });
+    }
+}
+
+pub enum ResponseType {
+    PrivMsg,
+    Notice,
+    Action,
+    // Offers the file named in the message from the DCC send directory
+    DccSend,
+    // Sends a WHOIS for the nick in the target, the command is dispatched again to the plugin once it's answered
+    Whois,
+    // Sets the topic of the channel in the target
+    Topic,
 }
 
 pub struct PrivMsgResponse {
     pub target: String,
     pub message: String,
+    pub response_type: ResponseType,
 }
 
 pub trait PrivMsgEvent: Send + Sync {
-    fn execute(&self, request: PrivMsgRequest) -> Option<PrivMsgResponse>;
+    fn name(&self) -> &'static str;
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse>;
+
+    // Schema of the plugin tables, applied in order when the bot starts. Released migrations can't be changed
+    fn migrations(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    // Called when someone else joins, leaves or changes nick, and about files sent over DCC. The request message is empty
+    fn on_event(&self, _request: PrivMsgRequest, _event: &UserEvent) -> Vec<PrivMsgResponse> {
+        vec![]
+    }
+
+    // Called when a job the plugin scheduled is due, the request user is who scheduled it and the source is the job target
+    fn on_scheduled(&self, _request: PrivMsgRequest, _job: &Job) -> Vec<PrivMsgResponse> {
+        vec![]
+    }
+}
+
+// The request user is who did it and the source is the channel. Quits and nick changes are
+// dispatched once for each channel the bot shares with the user
+pub enum UserEvent<'a> {
+    Join,
+    Part { reason: Option<&'a str> },
+    Quit { reason: Option<&'a str> },
+    Nick { new_nick: &'a str },
+    // The request user is who kicked
+    Kick { nick: &'a str, reason: Option<&'a str> },
+    // The request user is who gets the file and the source is their nick. Sent every megabyte
+    DccSendProgress { transfer: u64, file_name: &'a str, sent: u64, size: u64 },
+    // Bytes sent, or why it failed, including offers that expired
+    DccSendFinished { transfer: u64, file_name: &'a str, size: u64, result: &'a Result<u64, String> },
 }
 
+#[derive(Clone)]
 pub struct GeoIpPrivMsgEvent {
-    pub reader_asn: Reader<Vec<u8>>,
-    pub reader_city: Reader<Vec<u8>>,
+    pub databases: Arc<GeoIpDatabases>,
+    // Preferred languages for place names, English and then any other are used when missing
+    pub languages: Vec<String>,
 }
 
-pub struct Iai55Chan {}
+struct GeoIpOptions {
+    languages: Vec<String>,
+    verbose: bool,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl GeoIpPrivMsgEvent {
+    fn readers(&self) -> Option<GeoIpReaders> {
+        Some(self.databases.readers()).filter(|readers| readers.is_available())
+    }
+
+    // One line per location, addresses of a hostname in the same place are condensed into a single line
+    async fn geolocate(&self, ip_request: &str, label: bool, options: &GeoIpOptions) -> Result<Vec<String>, std::io::Error> {
+        let readers = self.readers().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "GeoIP databases aren't available"))?;
+        let query = geoip_response::ip_to_geoip(vec![ip_request], &readers, &self.databases.cache, options.resolver.as_ref(), &options.languages).await.remove(0);
+        let label = if label && query.query.parse::<IpAddr>().is_err() { format!("{} / ", query.query) } else { "".to_string() };
+        let responses = query.result?;
+        let mut groups: Vec<Vec<&GeoIpResponse>> = vec![];
 
-impl Default for GeoIpPrivMsgEvent {
-    fn default() -> Self {
-        GeoIpPrivMsgEvent {
-            reader_asn: maxminddb::Reader::open_readfile("GeoLite2-ASN.mmdb").unwrap(),
-            reader_city: maxminddb::Reader::open_readfile("GeoLite2-City.mmdb").unwrap(),
+        for geoip in &responses {
+            match groups.iter_mut().find(|group| same_location(group[0], geoip)) {
+                Some(group) => group.push(geoip),
+                None => groups.push(vec![geoip]),
+            }
         }
+
+        Ok(groups.iter().map(|group| {
+            let geoip = group[0];
+
+            // AS-NAME / ASN / PTR / país - estado - cidade
+
+            let address = if group.len() == 1 {
+                match geoip.asn.prefix {
+                    Some(prefix) => format!("{:} ({:}) / {:}", geoip.ip.ip, prefix, geoip.ip.ptr),
+                    None => format!("{:} / {:}", geoip.ip.ip, geoip.ip.ptr),
+                }
+            } else {
+                let mut ips: Vec<String> = group.iter().take(MAX_GROUPED_ADDRESSES).map(|geoip| geoip.ip.ip.clone()).collect();
+
+                if group.len() > MAX_GROUPED_ADDRESSES {
+                    ips.push(format!("+{} more", group.len() - MAX_GROUPED_ADDRESSES));
+                }
+
+                ips.join(", ")
+            };
+
+            let mut message = format!("{}{:} / {:} / {:} / {:} - {:} - {:}",
+                                      label,
+                                      geoip.asn.name,
+                                      geoip.asn.number,
+                                      address,
+                                      geoip.city.country,
+                                      geoip.city.state,
+                                      geoip.city.name
+            );
+
+            if let Some(network) = network(&geoip.network) {
+                message = format!("{} / {}", message, network);
+            }
+
+            if options.verbose {
+                format!("{} / {}", message, details(geoip))
+            } else {
+                message
+            }
+        }).collect())
+    }
+
+    // Geolocates the host of a user, cloaks and vhosts can't be geolocated
+    async fn geolocate_host(&self, nick: &str, host: &str, options: &GeoIpOptions) -> Result<Vec<String>, String> {
+        if is_cloaked(host) {
+            return Err(format!("{} is using a cloak ({}), can't geolocate it", nick, host));
+        }
+
+        match self.geolocate(host, false, options).await {
+            Ok(lines) => Ok(lines.iter().map(|line| format!("{} / {}", nick, line)).collect()),
+            Err(_) => Err(format!("{}'s host {} doesn't resolve, it's probably a vhost", nick, host)),
+        }
+    }
+
+    // Network / size / ASNs / countries
+    async fn summarize(&self, network: IpNetwork, options: &GeoIpOptions) -> Result<String, String> {
+        let min_prefix = if network.is_ipv4() { MIN_IPV4_PREFIX } else { MIN_IPV6_PREFIX };
+
+        if network.prefix() < min_prefix {
+            return Err(format!("Networks larger than /{} can't be summarized", min_prefix));
+        }
+
+        let readers = self.readers().ok_or_else(|| "GeoIP databases aren't available".to_string())?;
+
+        match geoip_response::summarize_network(network, readers, &self.databases.cache, &options.languages).await {
+            Ok(summary) => Ok(format!("{} / {} / {} / {}", summary.network, network_size(&summary.network), asns(&summary), countries(&summary))),
+            Err(e) => Err(format!("An error happened while summarizing network: {}, message: {}", network, e)),
+        }
+    }
+
+    // Lines prefixed with ^ on success, errors are returned as they are. The user is the nick and host of a known nick
+    async fn geolocate_query(&self, ip_request: &str, user: Option<&(String, String)>, label: bool, options: &GeoIpOptions) -> Result<Vec<String>, String> {
+        if ip_request.contains('/') {
+            if let Ok(network) = ip_request.parse::<IpNetwork>() {
+                return self.summarize(network, options).await.map(|line| vec![line]);
+            }
+        }
+
+        if let Some((nick, host)) = user {
+            return self.geolocate_host(nick, host, options).await;
+        }
+
+        // Not a nick after all, it may still be a hostname without dots like localhost
+        match self.geolocate(ip_request, label, options).await {
+            Ok(lines) => Ok(lines),
+            Err(_) if is_nick(ip_request) => Err(format!("No such nick or host: {}", ip_request)),
+            Err(e) => Err(format!("An error happened while geolocating IP: {}, message: {}", ip_request, e)),
+        }
+    }
+
+    // .asn <number> [page], the prefixes of the AS in the ASN database
+    async fn asn(&self, asn_request: &str, command_prefix: &str) -> String {
+        let mut arguments = asn_request.split_whitespace();
+        let number = match arguments.next().and_then(parse_asn) {
+            Some(number) => number,
+            None => return format!("Usage: {}asn <number> [page]", command_prefix),
+        };
+        let page: usize = match arguments.next().map(|page| page.parse()) {
+            Some(Ok(page)) if page > 0 => page,
+            Some(_) => return "Pages start at 1".to_string(),
+            None => 1,
+        };
+
+        let reader = match self.readers().and_then(|readers| readers.asn.or(readers.isp)) {
+            Some(reader) => reader,
+            None => return "GeoIP databases aren't available right now".to_string(),
+        };
+
+        let (name, prefixes) = match geoip_response::cached_asn_prefixes(reader, number, &self.databases.cache).await {
+            Ok(result) => result,
+            Err(e) => return format!("An error happened while listing prefixes of AS{}, message: {}", number, e),
+        };
+
+        if prefixes.is_empty() {
+            return format!("No prefixes found for AS{}", number);
+        }
+
+        let pages = prefixes.len().div_ceil(ASN_PAGE_SIZE);
+
+        if page > pages {
+            return format!("AS{} has only {} pages", number, pages);
+        }
+
+        let ipv4 = prefixes.iter().filter(|prefix| prefix.is_ipv4()).count();
+        let page_prefixes: Vec<String> = prefixes.iter().skip((page - 1) * ASN_PAGE_SIZE).take(ASN_PAGE_SIZE).map(|prefix| prefix.to_string()).collect();
+        let mut message = format!("AS{} {} / {} prefixes ({} IPv4, {} IPv6) / page {}/{}: {}",
+                                  number,
+                                  name.unwrap_or_else(|| "No ASN name".to_string()),
+                                  prefixes.len(),
+                                  ipv4,
+                                  prefixes.len() - ipv4,
+                                  page,
+                                  pages,
+                                  page_prefixes.join(", ")
+        );
+
+        if page < pages {
+            message = format!("{} / next page: {}asn {} {}", message, command_prefix, number, page + 1);
+        }
+
+        message
+    }
+
+    // Cache: hosts 10/1024 entries, 75% hits of 40 lookups, 0 evicted / ...
+    fn cache_stats(&self) -> String {
+        let stats: Vec<String> = self.databases.cache.stats().iter()
+            .map(|(name, stats)| format!("{} {}/{} entries, {:.0}% hits of {} lookups, {} evicted", name, stats.entries, stats.capacity, stats.hit_rate(), stats.hits + stats.misses, stats.evictions))
+            .collect();
+
+        format!("Cache: {}", stats.join(" / "))
+    }
+
+    // Languages can be set per channel, as a list or separated by commas
+    fn options(&self, request: &PrivMsgRequest, verbose: bool) -> GeoIpOptions {
+        let languages = match request.setting("geoip", "languages") {
+            Some(serde_yaml::Value::Sequence(languages)) => languages.iter().filter_map(|language| language.as_str()).map(|language| language.to_string()).collect(),
+            Some(serde_yaml::Value::String(languages)) => languages.split(',').map(|language| language.trim().to_string()).collect(),
+            _ => self.languages.clone(),
+        };
+
+        GeoIpOptions { languages, verbose, resolver: request.resolver.clone() }
+    }
+}
+
+// Country code / postal code / continent / coordinates / time zone and local time / registered and represented countries
+fn details(geoip: &GeoIpResponse) -> String {
+    let city = &geoip.city;
+    let mut details: Vec<String> = vec![city.country_iso_code.clone()];
+
+    if let Some(postal_code) = &city.postal_code {
+        details.push(format!("Postal code {}", postal_code));
+    }
+
+    if let Some(continent) = &city.continent {
+        details.push(continent.clone());
+    }
+
+    if let (Some(latitude), Some(longitude)) = (city.latitude, city.longitude) {
+        match city.accuracy_radius {
+            Some(radius) => details.push(format!("{}, {} (within {} km)", latitude, longitude, radius)),
+            None => details.push(format!("{}, {}", latitude, longitude)),
+        }
+    }
+
+    if let Some(time_zone) = &city.time_zone {
+        match time_zone.parse::<Tz>() {
+            Ok(tz) => details.push(format!("{} (local time {})", time_zone, Utc::now().with_timezone(&tz).format("%Y-%m-%d %H:%M %Z"))),
+            Err(_) => details.push(time_zone.clone()),
+        }
+    }
+
+    if let Some(registered_country) = city.registered_country.as_ref().filter(|country| **country != city.country) {
+        details.push(format!("Registered in {}", registered_country));
+    }
+
+    if let Some(represented_country) = &city.represented_country {
+        details.push(format!("Represents {}", represented_country));
+    }
+
+    details.join(" / ")
+}
+
+// ISP / organization / connection type / domain / anonymizer flags, only what the optional databases have
+fn network(network: &GeoIpNetworkResponse) -> Option<String> {
+    let mut parts: Vec<String> = vec![];
+
+    parts.extend(network.isp.iter().map(|isp| format!("ISP {}", isp)));
+    parts.extend(network.organization.iter().cloned());
+    parts.extend(network.connection_type.iter().cloned());
+    parts.extend(network.domain.iter().cloned());
+
+    if !network.anonymous.is_empty() {
+        parts.push(format!("[{}]", network.anonymous.join(", ")));
+    }
+
+    Some(parts.join(" / ")).filter(|parts| !parts.is_empty())
+}
+
+fn network_size(network: &IpNetwork) -> String {
+    match network {
+        IpNetwork::V4(network) => format!("{} addresses", network.size()),
+        IpNetwork::V6(network) => format!("2^{} addresses", 128 - network.prefix()),
+    }
+}
+
+// The ASN when the network is inside a single prefix, otherwise the ASNs with most prefixes inside it
+fn asns(summary: &GeoIpCidrResponse) -> String {
+    if let (Some(prefix), Some((number, name, _))) = (summary.prefix, summary.asns.first()) {
+        return format!("{} / {} / part of {}", name, number, prefix);
+    }
+
+    if summary.asns.is_empty() {
+        return "No ASN".to_string();
+    }
+
+    let prefixes: usize = summary.asns.iter().map(|(_, _, count)| count).sum();
+    let mut asns: Vec<String> = summary.asns.iter().take(MAX_SUMMARY_ENTRIES)
+        .map(|(number, name, count)| format!("{} ({}, {})", name, number, count))
+        .collect();
+
+    if summary.asns.len() > MAX_SUMMARY_ENTRIES {
+        asns.push(format!("+{} more", summary.asns.len() - MAX_SUMMARY_ENTRIES));
+    }
+
+    format!("{} prefixes from {} ASNs: {}", prefixes, summary.asns.len(), asns.join(", "))
+}
+
+fn countries(summary: &GeoIpCidrResponse) -> String {
+    if summary.countries.is_empty() {
+        return "No Country".to_string();
+    }
+
+    let mut countries: Vec<String> = summary.countries.iter().take(MAX_SUMMARY_ENTRIES).map(|(country, _)| country.clone()).collect();
+
+    if summary.countries.len() > MAX_SUMMARY_ENTRIES {
+        countries.push(format!("+{} more", summary.countries.len() - MAX_SUMMARY_ENTRIES));
+    }
+
+    countries.join(", ")
+}
+
+// Accepts 13335, AS13335 or as13335
+fn parse_asn(asn: &str) -> Option<u32> {
+    let asn = if asn.len() > 2 && asn[..2].eq_ignore_ascii_case("as") { &asn[2..] } else { asn };
+
+    asn.parse().ok()
+}
+
+fn same_location(a: &GeoIpResponse, b: &GeoIpResponse) -> bool {
+    a.network == b.network && a.asn.number == b.asn.number && a.city.country == b.city.country && a.city.state == b.city.state && a.city.name == b.city.name
+}
+
+// Hosts that don't look like a real hostname or IP, like user/nick, gateway/web/x or ABCD.EF01.IP
+fn is_cloaked(host: &str) -> bool {
+    if host.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+
+    host.contains('/') || !host.contains('.') || host.ends_with(".IP")
+}
+
+// Nicks can't contain dots or colons, unlike hostnames and IPs
+fn is_nick(request: &str) -> bool {
+    !request.contains('.') && !request.contains(':')
+}
+
+// Nick and host of a user the bot knows about
+fn known_host(irc_state: &IrcState, request: &str) -> Option<(String, String)> {
+    match irc_state.user(request) {
+        Some(User { nick, host: Some(host), .. }) if is_nick(request) => Some((nick.clone(), host.clone())),
+        _ => None,
     }
 }
 
 impl PrivMsgEvent for GeoIpPrivMsgEvent {
-    fn execute(&self, request: PrivMsgRequest) -> Option<PrivMsgResponse> {
-        if request.message.starts_with(".geoip") {
-            let ip_request = request.message[6..].trim();
+    fn name(&self) -> &'static str {
+        "geoip"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        if let Some(ip_request) = request.command("geoip") {
+            if ip_request.is_empty() {
+                return vec![PrivMsgResponse {
+                    target: request.source.clone(),
+                    message: "No IP, host or nick specified".to_string(),
+                    response_type: ResponseType::PrivMsg,
+                }];
+            }
+
+            if ip_request.split_whitespace().any(|argument| argument == "--stats") {
+                return vec![PrivMsgResponse {
+                    target: request.source.clone(),
+                    message: self.cache_stats(),
+                    response_type: ResponseType::PrivMsg,
+                }];
+            }
+
+            if self.readers().is_none() {
+                return vec![PrivMsgResponse {
+                    target: request.source.clone(),
+                    message: "GeoIP databases aren't available right now".to_string(),
+                    response_type: ResponseType::PrivMsg,
+                }];
+            }
+
+            let verbose = ip_request.split_whitespace().any(|argument| argument == "-v" || argument == "--verbose");
+            let options = self.options(&request, verbose);
+            let queries: Vec<&str> = ip_request.split_whitespace().filter(|argument| !argument.starts_with('-')).collect();
 
-            if ip_request.len() == 0 {
-                return Some(PrivMsgResponse {
+            if queries.is_empty() {
+                return vec![PrivMsgResponse {
                     target: request.source.clone(),
-                    message: "No IP specified".to_string(),
-                });
+                    message: "No IP, host or nick specified".to_string(),
+                    response_type: ResponseType::PrivMsg,
+                }];
             }
 
-            let mut message: String = "".to_string();
+            if queries.len() > MAX_QUERIES {
+                return vec![PrivMsgResponse {
+                    target: request.source.clone(),
+                    message: format!("Only {} IPs, hosts or nicks at a time", MAX_QUERIES),
+                    response_type: ResponseType::PrivMsg,
+                }];
+            }
+
+            // Nicks without a known host are looked up first, the command comes back here once the WHOIS is answered
+            let whois: Vec<PrivMsgResponse> = queries.iter()
+                .filter(|query| is_nick(query) && request.irc_state.user(query).is_none_or(|user| user.host.is_none()))
+                .filter(|query| !request.irc_state.is_whois_completed(query))
+                .map(|query| PrivMsgResponse {
+                    target: query.to_string(),
+                    message: "".to_string(),
+                    response_type: ResponseType::Whois,
+                })
+                .collect();
 
-            match geoip_response::ip_to_geoip(vec![ip_request], &self.reader_asn, &self.reader_city) {
-                Ok(vector_geoip) => {
-                    if let Some(geoip) = vector_geoip.first() {
-                        log::info!("IP: {}", ip_request);
+            if !whois.is_empty() {
+                return whois;
+            }
+
+            // Hosts of nicks are taken now, the users may be gone once the lookups are done
+            let queries: Vec<(String, Option<(String, String)>)> = queries.iter().map(|query| (query.to_string(), known_host(request.irc_state, query))).collect();
+            let geoip = self.clone();
+            let target = request.source.clone();
 
-                        // AS-NAME / ASN / PTR / país - estado - cidade
+            request.spawn(async move {
+                let mut lines: Vec<String> = vec![];
 
-                        message = format!("^ {:} / {:} / {:} / {:} / {:} - {:} - {:}",
-                                          geoip.asn.name,
-                                          geoip.asn.number,
-                                          geoip.ip.ip,
-                                          geoip.ip.ptr,
-                                          geoip.city.country,
-                                          geoip.city.state,
-                                          geoip.city.name
-                        );
+                for (query, user) in &queries {
+                    match geoip.geolocate_query(query, user.as_ref(), queries.len() > 1, &options).await {
+                        Ok(results) => lines.extend(results.iter().map(|result| format!("^ {}", result))),
+                        Err(e) => lines.push(e),
                     }
                 }
-                Err(e) => {
-                    message = format!("An error happened while geolocating IP: {}, message: {}", ip_request, e);
+
+                if lines.len() > MAX_LINES {
+                    let omitted = lines.len() - MAX_LINES + 1;
+
+                    lines.truncate(MAX_LINES - 1);
+                    lines.push(format!("... and {} more", omitted));
                 }
-            }
 
-            return Some(PrivMsgResponse {
-                target: request.source.clone(),
-                message,
+                vec![PrivMsgResponse {
+                    target,
+                    message: lines.join("\n"),
+                    response_type: ResponseType::PrivMsg,
+                }]
             });
+
+            return vec![];
         }
 
-        None
+        if let Some(asn_request) = request.command("asn") {
+            let geoip = self.clone();
+            let asn_request = asn_request.to_string();
+            let command_prefix = request.command_prefix.to_string();
+            let target = request.source.clone();
+
+            request.spawn(async move {
+                vec![PrivMsgResponse {
+                    target,
+                    message: geoip.asn(&asn_request, &command_prefix).await,
+                    response_type: ResponseType::PrivMsg,
+                }]
+            });
+
+            return vec![];
+        }
+
+        vec![]
+    }
+
+    fn on_event(&self, request: PrivMsgRequest, event: &UserEvent) -> Vec<PrivMsgResponse> {
+        if !matches!(event, UserEvent::Join) {
+            return vec![];
+        }
+
+        // Channel setting with the channel or nick where joins are geolocated to, for opers
+        let target = match request.setting("geoip", "join_log").and_then(|target| target.as_str()) {
+            Some(target) => target.to_string(),
+            None => return vec![],
+        };
+
+        let host = match &request.user.host {
+            Some(host) => host.clone(),
+            None => return vec![],
+        };
+        let geoip = self.clone();
+        let options = self.options(&request, false);
+        let nick = request.user.nick.clone();
+        let joined = format!("[{}] {} joined", request.source, request.user);
+
+        request.spawn(async move {
+            let message = geoip.geolocate_host(&nick, &host, &options).await.map(|lines| lines.join(" | ")).unwrap_or_else(|e| e);
+
+            vec![PrivMsgResponse {
+                target,
+                message: format!("{}: {}", joined, message),
+                response_type: ResponseType::Notice,
+            }]
+        });
+
+        vec![]
     }
 }
+// Owns what a request borrows, so plugins can be tested without a connection
+#[cfg(test)]
+pub struct FakeRequest {
+    pub server: Server,
+    pub irc_state: IrcState,
+    pub user: Prefix,
+    pub source: String,
+    pub message: String,
+    pub is_action: bool,
+    pub resolver: Arc<dyn Resolver>,
+    pub storage: Option<Arc<Storage>>,
+    pub events: UnboundedSender<BotEvent>,
+}
 
-impl PrivMsgEvent for Iai55Chan {
-    fn execute(&self, request: PrivMsgRequest) -> Option<PrivMsgResponse> {
-        if request.message.eq("IAI") {
-            return Some(PrivMsgResponse {
-                target: request.source.clone(),
-                message: "DA HORA?!".to_string(),
-            });
+#[cfg(test)]
+impl FakeRequest {
+    // Server irc.example.net with #chan configured, nothing stored
+    pub fn new(source: &str, nick: &str, message: &str) -> Self {
+        let server: Server = serde_yaml::from_str("
+            user_data: {nickname: bot, username: bot, realname: Bot}
+            hostname: irc.example.net
+            port: 6667
+            password: ''
+            use_tls: false
+            use_hostserv: false
+            sasl: {enabled: false, user: '', password: '', terminate_failed: false}
+            nickserv: {enabled: false, password: ''}
+            ctcp: {enabled: [], version: '', source: ''}
+            channels: [{name: '#chan', password: ''}]
+            privmsg_plugins: []
+        ").unwrap();
+
+        FakeRequest {
+            server,
+            irc_state: IrcState::default(),
+            user: Prefix::new_with_all(nick, Some("user"), Some(&format!("{}.example", nick.to_lowercase()))),
+            source: source.to_string(),
+            message: message.to_string(),
+            is_action: false,
+            resolver: Arc::new(crate::resolver::FakeResolver::default()),
+            storage: None,
+            events: futures::channel::mpsc::unbounded().0,
         }
+    }
 
-        None
+    // In memory, with the plugin tables
+    pub fn with_storage(mut self, plugin: &str, migrations: &[&str]) -> Self {
+        let storage = Storage::open(&crate::config::StorageConfig { path: ":memory:".to_string() }).unwrap();
+
+        storage.migrate(plugin, migrations).unwrap();
+        self.storage = Some(storage);
+
+        self
+    }
+
+    pub fn request(&self) -> PrivMsgRequest<'_> {
+        let channel = self.server.channel(&self.source);
+
+        PrivMsgRequest {
+            server: &self.server,
+            irc_state: &self.irc_state,
+            user: &self.user,
+            source: &self.source,
+            channel,
+            command_prefix: self.server.command_prefix(channel),
+            message: &self.message,
+            is_action: self.is_action,
+            resolver: &self.resolver,
+            storage: self.storage.as_deref(),
+            events: &self.events,
+        }
     }
-}
\ No newline at end of file
+}
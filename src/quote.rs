@@ -0,0 +1,337 @@
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::admin;
+use crate::irc_ext::{self, IrcExt};
+use crate::irc_state::CaseMapping;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+use crate::seen::format_duration;
+use crate::storage::{self, Row, StorageScope};
+
+// Quotes are numbered per channel, the score is the sum of the votes
+pub const MIGRATIONS: &[&str] = &["
+    CREATE TABLE quote_quotes (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        number INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        added_by TEXT NOT NULL,
+        display_added_by TEXT NOT NULL,
+        added INTEGER NOT NULL,
+        score INTEGER NOT NULL,
+        UNIQUE (server, channel, number)
+    );
+    CREATE TABLE quote_votes (
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        number INTEGER NOT NULL,
+        voter TEXT NOT NULL,
+        vote INTEGER NOT NULL,
+        PRIMARY KEY (server, channel, number, voter)
+    );
+"];
+// Other matches listed after a search result
+const MAX_LISTED: usize = 10;
+
+pub struct QuotePrivMsgEvent {}
+
+// How quotes are imported and exported, everything but the text is optional when importing
+#[derive(Serialize, Deserialize)]
+pub struct ExportedQuote {
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub text: String,
+    #[serde(default)]
+    pub added_by: String,
+    // Seconds since the epoch, now if not set
+    #[serde(default)]
+    pub added: i64,
+    #[serde(default)]
+    pub score: i64,
+}
+
+impl QuotePrivMsgEvent {
+    fn add(&self, request: &PrivMsgRequest, storage: &StorageScope, text: &str) -> Result<String> {
+        let number = add_quote(storage, None, text, &request.irc_state.fold(&request.user.nick), &request.user.nick, storage::now(), 0)?;
+
+        Ok(format!("Quote #{} added", number))
+    }
+
+    fn delete(&self, request: &PrivMsgRequest, storage: &StorageScope, number: i64) -> Result<String> {
+        let quote = match quote(storage, number)? {
+            Some(quote) => quote,
+            None => return Ok(format!("There's no quote #{}", number)),
+        };
+
+        // Only admins and whoever added it
+        if !admin::is_admin(request.server, request.user) && storage::text(&quote, "added_by") != request.irc_state.fold(&request.user.nick) {
+            return Ok(format!("Only admins and {} can remove quote #{}", storage::text(&quote, "display_added_by"), number));
+        }
+
+        storage.delete("quotes", &[("number", Value::from(number))])?;
+        storage.delete("votes", &[("number", Value::from(number))])?;
+
+        Ok(format!("Quote #{} removed", number))
+    }
+
+    // Voting again the same way does nothing, the other way changes the vote
+    fn vote(&self, request: &PrivMsgRequest, storage: &StorageScope, number: i64, vote: i64) -> Result<String> {
+        let quote = match quote(storage, number)? {
+            Some(quote) => quote,
+            None => return Ok(format!("There's no quote #{}", number)),
+        };
+
+        let voter = voter(request);
+        let filter = [("number", Value::from(number)), ("voter", Value::from(voter.clone()))];
+        let previous = storage.select("votes", &filter, "", 1)?.first().map(|row| storage::integer(row, "vote")).unwrap_or_default();
+
+        if previous == vote {
+            return Ok(format!("You already voted on quote #{}", number));
+        }
+
+        let score = storage::integer(&quote, "score") - previous + vote;
+
+        storage.replace("votes", &[("number", Value::from(number)), ("voter", Value::from(voter)), ("vote", Value::from(vote))])?;
+        storage.update("quotes", &[("number", Value::from(number))], &[("score", Value::from(score))])?;
+
+        Ok(format!("Quote #{} now has a score of {:+}", number, score))
+    }
+
+    fn random(&self, storage: &StorageScope) -> Result<String> {
+        let quotes = storage.select("quotes", &[], "", 0)?;
+
+        match quotes.choose(&mut rand::thread_rng()) {
+            Some(quote) => Ok(describe(quote)),
+            None => Ok("There are no quotes yet".to_string()),
+        }
+    }
+
+    // A random quote with every word, and the numbers of some of the others
+    fn search(&self, storage: &StorageScope, words: &str) -> Result<String> {
+        let words: Vec<String> = words.to_lowercase().split_whitespace().map(|word| word.to_string()).collect();
+        let quotes: Vec<Row> = storage.select("quotes", &[], "number", 0)?.into_iter().filter(|quote| {
+            let text = storage::text(quote, "text").to_lowercase();
+
+            words.iter().all(|word| text.contains(word))
+        }).collect();
+
+        let quote = match quotes.choose(&mut rand::thread_rng()) {
+            Some(quote) => quote,
+            None => return Ok("No quotes found".to_string()),
+        };
+
+        if quotes.len() == 1 {
+            return Ok(describe(quote));
+        }
+
+        let mut others: Vec<String> = quotes.iter()
+            .filter(|other| storage::integer(other, "number") != storage::integer(quote, "number"))
+            .take(MAX_LISTED)
+            .map(|other| format!("#{}", storage::integer(other, "number")))
+            .collect();
+
+        if quotes.len() - 1 > MAX_LISTED {
+            others.push(format!("+{} more", quotes.len() - 1 - MAX_LISTED));
+        }
+
+        Ok(format!("{} (also: {})", describe(quote), others.join(", ")))
+    }
+}
+
+// Services account when it's known, otherwise user@host, so changing nicks doesn't allow voting again
+fn voter(request: &PrivMsgRequest) -> String {
+    match request.irc_state.user(&request.user.nick).and_then(|user| user.account.as_ref()) {
+        Some(account) => format!("account:{}", request.irc_state.fold(account)),
+        None => irc_ext::user_key(request.user),
+    }
+}
+
+// #12: <bob> hi (added by alice 3d 2h ago, score +2)
+fn describe(quote: &Row) -> String {
+    format!("#{}: {} (added by {} {} ago, score {:+})",
+            storage::integer(quote, "number"),
+            storage::text(quote, "text"),
+            storage::text(quote, "display_added_by"),
+            format_duration(storage::now() - storage::integer(quote, "added")),
+            storage::integer(quote, "score"))
+}
+
+fn quote(storage: &StorageScope, number: i64) -> Result<Option<Row>> {
+    Ok(storage.select("quotes", &[("number", Value::from(number))], "", 1)?.into_iter().next())
+}
+
+// The given number is kept if it isn't taken, otherwise the quote gets the next one. Returns the number
+fn add_quote(storage: &StorageScope, number: Option<i64>, text: &str, added_by: &str, display_added_by: &str, added: i64, score: i64) -> Result<i64> {
+    let number = match number {
+        Some(number) if number > 0 && quote(storage, number)?.is_none() => number,
+        _ => storage.select("quotes", &[], "number DESC", 1)?.first().map(|quote| storage::integer(quote, "number")).unwrap_or_default() + 1,
+    };
+
+    storage.insert("quotes", &[
+        ("number", Value::from(number)),
+        ("text", Value::from(text.to_string())),
+        ("added_by", Value::from(added_by.to_string())),
+        ("display_added_by", Value::from(display_added_by.to_string())),
+        ("added", Value::from(added)),
+        ("score", Value::from(score)),
+    ])?;
+
+    Ok(number)
+}
+
+// Quotes of a channel as JSON, for the quotes export subcommand
+pub fn export(storage: &StorageScope) -> Result<String> {
+    let quotes: Vec<ExportedQuote> = storage.select("quotes", &[], "number", 0)?.iter().map(|quote| ExportedQuote {
+        id: Some(storage::integer(quote, "number")),
+        text: storage::text(quote, "text"),
+        added_by: storage::text(quote, "display_added_by"),
+        added: storage::integer(quote, "added"),
+        score: storage::integer(quote, "score"),
+    }).collect();
+
+    Ok(serde_json::to_string_pretty(&quotes)?)
+}
+
+// Adds the quotes in the JSON to the channel, returns how many were added
+pub fn import(storage: &StorageScope, casemapping: CaseMapping, json: &str) -> Result<usize> {
+    let quotes: Vec<ExportedQuote> = serde_json::from_str(json)?;
+
+    // All or nothing, so a failed import can be run again
+    storage.transaction(|storage| {
+        for quote in &quotes {
+            let added = if quote.added > 0 { quote.added } else { storage::now() };
+
+            add_quote(storage, quote.id, &quote.text, &casemapping.fold(&quote.added_by), &quote.added_by, added, quote.score)?;
+        }
+
+        Ok(quotes.len())
+    })
+}
+
+fn parse_number(argument: &str) -> Option<i64> {
+    argument.trim_start_matches('#').parse().ok()
+}
+
+impl PrivMsgEvent for QuotePrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "quote"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        let arguments = match request.command("quote") {
+            Some(arguments) => arguments,
+            None => return vec![],
+        };
+
+        let message = if !request.source.is_channel_name() {
+            "Quotes are kept for each channel, use it in one".to_string()
+        } else {
+            match request.storage(self.name()) {
+                Some(storage) => {
+                    let (subcommand, rest) = arguments.split_once(' ').map(|(subcommand, rest)| (subcommand, rest.trim())).unwrap_or((arguments, ""));
+
+                    let result = match (subcommand, rest, parse_number(rest)) {
+                        ("" | "random", "", _) => self.random(&storage),
+                        ("add", "", _) => Ok(format!("Usage: {}quote add <text>", request.command_prefix)),
+                        ("add", text, _) => self.add(&request, &storage, text),
+                        ("del", _, Some(number)) => self.delete(&request, &storage, number),
+                        ("up", _, Some(number)) => self.vote(&request, &storage, number, 1),
+                        ("down", _, Some(number)) => self.vote(&request, &storage, number, -1),
+                        ("del" | "up" | "down", _, None) => Ok(format!("Usage: {}quote {} <id>", request.command_prefix, subcommand)),
+                        _ => match parse_number(arguments) {
+                            Some(number) => quote(&storage, number).map(|quote| quote.map(|quote| describe(&quote)).unwrap_or_else(|| format!("There's no quote #{}", number))),
+                            None => self.search(&storage, arguments),
+                        },
+                    };
+
+                    result.unwrap_or_else(|e| {
+                        log::error!("Couldn't access the quotes of {}: {}", request.source, e);
+
+                        "Couldn't access the quotes".to_string()
+                    })
+                }
+                None => "Storage isn't available".to_string(),
+            }
+        };
+
+        vec![PrivMsgResponse {
+            target: request.source.clone(),
+            message,
+            response_type: ResponseType::PrivMsg,
+        }]
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Storage;
+
+    fn storage() -> Arc<Storage> {
+        let storage = Storage::open(&StorageConfig { path: ":memory:".to_string() }).unwrap();
+
+        storage.migrate("quote", MIGRATIONS).unwrap();
+
+        storage
+    }
+
+    #[test]
+    fn exported_quotes_are_imported_back() {
+        let storage = storage();
+        let scope = storage.scope("quote", "irc.example.net", Some("#rust"));
+
+        add_quote(&scope, None, "<alice> hi", "alice", "Alice", 1700000000, 2).unwrap();
+        add_quote(&scope, None, "<bob> bye", "bob", "bob", 1700000100, -1).unwrap();
+
+        let json = export(&scope).unwrap();
+        let other = storage.scope("quote", "irc.example.net", Some("#other"));
+
+        assert_eq!(import(&other, CaseMapping::Rfc1459, &json).unwrap(), 2);
+        assert_eq!(export(&other).unwrap(), json);
+    }
+
+    #[test]
+    fn imported_quotes_keep_free_numbers() {
+        let storage = storage();
+        let scope = storage.scope("quote", "irc.example.net", Some("#rust"));
+
+        add_quote(&scope, None, "first", "alice", "alice", 1700000000, 0).unwrap();
+
+        // 1 is taken, so that quote goes after the highest number
+        let json = r#"[{"id": 5, "text": "fifth", "added_by": "Bob[m]"}, {"id": 1, "text": "taken"}, {"text": "no id"}]"#;
+
+        assert_eq!(import(&scope, CaseMapping::Rfc1459, json).unwrap(), 3);
+
+        let quotes: Vec<(i64, String)> = scope.select("quotes", &[], "number", 0).unwrap().iter()
+            .map(|quote| (storage::integer(quote, "number"), storage::text(quote, "text")))
+            .collect();
+
+        assert_eq!(quotes, vec![(1, "first".to_string()), (5, "fifth".to_string()), (6, "taken".to_string()), (7, "no id".to_string())]);
+
+        let fifth = quote(&scope, 5).unwrap().unwrap();
+
+        assert_eq!(storage::text(&fifth, "added_by"), "bob{m}");
+        assert_eq!(storage::text(&fifth, "display_added_by"), "Bob[m]");
+        assert!(storage::integer(&fifth, "added") > 0);
+    }
+
+    #[test]
+    fn invalid_imports_add_nothing() {
+        let storage = storage();
+        let scope = storage.scope("quote", "irc.example.net", Some("#rust"));
+
+        assert!(import(&scope, CaseMapping::Rfc1459, r#"[{"id": 1}]"#).is_err());
+        assert!(import(&scope, CaseMapping::Rfc1459, "not json").is_err());
+        assert_eq!(export(&scope).unwrap(), "[]");
+    }
+}
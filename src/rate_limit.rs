@@ -0,0 +1,258 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use simple_irc::Prefix;
+
+use crate::config::{CtcpRateLimitConfig, RateLimitConfig};
+use crate::irc_ext;
+
+pub enum RateLimitResult {
+    Allowed,
+    Limited,
+    // The user just exceeded the violation threshold and is now temporarily ignored
+    Ignored(Duration),
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    user_requests: HashMap<String, VecDeque<Instant>>,
+    channel_requests: HashMap<String, VecDeque<Instant>>,
+    command_requests: HashMap<(String, String), Instant>,
+    violations: HashMap<String, (u32, Instant)>,
+    ctcp_requests: VecDeque<Instant>,
+    // Temporary ignores, keyed by user@host so changing nicks doesn't help. Until when, and the hostmask
+    // that was ignored, for listing and removing them
+    pub ignored: HashMap<String, (Instant, String)>,
+    last_prune: Option<Instant>,
+}
+
+impl RateLimiter {
+    // The channel is folded by the caller, with the server casemapping
+    pub fn check(&mut self, config: &RateLimitConfig, user: &Prefix, channel: Option<&str>, command: &str) -> RateLimitResult {
+        let now = Instant::now();
+        let user_key = irc_ext::user_key(user);
+
+        self.prune(config, now);
+
+        if self.is_ignored(&user_key) {
+            return RateLimitResult::Limited;
+        }
+
+        let user_period = Duration::from_secs(config.user_period);
+        let channel_period = Duration::from_secs(config.channel_period);
+        let command_key = (user_key.clone(), command.to_lowercase());
+
+        let cooling_down = self.command_requests.get(&command_key)
+            .is_some_and(|last_request| now.duration_since(*last_request) < Duration::from_secs(config.command_cooldown));
+        let user_requests = self.user_requests.entry(user_key.clone()).or_default();
+
+        if !cooling_down && within_limit(user_requests, now, user_period, config.user_requests) {
+            // The user isn't at fault when the channel is busy, so it's not a violation
+            if let Some(channel) = channel {
+                let channel_requests = self.channel_requests.entry(channel.to_string()).or_default();
+
+                if !within_limit(channel_requests, now, channel_period, config.channel_requests) {
+                    return RateLimitResult::Limited;
+                }
+
+                record(channel_requests, now, config.channel_requests);
+            }
+
+            // Only commands that are run count
+            record(user_requests, now, config.user_requests);
+            self.command_requests.insert(command_key, now);
+
+            return RateLimitResult::Allowed;
+        }
+
+        let violations = self.violations.entry(user_key.clone()).or_insert((0, now));
+
+        // Violations are forgotten after a quiet period
+        if now.duration_since(violations.1) > user_period {
+            violations.0 = 0;
+        }
+
+        violations.0 += 1;
+        violations.1 = now;
+
+        if config.ignore_threshold > 0 && violations.0 >= config.ignore_threshold {
+            let duration = Duration::from_secs(config.ignore_duration);
+
+            self.violations.remove(&user_key);
+            self.ignored.insert(user_key, (now + duration, user.to_string()));
+
+            return RateLimitResult::Ignored(duration);
+        }
+
+        RateLimitResult::Limited
+    }
+
+    pub fn check_ctcp(&mut self, config: &CtcpRateLimitConfig) -> bool {
+        let now = Instant::now();
+
+        if !within_limit(&mut self.ctcp_requests, now, Duration::from_secs(config.period), config.requests) {
+            return false;
+        }
+
+        record(&mut self.ctcp_requests, now, config.requests);
+
+        true
+    }
+
+    pub fn is_ignored(&self, user_key: &str) -> bool {
+        match self.ignored.get(user_key) {
+            Some((until, _)) => Instant::now() < *until,
+            None => false,
+        }
+    }
+
+    fn prune(&mut self, config: &RateLimitConfig, now: Instant) {
+        if let Some(last_prune) = self.last_prune {
+            if now.duration_since(last_prune) < Duration::from_secs(60) {
+                return;
+            }
+        }
+
+        self.last_prune = Some(now);
+
+        let user_period = Duration::from_secs(config.user_period);
+        let channel_period = Duration::from_secs(config.channel_period);
+        let command_cooldown = Duration::from_secs(config.command_cooldown);
+
+        self.user_requests.retain(|_, requests| requests.back().is_some_and(|last| now.duration_since(*last) < user_period));
+        self.channel_requests.retain(|_, requests| requests.back().is_some_and(|last| now.duration_since(*last) < channel_period));
+        self.command_requests.retain(|_, last| now.duration_since(*last) < command_cooldown);
+        self.violations.retain(|_, (_, last)| now.duration_since(*last) < user_period);
+        self.ignored.retain(|_, (until, _)| now < *until);
+    }
+}
+
+// Sliding window, drops the requests that left it and tells whether there's room for another
+fn within_limit(requests: &mut VecDeque<Instant>, now: Instant, period: Duration, limit: usize) -> bool {
+    if limit == 0 {
+        return true;
+    }
+
+    while requests.front().is_some_and(|first| now.duration_since(*first) >= period) {
+        requests.pop_front();
+    }
+
+    requests.len() < limit
+}
+
+fn record(requests: &mut VecDeque<Instant>, now: Instant, limit: usize) {
+    if limit > 0 {
+        requests.push_back(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(nick: &str, host: &str) -> Prefix {
+        Prefix::new_with_all(nick, Some("user"), Some(host))
+    }
+
+    // Records it when there's room, like a command that is run
+    fn request(requests: &mut VecDeque<Instant>, now: Instant, limit: usize) -> bool {
+        let allowed = within_limit(requests, now, Duration::from_secs(10), limit);
+
+        if allowed {
+            record(requests, now, limit);
+        }
+
+        allowed
+    }
+
+    #[test]
+    fn window_slides() {
+        let start = Instant::now();
+        let mut requests = VecDeque::new();
+
+        assert!(request(&mut requests, start, 2));
+        assert!(request(&mut requests, start + Duration::from_secs(5), 2));
+        assert!(!request(&mut requests, start + Duration::from_secs(9), 2));
+
+        // The first request left the window
+        assert!(request(&mut requests, start + Duration::from_secs(10), 2));
+        assert!(!request(&mut requests, start + Duration::from_secs(11), 2));
+    }
+
+    #[test]
+    fn no_limit_when_zero() {
+        let mut requests = VecDeque::new();
+
+        assert!((0..100).all(|_| request(&mut requests, Instant::now(), 0)));
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn same_command_waits_for_the_cooldown() {
+        let config = RateLimitConfig { ..Default::default() };
+        let mut limiter = RateLimiter::default();
+        let alice = user("alice", "a.example");
+
+        assert!(matches!(limiter.check(&config, &alice, Some("#chan"), "geoip"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &alice, Some("#chan"), "GEOIP"), RateLimitResult::Limited));
+        assert!(matches!(limiter.check(&config, &alice, Some("#chan"), "dns"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("bob", "b.example"), Some("#chan"), "geoip"), RateLimitResult::Allowed));
+    }
+
+    #[test]
+    fn users_and_channels_have_their_own_limits() {
+        let config = RateLimitConfig { user_requests: 2, channel_requests: 3, command_cooldown: 0, ..Default::default() };
+        let mut limiter = RateLimiter::default();
+
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), Some("#chan"), "a"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), Some("#chan"), "b"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), Some("#chan"), "c"), RateLimitResult::Limited));
+        assert!(matches!(limiter.check(&config, &user("bob", "b.example"), Some("#chan"), "a"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("carol", "c.example"), Some("#chan"), "a"), RateLimitResult::Limited));
+        assert!(matches!(limiter.check(&config, &user("carol", "c.example"), Some("#other"), "a"), RateLimitResult::Allowed));
+    }
+
+    #[test]
+    fn busy_channels_do_not_count_against_the_user() {
+        let config = RateLimitConfig { user_requests: 2, channel_requests: 1, command_cooldown: 0, ignore_threshold: 2, ..Default::default() };
+        let mut limiter = RateLimiter::default();
+
+        assert!(matches!(limiter.check(&config, &user("bob", "b.example"), Some("#chan"), "a"), RateLimitResult::Allowed));
+
+        for _ in 0..5 {
+            assert!(matches!(limiter.check(&config, &user("alice", "a.example"), Some("#chan"), "a"), RateLimitResult::Limited));
+        }
+
+        // None of the rejected commands were run, so alice can still run two
+        assert!(!limiter.is_ignored("user@a.example"));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), Some("#other"), "a"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), None, "b"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), None, "c"), RateLimitResult::Limited));
+    }
+
+    #[test]
+    fn repeated_violations_ignore_the_host() {
+        let config = RateLimitConfig { ignore_threshold: 3, ..Default::default() };
+        let mut limiter = RateLimiter::default();
+
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), None, "geoip"), RateLimitResult::Allowed));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), None, "geoip"), RateLimitResult::Limited));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), None, "geoip"), RateLimitResult::Limited));
+        assert!(matches!(limiter.check(&config, &user("alice", "a.example"), None, "geoip"), RateLimitResult::Ignored(duration) if duration == Duration::from_secs(600)));
+
+        // Changing nicks doesn't help
+        assert!(limiter.is_ignored("user@a.example"));
+        assert!(matches!(limiter.check(&config, &user("alice_", "a.example"), None, "dns"), RateLimitResult::Limited));
+        assert_eq!(limiter.ignored["user@a.example"].1, "alice!user@a.example");
+    }
+
+    #[test]
+    fn ctcp_limit_is_global() {
+        let config = CtcpRateLimitConfig { requests: 2, period: 10 };
+        let mut limiter = RateLimiter::default();
+
+        assert!(limiter.check_ctcp(&config));
+        assert!(limiter.check_ctcp(&config));
+        assert!(!limiter.check_ctcp(&config));
+    }
+}
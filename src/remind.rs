@@ -0,0 +1,407 @@
+use chrono::{Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::config::RemindConfig;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType};
+use crate::scheduler::{Job, Scheduler};
+use crate::seen::format_duration;
+use crate::storage;
+
+const UNITS: [(&[&str], i64); 5] = [
+    (&["s", "sec", "secs", "second", "seconds"], 1),
+    (&["m", "min", "mins", "minute", "minutes"], 60),
+    (&["h", "hr", "hrs", "hour", "hours"], 3600),
+    (&["d", "day", "days"], 86400),
+    (&["w", "week", "weeks"], 604800),
+];
+// Reminders that fire later than this, like after a disconnection, say when they were due
+const LATE_AFTER: i64 = 60;
+
+pub struct RemindPrivMsgEvent {
+    pub config: RemindConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reminder {
+    // Who is reminded
+    nick: String,
+    text: String,
+}
+
+impl RemindPrivMsgEvent {
+    // The user's own timezone, set with .remind tz
+    fn timezone(&self, request: &PrivMsgRequest) -> Tz {
+        let key = format!("timezone:{}", request.irc_state.fold(&request.user.nick));
+        let timezone = request.server_storage(self.name()).and_then(|storage| storage.get(&key).ok().flatten());
+
+        timezone.unwrap_or_else(|| self.config.timezone.clone()).parse::<Tz>().unwrap_or(Tz::UTC)
+    }
+
+    fn set_timezone(&self, request: &PrivMsgRequest, timezone: &str) -> String {
+        let key = format!("timezone:{}", request.irc_state.fold(&request.user.nick));
+
+        if timezone.is_empty() {
+            return format!("Your timezone is {}", self.timezone(request));
+        }
+
+        let timezone = match timezone.parse::<Tz>() {
+            Ok(timezone) => timezone,
+            Err(_) => return format!("Unknown timezone: {}, use a name like America/Sao_Paulo", timezone),
+        };
+
+        match request.server_storage(self.name()).map(|storage| storage.set(&key, timezone.name())) {
+            Some(Ok(())) => format!("Your timezone is now {}", timezone),
+            Some(Err(e)) => {
+                log::error!("Couldn't store the timezone of {}: {}", request.user.nick, e);
+
+                "Couldn't store your timezone".to_string()
+            }
+            None => "Storage isn't available".to_string(),
+        }
+    }
+
+    fn remind(&self, request: &PrivMsgRequest, scheduler: &Scheduler, arguments: &str) -> String {
+        let mut words: Vec<&str> = arguments.split_whitespace().collect();
+        let nick = match words.first() {
+            Some(&"in") | Some(&"at") => request.user.nick.clone(),
+            Some(&"me") => {
+                words.remove(0);
+
+                request.user.nick.clone()
+            }
+            Some(nick) => {
+                let nick = nick.to_string();
+
+                words.remove(0);
+
+                nick
+            }
+            None => return self.usage(request),
+        };
+
+        let timezone = self.timezone(request);
+        let now = storage::now();
+        let (due, used) = match words.first() {
+            Some(&"in") => match parse_duration(&words[1..]).and_then(|(seconds, used)| Some((now.checked_add(seconds)?, used + 1))) {
+                Some(due) => due,
+                None => return format!("Couldn't understand the duration, use something like {}remind me in 2h30m <text>", request.command_prefix),
+            },
+            Some(&"at") => match parse_time(&words[1..], timezone, now) {
+                Ok((due, used)) => (due, used + 1),
+                Err(e) => return e,
+            },
+            _ => return self.usage(request),
+        };
+
+        let text = words[used..].join(" ");
+
+        if text.is_empty() {
+            return "What should I remind about?".to_string();
+        }
+
+        if due <= now {
+            return "That's in the past".to_string();
+        }
+
+        if due - now > self.config.max_days * 86400 {
+            return format!("Reminders can be set up to {} days ahead", self.config.max_days);
+        }
+
+        let user = request.irc_state.fold(&request.user.nick);
+        let pending = match scheduler.jobs() {
+            Ok(jobs) => jobs.iter().filter(|job| request.irc_state.fold(&job.nick) == user).count(),
+            Err(e) => return storage_error(e),
+        };
+
+        if pending >= self.config.max_reminders {
+            return format!("You already have {} reminders, cancel one with {}remind cancel <id>", pending, request.command_prefix);
+        }
+
+        match scheduler.schedule(due, request.source, &request.user.nick, &Reminder { nick: nick.clone(), text }) {
+            Ok(id) => {
+                let who = if request.irc_state.fold(&nick) == user { "you".to_string() } else { nick };
+
+                format!("I'll remind {} in {} ({}), #{}", who, format_duration(due - now), format_time(due, timezone), id)
+            }
+            Err(e) => storage_error(e),
+        }
+    }
+
+    // Reminders the user created or is going to get
+    fn user_reminders(&self, request: &PrivMsgRequest, scheduler: &Scheduler) -> anyhow::Result<Vec<(Job, Reminder)>> {
+        let user = request.irc_state.fold(&request.user.nick);
+        let mut reminders: Vec<(Job, Reminder)> = vec![];
+
+        for job in scheduler.jobs()? {
+            let reminder: Reminder = job.data()?;
+
+            if request.irc_state.fold(&job.nick) == user || request.irc_state.fold(&reminder.nick) == user {
+                reminders.push((job, reminder));
+            }
+        }
+
+        Ok(reminders)
+    }
+
+    fn list(&self, request: &PrivMsgRequest, scheduler: &Scheduler) -> String {
+        let reminders = match self.user_reminders(request, scheduler) {
+            Ok(reminders) => reminders,
+            Err(e) => return storage_error(e),
+        };
+
+        if reminders.is_empty() {
+            return "You don't have any reminders".to_string();
+        }
+
+        let timezone = self.timezone(request);
+        let user = request.irc_state.fold(&request.user.nick);
+
+        reminders.iter().map(|(job, reminder)| {
+            let who = if request.irc_state.fold(&reminder.nick) == user { "".to_string() } else { format!(" for {}", reminder.nick) };
+            let from = if request.irc_state.fold(&job.nick) == user { "".to_string() } else { format!(" from {}", job.nick) };
+
+            format!("#{} in {} ({}){}{}: {}", job.id, format_duration(job.due - storage::now()), format_time(job.due, timezone), who, from, reminder.text)
+        }).collect::<Vec<String>>().join(" / ")
+    }
+
+    fn cancel(&self, request: &PrivMsgRequest, scheduler: &Scheduler, id: &str) -> String {
+        let id = match id.trim_start_matches('#').parse::<i64>() {
+            Ok(id) => id,
+            Err(_) => return format!("Usage: {}remind cancel <id>", request.command_prefix),
+        };
+
+        let user = request.irc_state.fold(&request.user.nick);
+        let job = match scheduler.job(id) {
+            Ok(job) => job,
+            Err(e) => return storage_error(e),
+        };
+
+        // Either who created it or who is going to get it
+        let allowed = job.is_some_and(|job| {
+            request.irc_state.fold(&job.nick) == user || job.data::<Reminder>().is_ok_and(|reminder| request.irc_state.fold(&reminder.nick) == user)
+        });
+
+        if !allowed {
+            return format!("You don't have a reminder #{}", id);
+        }
+
+        match scheduler.cancel(id) {
+            Ok(_) => format!("Reminder #{} cancelled", id),
+            Err(e) => storage_error(e),
+        }
+    }
+
+    fn usage(&self, request: &PrivMsgRequest) -> String {
+        format!("Usage: {0}remind [me|<nick>] in <2h30m> <text>, {0}remind [me|<nick>] at [YYYY-MM-DD] <HH:MM> <text>, {0}remind list, {0}remind cancel <id> or {0}remind tz [timezone]", request.command_prefix)
+    }
+}
+
+// Like 2h30m, 1d 12h or 2 hours 30 minutes, returns the seconds and how many words were used
+fn parse_duration(words: &[&str]) -> Option<(i64, usize)> {
+    let mut seconds: i64 = 0;
+    let mut used = 0;
+
+    while used < words.len() {
+        let word = words[used].to_lowercase();
+        let (value, unit_words) = match parse_duration_word(&word) {
+            Some(value) => (value, 1),
+            // The unit can be the next word
+            None => match (word.parse::<i64>(), words.get(used + 1).and_then(|unit| unit_seconds(&unit.to_lowercase()))) {
+                (Ok(value), Some(unit)) => (value.checked_mul(unit)?, 2),
+                _ => break,
+            },
+        };
+
+        seconds = seconds.checked_add(value)?;
+        used += unit_words;
+    }
+
+    Some((seconds, used)).filter(|(seconds, _)| *seconds > 0)
+}
+
+// Numbers each followed by a unit, like 1h30m
+fn parse_duration_word(word: &str) -> Option<i64> {
+    let mut seconds: i64 = 0;
+    let mut rest = word;
+
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value = rest[..digits].parse::<i64>().ok()?;
+        let rest_unit = &rest[digits..];
+        let unit_length = rest_unit.find(|c: char| c.is_ascii_digit()).unwrap_or(rest_unit.len());
+
+        seconds = seconds.checked_add(value.checked_mul(unit_seconds(&rest_unit[..unit_length])?)?)?;
+        rest = &rest_unit[unit_length..];
+    }
+
+    Some(seconds).filter(|seconds| *seconds > 0)
+}
+
+fn unit_seconds(unit: &str) -> Option<i64> {
+    UNITS.iter().find(|(names, _)| names.contains(&unit)).map(|(_, seconds)| *seconds)
+}
+
+// [YYYY-MM-DD|today|tomorrow] HH:MM in the user's timezone, without a date it's the next time it's that time
+fn parse_time(words: &[&str], timezone: Tz, now: i64) -> Result<(i64, usize), String> {
+    let today = Utc.timestamp_opt(now, 0).unwrap().with_timezone(&timezone).date_naive();
+    let (date, used) = match words.first().map(|word| word.to_lowercase()).as_deref() {
+        Some("today") => (Some(today), 1),
+        Some("tomorrow") => (Some(today + Duration::days(1)), 1),
+        Some(word) => match NaiveDate::parse_from_str(word, "%Y-%m-%d") {
+            Ok(date) => (Some(date), 1),
+            Err(_) => (None, 0),
+        },
+        None => (None, 0),
+    };
+
+    let time = match words.get(used).map(|word| NaiveTime::parse_from_str(word, "%H:%M")) {
+        Some(Ok(time)) => time,
+        _ => return Err("Couldn't understand the time, use something like 2026-11-01 18:00 or 18:00".to_string()),
+    };
+
+    let mut local = date.unwrap_or(today).and_time(time);
+
+    if date.is_none() && timezone.from_local_datetime(&local).earliest().is_some_and(|due| due.timestamp() <= now) {
+        local += Duration::days(1);
+    }
+
+    match timezone.from_local_datetime(&local).earliest() {
+        Some(due) => Ok((due.timestamp(), used + 1)),
+        None => Err(format!("{} doesn't exist in {}", local.format("%Y-%m-%d %H:%M"), timezone)),
+    }
+}
+
+fn format_time(time: i64, timezone: Tz) -> String {
+    Utc.timestamp_opt(time, 0).unwrap().with_timezone(&timezone).format("%Y-%m-%d %H:%M %Z").to_string()
+}
+
+fn storage_error(e: anyhow::Error) -> String {
+    log::error!("Couldn't access the reminders: {}", e);
+
+    "Couldn't access the reminders".to_string()
+}
+
+impl PrivMsgEvent for RemindPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "remind"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        if let Some(arguments) = request.command("remind") {
+            let (subcommand, rest) = arguments.split_once(' ').map(|(subcommand, rest)| (subcommand, rest.trim())).unwrap_or((arguments, ""));
+            let message = match request.scheduler(self.name()) {
+                None => "Storage isn't available".to_string(),
+                Some(_) if subcommand == "tz" => self.set_timezone(&request, rest),
+                Some(scheduler) => match subcommand {
+                    "list" if rest.is_empty() => self.list(&request, &scheduler),
+                    "cancel" => self.cancel(&request, &scheduler, rest),
+                    "" => self.usage(&request),
+                    _ => self.remind(&request, &scheduler, arguments),
+                },
+            };
+
+            return vec![PrivMsgResponse {
+                target: request.source.clone(),
+                message,
+                response_type: ResponseType::PrivMsg,
+            }];
+        }
+
+        vec![]
+    }
+
+    fn on_scheduled(&self, request: PrivMsgRequest, job: &Job) -> Vec<PrivMsgResponse> {
+        let reminder: Reminder = match job.data() {
+            Ok(reminder) => reminder,
+            Err(e) => {
+                log::error!("Invalid reminder #{}: {}", job.id, e);
+
+                return vec![];
+            }
+        };
+
+        let mut message = if request.irc_state.fold(&reminder.nick) == request.irc_state.fold(&job.nick) {
+            format!("{}: Reminder: {}", reminder.nick, reminder.text)
+        } else {
+            format!("{}: {} asked me {} ago to remind you: {}", reminder.nick, job.nick, format_duration(job.due - job.created), reminder.text)
+        };
+
+        let late = storage::now() - job.due;
+
+        if late > LATE_AFTER {
+            message = format!("{} (was due {} ago)", message, format_duration(late));
+        }
+
+        vec![PrivMsgResponse {
+            target: request.source.clone(),
+            message,
+            response_type: ResponseType::PrivMsg,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> i64 {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap().timestamp()
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration(&["2h30m", "check", "the", "oven"]), Some((9000, 1)));
+        assert_eq!(parse_duration(&["1", "day", "2", "Hours", "later"]), Some((93600, 4)));
+        assert_eq!(parse_duration(&["1w", "10", "s"]), Some((604810, 3)));
+        assert_eq!(parse_duration(&["tomorrow"]), None);
+        assert_eq!(parse_duration(&["0m"]), None);
+        assert_eq!(parse_duration(&["5", "apples"]), None);
+    }
+
+    #[test]
+    fn overflowing_durations_are_rejected() {
+        assert_eq!(parse_duration(&["9223372036854775807w"]), None);
+        assert_eq!(parse_duration(&["9223372036854775807", "seconds", "1s"]), None);
+        assert_eq!(parse_duration_word("99999999999999999999s"), None);
+    }
+
+    #[test]
+    fn duration_words_need_a_unit_after_each_number() {
+        assert_eq!(parse_duration_word("1h30m"), Some(5400));
+        assert_eq!(parse_duration_word("90min"), Some(5400));
+        assert_eq!(parse_duration_word("1h30"), None);
+        assert_eq!(parse_duration_word("h"), None);
+        assert_eq!(parse_duration_word("1y"), None);
+    }
+
+    #[test]
+    fn times_without_a_date_are_the_next_one() {
+        let now = timestamp(2026, 3, 1, 12, 0);
+
+        assert_eq!(parse_time(&["18:00", "dinner"], Tz::UTC, now), Ok((timestamp(2026, 3, 1, 18, 0), 1)));
+        assert_eq!(parse_time(&["09:00"], Tz::UTC, now), Ok((timestamp(2026, 3, 2, 9, 0), 1)));
+        // 12:00 UTC is 09:00 in São Paulo
+        assert_eq!(parse_time(&["10:00"], Tz::America__Sao_Paulo, now), Ok((timestamp(2026, 3, 1, 13, 0), 1)));
+    }
+
+    #[test]
+    fn times_with_a_date_are_kept() {
+        let now = timestamp(2026, 3, 1, 12, 0);
+
+        assert_eq!(parse_time(&["tomorrow", "09:00"], Tz::UTC, now), Ok((timestamp(2026, 3, 2, 9, 0), 2)));
+        assert_eq!(parse_time(&["Today", "09:00"], Tz::UTC, now), Ok((timestamp(2026, 3, 1, 9, 0), 2)));
+        assert_eq!(parse_time(&["2026-11-01", "18:00"], Tz::UTC, now), Ok((timestamp(2026, 11, 1, 18, 0), 2)));
+    }
+
+    #[test]
+    fn invalid_times_are_rejected() {
+        let now = timestamp(2026, 3, 1, 12, 0);
+
+        assert!(parse_time(&["tomorrow"], Tz::UTC, now).is_err());
+        assert!(parse_time(&["25:00"], Tz::UTC, now).is_err());
+        assert!(parse_time(&[], Tz::UTC, now).is_err());
+        // Skipped when the clocks went forward
+        assert_eq!(parse_time(&["2026-03-08", "02:30"], Tz::America__New_York, now), Err("2026-03-08 02:30 doesn't exist in America/New_York".to_string()));
+    }
+}
@@ -0,0 +1,418 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::net::{TcpStream, UdpSocket};
+use async_std::task;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{Name, RData, RecordType};
+
+use crate::config::{DnsConfig, IpPreference};
+
+const DNS_PORT: u16 = 53;
+const MAX_UDP_SIZE: usize = 4096;
+
+// Everything that needs DNS goes through this, so lookups can be pointed somewhere else
+pub trait Resolver: Send + Sync {
+    // A and AAAA records of a hostname, ordered by the configured preference
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+
+    // Hostname the IP points back to, None when it doesn't have one
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<Option<String>>>;
+
+    // The whole answer for any record type, with DNSSEC the nameserver tells whether it validated the answer
+    fn query<'a>(&'a self, name: &'a str, record_type: RecordType, dnssec: bool) -> BoxFuture<'a, io::Result<Message>>;
+}
+
+// Uses the system configuration, like /etc/hosts and /etc/resolv.conf. Its lookups block, they run in the executor's blocking pool
+pub struct SystemResolver {
+    pub timeout: Duration,
+    pub prefer: IpPreference,
+}
+
+// Asks the configured nameservers directly
+pub struct DnsResolver {
+    pub nameservers: Vec<SocketAddr>,
+    pub timeout: Duration,
+    pub prefer: IpPreference,
+}
+
+pub fn from_config(config: &DnsConfig) -> Arc<dyn Resolver> {
+    let timeout = Duration::from_secs(config.timeout);
+    let nameservers: Vec<SocketAddr> = config.nameservers.iter().filter_map(|nameserver| {
+        let address = parse_nameserver(nameserver);
+
+        if address.is_none() {
+            log::error!("Invalid nameserver: {}", nameserver);
+        }
+
+        address
+    }).collect();
+
+    if nameservers.is_empty() {
+        return Arc::new(SystemResolver { timeout, prefer: config.prefer });
+    }
+
+    Arc::new(DnsResolver { nameservers, timeout, prefer: config.prefer })
+}
+
+impl Resolver for SystemResolver {
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![ip]);
+            }
+
+            let name = host.to_string();
+            let ips = async_std::io::timeout(self.timeout, task::spawn_blocking(move || dns_lookup::lookup_host(&name))).await?;
+            let mut addresses: Vec<IpAddr> = vec![];
+
+            // One of each is returned for every socket type
+            for ip in ips {
+                if !addresses.contains(&ip) {
+                    addresses.push(ip);
+                }
+            }
+
+            prefer(addresses, self.prefer, host)
+        }.boxed()
+    }
+
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<Option<String>>> {
+        async move {
+            let ptr = async_std::io::timeout(self.timeout, task::spawn_blocking(move || dns_lookup::lookup_addr(&ip))).await?;
+
+            // The IP itself is returned when there's no PTR record
+            Ok(Some(ptr).filter(|ptr| *ptr != ip.to_string()))
+        }.boxed()
+    }
+
+    // The system resolver only knows about addresses, other records are asked to its nameservers
+    fn query<'a>(&'a self, name: &'a str, record_type: RecordType, dnssec: bool) -> BoxFuture<'a, io::Result<Message>> {
+        async move {
+            let resolver = DnsResolver { nameservers: system_nameservers()?, timeout: self.timeout, prefer: self.prefer };
+
+            resolver.query(name, record_type, dnssec).await
+        }.boxed()
+    }
+}
+
+impl DnsResolver {
+    // Each nameserver is tried in turn until one of them answers
+    async fn send_query(&self, name: &Name, record_type: RecordType, dnssec: bool) -> io::Result<Message> {
+        let mut request = Message::new();
+
+        request.set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name.clone(), record_type));
+
+        if dnssec {
+            let mut edns = Edns::new();
+
+            edns.set_max_payload(MAX_UDP_SIZE as u16).set_dnssec_ok(true);
+
+            // Asks validating nameservers to say whether they validated the answer
+            request.set_authentic_data(true).set_edns(edns);
+        }
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No nameservers configured");
+
+        for nameserver in &self.nameservers {
+            match async_std::io::timeout(self.timeout, exchange(*nameserver, &request)).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    log::warn!("Nameserver {} didn't answer {} {}: {}", nameserver, record_type, name, e);
+
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn lookup_records(&self, host: &str, record_type: RecordType) -> io::Result<Vec<RData>> {
+        let response = self.query(host, record_type, false).await?;
+
+        match response.response_code() {
+            ResponseCode::NoError => Ok(response.answers().iter().filter_map(|record| record.data().cloned()).collect()),
+            ResponseCode::NXDomain => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist", host))),
+            code => Err(io::Error::other(format!("Nameserver answered {}", code))),
+        }
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![ip]);
+            }
+
+            let (a, aaaa) = future::join(
+                self.lookup_records(host, RecordType::A),
+                self.lookup_records(host, RecordType::AAAA),
+            ).await;
+
+            // CNAMEs are answered together with the records they point to
+            let addresses: Vec<IpAddr> = match (a, aaaa) {
+                (Err(e), Err(_)) => return Err(e),
+                (a, aaaa) => a.into_iter().chain(aaaa).flatten().filter_map(|data| match data {
+                    RData::A(a) => Some(IpAddr::V4(a.0)),
+                    RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+                    _ => None,
+                }).collect(),
+            };
+
+            if addresses.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't have any address", host)));
+            }
+
+            prefer(addresses, self.prefer, host)
+        }.boxed()
+    }
+
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<Option<String>>> {
+        async move {
+            let name = Name::from(ip);
+            let records = match self.lookup_records(&name.to_string(), RecordType::PTR).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                records => records?,
+            };
+
+            Ok(records.into_iter().find_map(|data| match data {
+                RData::PTR(ptr) => Some(ptr.0.to_string().trim_end_matches('.').to_string()),
+                _ => None,
+            }))
+        }.boxed()
+    }
+
+    fn query<'a>(&'a self, name: &'a str, record_type: RecordType, dnssec: bool) -> BoxFuture<'a, io::Result<Message>> {
+        async move {
+            let mut name = Name::from_utf8(name).map_err(invalid_data)?;
+
+            name.set_fqdn(true);
+
+            self.send_query(&name, record_type, dnssec).await
+        }.boxed()
+    }
+}
+
+// ip or ip:port, IPv6 addresses with a port are written like [::1]:53
+pub fn parse_nameserver(nameserver: &str) -> Option<SocketAddr> {
+    nameserver.parse::<SocketAddr>().ok().or_else(|| nameserver.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+}
+
+fn system_nameservers() -> io::Result<Vec<SocketAddr>> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    let nameservers: Vec<SocketAddr> = resolv_conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|nameserver| parse_nameserver(nameserver.trim()))
+        .collect();
+
+    if nameservers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No nameservers in /etc/resolv.conf"));
+    }
+
+    Ok(nameservers)
+}
+
+// Over UDP, or TCP when the answer doesn't fit
+async fn exchange(nameserver: SocketAddr, request: &Message) -> io::Result<Message> {
+    let bytes = request.to_vec().map_err(invalid_data)?;
+    let response = exchange_udp(nameserver, request.id(), &bytes).await?;
+
+    if !response.truncated() {
+        return Ok(response);
+    }
+
+    exchange_tcp(nameserver, request.id(), &bytes).await
+}
+
+async fn exchange_udp(nameserver: SocketAddr, id: u16, bytes: &[u8]) -> io::Result<Message> {
+    let socket = UdpSocket::bind(if nameserver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    let mut buffer = vec![0u8; MAX_UDP_SIZE];
+
+    socket.connect(nameserver).await?;
+    socket.send(bytes).await?;
+
+    loop {
+        let length = socket.recv(&mut buffer).await?;
+
+        // Late answers to previous queries are ignored
+        match Message::from_vec(&buffer[..length]) {
+            Ok(response) if response.id() == id => return Ok(response),
+            _ => continue,
+        }
+    }
+}
+
+// Messages are prefixed with their length
+async fn exchange_tcp(nameserver: SocketAddr, id: u16, bytes: &[u8]) -> io::Result<Message> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+
+    stream.write_all(&(bytes.len() as u16).to_be_bytes()).await?;
+    stream.write_all(bytes).await?;
+
+    let mut length = [0u8; 2];
+
+    stream.read_exact(&mut length).await?;
+
+    let mut buffer = vec![0u8; u16::from_be_bytes(length) as usize];
+
+    stream.read_exact(&mut buffer).await?;
+
+    let response = Message::from_vec(&buffer).map_err(invalid_data)?;
+
+    if response.id() != id {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Answer to another query"));
+    }
+
+    Ok(response)
+}
+
+fn prefer(mut addresses: Vec<IpAddr>, preference: IpPreference, host: &str) -> io::Result<Vec<IpAddr>> {
+    match preference {
+        IpPreference::Any => {}
+        IpPreference::Ipv4 => addresses.sort_by_key(|address| address.is_ipv6()),
+        IpPreference::Ipv6 => addresses.sort_by_key(|address| address.is_ipv4()),
+        IpPreference::Ipv4Only => addresses.retain(|address| address.is_ipv4()),
+        IpPreference::Ipv6Only => addresses.retain(|address| address.is_ipv6()),
+    }
+
+    if addresses.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't have any address of the preferred family", host)));
+    }
+
+    Ok(addresses)
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Answers from fixed records and counts the lookups, so what uses DNS can be tested without a nameserver
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeResolver {
+    pub hosts: std::collections::HashMap<String, Vec<IpAddr>>,
+    pub ptrs: std::collections::HashMap<IpAddr, String>,
+    pub lookups: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl FakeResolver {
+    fn count(&self) {
+        self.lookups.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn lookup_count(&self) -> usize {
+        self.lookups.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl Resolver for FakeResolver {
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        self.count();
+
+        let addresses = match host.parse::<IpAddr>() {
+            Ok(ip) => Ok(vec![ip]),
+            Err(_) => self.hosts.get(host).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist", host))),
+        };
+
+        future::ready(addresses).boxed()
+    }
+
+    fn lookup_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<Option<String>>> {
+        self.count();
+
+        future::ready(Ok(self.ptrs.get(&ip).cloned())).boxed()
+    }
+
+    // Addresses of the known hosts, anything else doesn't exist
+    fn query<'a>(&'a self, name: &'a str, record_type: RecordType, _dnssec: bool) -> BoxFuture<'a, io::Result<Message>> {
+        self.count();
+
+        let owner = match Name::from_utf8(name) {
+            Ok(owner) => owner,
+            Err(e) => return future::ready(Err(invalid_data(e))).boxed(),
+        };
+        let mut message = Message::new();
+
+        match self.hosts.get(name.trim_end_matches('.')) {
+            Some(addresses) => {
+                for address in addresses {
+                    let data = match (address, record_type) {
+                        (IpAddr::V4(ip), RecordType::A) => RData::A((*ip).into()),
+                        (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA((*ip).into()),
+                        _ => continue,
+                    };
+
+                    message.add_answer(hickory_proto::rr::Record::from_rdata(owner.clone(), 300, data));
+                }
+            }
+            None => {
+                message.set_response_code(ResponseCode::NXDomain);
+            }
+        }
+
+        future::ready(Ok(message)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use async_std::task;
+
+    use super::*;
+
+    fn addresses() -> Vec<IpAddr> {
+        vec![IpAddr::V6(Ipv6Addr::LOCALHOST), IpAddr::V4(Ipv4Addr::LOCALHOST)]
+    }
+
+    #[test]
+    fn prefers_the_configured_family() {
+        assert_eq!(prefer(addresses(), IpPreference::Ipv4, "localhost").unwrap()[0], IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(prefer(addresses(), IpPreference::Ipv6, "localhost").unwrap()[0], IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(prefer(addresses(), IpPreference::Any, "localhost").unwrap(), addresses());
+        assert_eq!(prefer(addresses(), IpPreference::Ipv4Only, "localhost").unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert!(prefer(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], IpPreference::Ipv6Only, "localhost").is_err());
+    }
+
+    #[test]
+    fn parses_nameservers_with_and_without_port() {
+        assert_eq!(parse_nameserver("127.0.0.1"), Some("127.0.0.1:53".parse().unwrap()));
+        assert_eq!(parse_nameserver("127.0.0.1:5353"), Some("127.0.0.1:5353".parse().unwrap()));
+        assert_eq!(parse_nameserver("::1"), Some("[::1]:53".parse().unwrap()));
+        assert_eq!(parse_nameserver("[::1]:5353"), Some("[::1]:5353".parse().unwrap()));
+        assert_eq!(parse_nameserver("localhost"), None);
+    }
+
+    #[test]
+    fn fake_resolver_answers_from_its_records() {
+        let mut resolver = FakeResolver::default();
+
+        resolver.hosts.insert("example.com".to_string(), addresses());
+
+        task::block_on(async {
+            assert_eq!(resolver.lookup_ip("example.com").await.unwrap(), addresses());
+            assert_eq!(resolver.lookup_ip("127.0.0.2").await.unwrap(), vec!["127.0.0.2".parse::<IpAddr>().unwrap()]);
+            assert_eq!(resolver.lookup_ip("nope.example").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+            let answer = resolver.query("example.com.", RecordType::AAAA, false).await.unwrap();
+
+            assert_eq!(answer.answers().len(), 1);
+            assert_eq!(resolver.query("nope.example.", RecordType::A, false).await.unwrap().response_code(), ResponseCode::NXDomain);
+        });
+
+        assert_eq!(resolver.lookup_count(), 5);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::announcement;
+use crate::config::{ChannelOverrides, Server};
+
+// Changes made by admins at runtime, stored apart from config.yml so it's never rewritten
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RuntimeConfig {
+    // Folded channel name -> what was changed in it
+    #[serde(default)]
+    pub channels: BTreeMap<String, ChannelOverrides>,
+    #[serde(default)]
+    pub ignores: Option<Vec<String>>,
+}
+
+pub fn load(server: &mut Server) -> Result<()> {
+    let path = server.runtime_config_path();
+
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+
+    let mut runtime_config: RuntimeConfig = serde_yaml::from_reader(File::open(&path)?)?;
+
+    // The file could have been edited by hand, the other changes still apply
+    for (channel, overrides) in runtime_config.channels.iter_mut() {
+        overrides.announcements.retain(|name, announcement| match announcement.as_ref().map(announcement::validate) {
+            Some(Err(e)) => {
+                log::error!("Ignoring invalid announcement {} in {}: {}", name, channel, e);
+
+                false
+            }
+            _ => true,
+        });
+    }
+
+    // Older files were keyed by lowercase name
+    server.channel_overrides = runtime_config.channels.into_iter().map(|(name, overrides)| (server.casemapping.fold(&name), overrides)).collect();
+    server.apply_overrides();
+
+    if let Some(ignores) = runtime_config.ignores {
+        server.ignores = ignores;
+    }
+
+    log::info!("Loaded runtime config from {}", path);
+
+    Ok(())
+}
+
+pub fn save(server: &Server) -> Result<()> {
+    let runtime_config = RuntimeConfig {
+        channels: server.channel_overrides.clone(),
+        ignores: Some(server.ignores.clone()),
+    };
+
+    let path = server.runtime_config_path();
+    let temporary = format!("{}.tmp", path);
+
+    // Written next to it and renamed over it, so a failed write doesn't lose what was there
+    fs::write(&temporary, serde_yaml::to_string(&runtime_config)?)?;
+    fs::rename(&temporary, &path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privmsg::FakeRequest;
+
+    #[test]
+    fn saved_changes_are_loaded_back() {
+        let directory = std::env::temp_dir().join(format!("runtime-config-{}", std::process::id()));
+        let path = directory.join("runtime.yml");
+        let mut server = FakeRequest::new("#chan", "admin", "").server;
+
+        fs::create_dir_all(&directory).unwrap();
+        server.runtime_config = Some(path.to_string_lossy().to_string());
+        server.ignores = vec!["*!*@spam.example".to_string()];
+        server.override_channel("#Chan", |overrides| overrides.command_prefix = Some("!".to_string()));
+
+        save(&server).unwrap();
+        save(&server).unwrap();
+
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+
+        let mut loaded = FakeRequest::new("#chan", "admin", "").server;
+
+        loaded.runtime_config = server.runtime_config.clone();
+        load(&mut loaded).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(loaded.ignores, vec!["*!*@spam.example"]);
+        assert_eq!(loaded.command_prefix(loaded.channel("#CHAN")), "!");
+    }
+
+    #[test]
+    fn invalid_announcements_are_not_loaded() {
+        let directory = std::env::temp_dir().join(format!("runtime-config-announcements-{}", std::process::id()));
+        let path = directory.join("runtime.yml");
+        let mut server = FakeRequest::new("#chan", "admin", "").server;
+
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(&path, "
+            channels:
+              '#chan':
+                command_prefix: '!'
+                announcements:
+                  rules: {name: rules, cron: '0 9 * * *', text: Be nice}
+                  broken: {name: broken, cron: '0 9 * *', text: Never}
+        ").unwrap();
+        server.runtime_config = Some(path.to_string_lossy().to_string());
+        load(&mut server).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let channel = server.channel("#chan").unwrap();
+
+        assert_eq!(channel.announcements.iter().map(|announcement| announcement.name.as_str()).collect::<Vec<_>>(), vec!["rules"]);
+        assert_eq!(server.command_prefix(Some(channel)), "!");
+    }
+
+    #[test]
+    fn channels_are_found_with_the_casemapping() {
+        let mut server = FakeRequest::new("#chan", "admin", "").server;
+
+        server.override_channel("#Rust[dev]", |overrides| overrides.command_prefix = Some("!".to_string()));
+
+        assert!(server.channel_overrides.contains_key("#rust{dev}"));
+        assert_eq!(server.channel("#rust{DEV}").map(|channel| channel.name.as_str()), Some("#rust{dev}"));
+        assert!(server.channel("#CHAN").is_some());
+        assert!(server.channel("#other").is_none());
+    }
+}
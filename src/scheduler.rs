@@ -0,0 +1,122 @@
+use anyhow::Result;
+use rusqlite::{params, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::storage::{self, Storage};
+
+// Plugins schedule jobs to be given back to them later, see PrivMsgEvent::on_scheduled
+pub const MIGRATIONS: &[&str] = &["
+    CREATE TABLE scheduler_jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        server TEXT NOT NULL,
+        plugin TEXT NOT NULL,
+        target TEXT NOT NULL,
+        nick TEXT NOT NULL,
+        due INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE INDEX scheduler_jobs_due ON scheduler_jobs (server, due);
+"];
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub plugin: String,
+    // Channel or nick the job answers to
+    pub target: String,
+    // Who scheduled it
+    pub nick: String,
+    // Seconds since the epoch
+    pub due: i64,
+    pub created: i64,
+    data: String,
+}
+
+// Jobs of a plugin in a server
+pub struct Scheduler<'a> {
+    storage: &'a Storage,
+    plugin: String,
+    server: String,
+}
+
+impl Job {
+    pub fn data<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_str(&self.data)?)
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Job {
+            id: row.get("id")?,
+            plugin: row.get("plugin")?,
+            target: row.get("target")?,
+            nick: row.get("nick")?,
+            due: row.get("due")?,
+            created: row.get("created")?,
+            data: row.get("data")?,
+        })
+    }
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(storage: &'a Storage, plugin: &str, server: &str) -> Self {
+        Scheduler { storage, plugin: plugin.to_string(), server: server.to_string() }
+    }
+
+    // Returns the id of the job
+    pub fn schedule<T: Serialize>(&self, due: i64, target: &str, nick: &str, data: &T) -> Result<i64> {
+        let data = serde_json::to_string(data)?;
+
+        self.storage.with_connection(|connection| {
+            connection.execute("INSERT INTO scheduler_jobs (server, plugin, target, nick, due, data, created) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                               params![self.server, self.plugin, target, nick, due, data, storage::now()])?;
+
+            Ok(connection.last_insert_rowid())
+        })
+    }
+
+    pub fn job(&self, id: i64) -> Result<Option<Job>> {
+        self.storage.with_connection(|connection| {
+            connection.query_row("SELECT * FROM scheduler_jobs WHERE server = ?1 AND plugin = ?2 AND id = ?3",
+                                 params![self.server, self.plugin, id],
+                                 Job::from_row).optional()
+        })
+    }
+
+    // Pending jobs, the next one first
+    pub fn jobs(&self) -> Result<Vec<Job>> {
+        self.storage.with_connection(|connection| {
+            let mut statement = connection.prepare("SELECT * FROM scheduler_jobs WHERE server = ?1 AND plugin = ?2 ORDER BY due, id")?;
+            let jobs = statement.query_map(params![self.server, self.plugin], Job::from_row)?;
+
+            jobs.collect()
+        })
+    }
+
+    // Returns whether the job was pending
+    pub fn cancel(&self, id: i64) -> Result<bool> {
+        let cancelled = self.storage.with_connection(|connection| {
+            connection.execute("DELETE FROM scheduler_jobs WHERE server = ?1 AND plugin = ?2 AND id = ?3", params![self.server, self.plugin, id])
+        })?;
+
+        Ok(cancelled > 0)
+    }
+}
+
+// Jobs of every plugin due by now, they stay pending until they're finished
+pub fn due(storage: &Storage, server: &str) -> Result<Vec<Job>> {
+    storage.with_connection(|connection| {
+        let mut statement = connection.prepare("SELECT * FROM scheduler_jobs WHERE server = ?1 AND due <= ?2 ORDER BY due, id")?;
+        let jobs = statement.query_map(params![server, storage::now()], Job::from_row)?;
+
+        jobs.collect()
+    })
+}
+
+// Removes a job once it was delivered, so it runs only once
+pub fn finish(storage: &Storage, id: i64) -> Result<()> {
+    storage.with_connection(|connection| connection.execute("DELETE FROM scheduler_jobs WHERE id = ?1", params![id]))?;
+
+    Ok(())
+}
@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_std::task;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Position, Scope, AST};
+
+use crate::config::ScriptConfig;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType, UserEvent};
+use crate::storage::{Storage, StorageScope};
+
+const HOOKS: [&str; 9] = ["message", "action", "join", "part", "quit", "nick", "kick", "dcc_progress", "dcc_finished"];
+
+// Scripts run in the blocking pool, one at a time, so a slow one doesn't hold up the connection
+pub struct ScriptPrivMsgEvent {
+    runner: Arc<ScriptRunner>,
+}
+
+struct ScriptRunner {
+    config: ScriptConfig,
+    engine: Engine,
+    context: Arc<Mutex<ScriptContext>>,
+    deadline: Arc<Mutex<Instant>>,
+    state: Mutex<ScriptState>,
+}
+
+struct Script {
+    name: String,
+    modified: Option<SystemTime>,
+    ast: AST,
+    commands: HashMap<String, String>,
+    hooks: HashMap<String, Vec<String>>,
+}
+
+// What a script run needs from the request, copied so it can outlive it
+struct ScriptRun {
+    nick: String,
+    user: String,
+    host: String,
+    source: String,
+    is_action: bool,
+    reason: String,
+    bot_nick: String,
+    server: String,
+    channels: Vec<String>,
+    settings: HashMap<String, HashMap<String, serde_yaml::Value>>,
+    hook: &'static str,
+    // Passed to the hooks: the message, the new nick, who was kicked or the file name
+    argument: String,
+    // Name and arguments
+    command: Option<(String, String)>,
+    transfer: Map,
+}
+
+#[derive(Default)]
+struct ScriptState {
+    scripts: HashMap<PathBuf, Script>,
+    last_scan: Option<Instant>,
+}
+
+// Data available to the bot API while a script is running
+#[derive(Default)]
+struct ScriptContext {
+    script: String,
+    nick: String,
+    user: String,
+    host: String,
+    source: String,
+    is_action: bool,
+    reason: String,
+    // The DCC SEND of the dcc_* hooks
+    transfer: Map,
+    bot_nick: String,
+    server: String,
+    channels: Vec<String>,
+    settings: HashMap<String, HashMap<String, serde_yaml::Value>>,
+    commands: HashMap<String, String>,
+    hooks: HashMap<String, Vec<String>>,
+    responses: Vec<PrivMsgResponse>,
+    storage: Option<Arc<Storage>>,
+    // Used when there's no storage, lost on restart
+    memory: HashMap<String, HashMap<String, Dynamic>>,
+}
+
+impl ScriptPrivMsgEvent {
+    pub fn new(config: &ScriptConfig, storage: Option<Arc<Storage>>) -> Self {
+        let context = Arc::new(Mutex::new(ScriptContext { storage, ..Default::default() }));
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let mut engine = Engine::new();
+
+        engine.set_max_operations(config.max_operations);
+        engine.set_max_call_levels(config.max_call_levels);
+        engine.set_max_string_size(config.max_string_size);
+        engine.set_max_array_size(config.max_array_size);
+        engine.set_max_map_size(config.max_map_size);
+
+        let progress_deadline = deadline.clone();
+
+        engine.on_progress(move |operations| {
+            // Checking the clock on every operation is too expensive
+            if operations % 1024 == 0 && Instant::now() > *progress_deadline.lock().unwrap() {
+                return Some(Dynamic::from("Script execution time exceeded"));
+            }
+
+            None
+        });
+
+        engine.on_print(|text| log::info!("[script] {}", text));
+
+        register_api(&mut engine, &context);
+
+        ScriptPrivMsgEvent {
+            runner: Arc::new(ScriptRunner {
+                config: config.clone(),
+                engine,
+                context,
+                deadline,
+                state: Mutex::new(ScriptState { ..Default::default() }),
+            }),
+        }
+    }
+
+    fn spawn(&self, request: &PrivMsgRequest, run: ScriptRun) {
+        let runner = self.runner.clone();
+
+        request.spawn(async move { task::spawn_blocking(move || runner.run(run)).await });
+    }
+}
+
+impl ScriptRun {
+    fn new(request: &PrivMsgRequest, hook: &'static str, argument: &str, reason: Option<&str>) -> Self {
+        ScriptRun {
+            nick: request.user.nick.clone(),
+            user: request.user.user.clone().unwrap_or_default(),
+            host: request.user.host.clone().unwrap_or_default(),
+            source: request.source.clone(),
+            is_action: request.is_action,
+            reason: reason.unwrap_or_default().to_string(),
+            bot_nick: request.server.user_data.nickname.clone(),
+            server: request.server.hostname.clone(),
+            channels: request.server.channels.iter().map(|channel| channel.name.clone()).collect(),
+            settings: request.channel.map(|channel| channel.settings.clone()).unwrap_or_default(),
+            hook,
+            argument: argument.to_string(),
+            command: None,
+            transfer: Map::new(),
+        }
+    }
+
+    fn dcc(request: &PrivMsgRequest, hook: &'static str, transfer: u64, file_name: &str, sent: u64, size: u64, error: &str) -> Self {
+        let mut run = ScriptRun::new(request, hook, file_name, None);
+
+        run.transfer.insert("id".into(), Dynamic::from(transfer as i64));
+        run.transfer.insert("file".into(), Dynamic::from(file_name.to_string()));
+        run.transfer.insert("sent".into(), Dynamic::from(sent as i64));
+        run.transfer.insert("size".into(), Dynamic::from(size as i64));
+        run.transfer.insert("error".into(), Dynamic::from(error.to_string()));
+
+        run
+    }
+}
+
+impl ScriptRunner {
+    fn run(&self, run: ScriptRun) -> Vec<PrivMsgResponse> {
+        let mut state = self.state.lock().unwrap();
+
+        self.reload_scripts(&mut state);
+
+        if state.scripts.is_empty() {
+            return vec![];
+        }
+
+        {
+            let mut context = self.context.lock().unwrap();
+
+            context.nick = run.nick;
+            context.user = run.user;
+            context.host = run.host;
+            context.source = run.source;
+            context.is_action = run.is_action;
+            context.reason = run.reason;
+            context.transfer = run.transfer;
+            context.bot_nick = run.bot_nick;
+            context.server = run.server;
+            context.channels = run.channels;
+            context.settings = run.settings;
+            context.responses.clear();
+        }
+
+        for script in state.scripts.values() {
+            if let Some(hooks) = script.hooks.get(run.hook) {
+                for hook in hooks {
+                    self.call_function(script, hook, &run.argument);
+                }
+            }
+
+            if let Some((name, arguments)) = &run.command {
+                if let Some(function) = script.commands.get(name) {
+                    self.call_function(script, function, arguments);
+                }
+            }
+        }
+
+        std::mem::take(&mut self.context.lock().unwrap().responses)
+    }
+
+    fn reload_scripts(&self, state: &mut ScriptState) {
+        if let Some(last_scan) = state.last_scan {
+            if last_scan.elapsed() < Duration::from_secs(self.config.reload_interval) {
+                return;
+            }
+        }
+
+        state.last_scan = Some(Instant::now());
+
+        let entries = match fs::read_dir(&self.config.directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                if !state.scripts.is_empty() {
+                    log::error!("Couldn't read script directory {}: {}", self.config.directory, e);
+
+                    state.scripts.clear();
+                }
+
+                return;
+            }
+        };
+
+        let mut found: Vec<PathBuf> = vec![];
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some("rhai") {
+                continue;
+            }
+
+            let modified = entry.metadata().and_then(|metadata| metadata.modified()).ok();
+
+            found.push(path.clone());
+
+            if let Some(script) = state.scripts.get(&path) {
+                if script.modified == modified {
+                    continue;
+                }
+            }
+
+            match self.load_script(&path, modified) {
+                Ok(script) => {
+                    log::info!("Loaded script {}", path.display());
+
+                    state.scripts.insert(path, script);
+                }
+                Err(e) => {
+                    log::error!("Couldn't load script {}: {}", path.display(), e);
+
+                    // Keep the previous version running, but don't retry until the file changes again
+                    let script = state.scripts.entry(path.clone()).or_insert_with(|| Script {
+                        name: script_name(&path),
+                        modified,
+                        ast: AST::empty(),
+                        commands: HashMap::new(),
+                        hooks: HashMap::new(),
+                    });
+
+                    script.modified = modified;
+                }
+            }
+        }
+
+        state.scripts.retain(|path, _| {
+            let exists = found.contains(path);
+
+            if !exists {
+                log::info!("Unloaded script {}", path.display());
+            }
+
+            exists
+        });
+    }
+
+    fn load_script(&self, path: &Path, modified: Option<SystemTime>) -> Result<Script, Box<EvalAltResult>> {
+        let name = script_name(path);
+        let ast = self.engine.compile_file(path.to_path_buf())?;
+
+        {
+            let mut context = self.context.lock().unwrap();
+
+            context.script = name.clone();
+            context.commands.clear();
+            context.hooks.clear();
+            context.responses.clear();
+        }
+
+        // Top level statements are where the script registers its commands and hooks
+        self.reset_deadline();
+        self.engine.run_ast_with_scope(&mut Scope::new(), &ast)?;
+
+        let mut context = self.context.lock().unwrap();
+
+        context.responses.clear();
+
+        Ok(Script {
+            name,
+            modified,
+            ast,
+            commands: context.commands.clone(),
+            hooks: context.hooks.clone(),
+        })
+    }
+
+    fn call_function(&self, script: &Script, function: &str, argument: &str) {
+        self.context.lock().unwrap().script = script.name.clone();
+        self.reset_deadline();
+
+        let options = CallFnOptions::new().eval_ast(false);
+
+        match self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &script.ast, function, (argument.to_string(),)) {
+            Ok(result) => {
+                // Returning a string from a handler is a shortcut for reply()
+                if let Some(message) = result.try_cast::<String>() {
+                    let mut context = self.context.lock().unwrap();
+                    let target = context.source.clone();
+
+                    context.responses.push(PrivMsgResponse {
+                        target,
+                        message,
+                        response_type: ResponseType::PrivMsg,
+                    });
+                }
+            }
+            Err(e) => log::error!("Error while executing function {} of script {}: {}", function, script.name, e),
+        }
+    }
+
+    fn reset_deadline(&self) {
+        *self.deadline.lock().unwrap() = Instant::now() + Duration::from_millis(self.config.max_execution_time);
+    }
+}
+
+impl PrivMsgEvent for ScriptPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        let hook = if request.is_action { "action" } else { "message" };
+        let mut run = ScriptRun::new(&request, hook, request.message, None);
+
+        run.command = request.message.strip_prefix(request.command_prefix).filter(|_| !request.is_action).map(|command| {
+            let name = command.split(' ').next().unwrap();
+
+            (name.to_string(), command[name.len()..].trim().to_string())
+        });
+
+        self.spawn(&request, run);
+
+        vec![]
+    }
+
+    fn on_event(&self, request: PrivMsgRequest, event: &UserEvent) -> Vec<PrivMsgResponse> {
+        let run = match event {
+            UserEvent::Join => ScriptRun::new(&request, "join", "", None),
+            UserEvent::Part { reason } => ScriptRun::new(&request, "part", "", *reason),
+            UserEvent::Quit { reason } => ScriptRun::new(&request, "quit", "", *reason),
+            UserEvent::Nick { new_nick } => ScriptRun::new(&request, "nick", new_nick, None),
+            UserEvent::Kick { nick, reason } => ScriptRun::new(&request, "kick", nick, *reason),
+            UserEvent::DccSendProgress { transfer, file_name, sent, size } => ScriptRun::dcc(&request, "dcc_progress", *transfer, file_name, *sent, *size, ""),
+            UserEvent::DccSendFinished { transfer, file_name, size, result } => match result {
+                Ok(sent) => ScriptRun::dcc(&request, "dcc_finished", *transfer, file_name, *sent, *size, ""),
+                Err(error) => ScriptRun::dcc(&request, "dcc_finished", *transfer, file_name, 0, *size, error),
+            },
+        };
+
+        self.spawn(&request, run);
+
+        vec![]
+    }
+}
+
+fn script_name(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn register_api(engine: &mut Engine, context: &Arc<Mutex<ScriptContext>>) {
+    let ctx = context.clone();
+    engine.register_fn("register_command", move |name: &str, function: &str| {
+        ctx.lock().unwrap().commands.insert(name.to_string(), function.to_string());
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("register_hook", move |event: &str, function: &str| -> Result<(), Box<EvalAltResult>> {
+        if !HOOKS.contains(&event) {
+            return Err(Box::new(EvalAltResult::ErrorRuntime(Dynamic::from(format!("Unknown hook: {}, use one of {}", event, HOOKS.join(", "))), Position::NONE)));
+        }
+
+        ctx.lock().unwrap().hooks.entry(event.to_string()).or_default().push(function.to_string());
+
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("reply", move |message: &str| {
+        let mut context = ctx.lock().unwrap();
+        let target = context.source.clone();
+
+        context.responses.push(PrivMsgResponse {
+            target,
+            message: message.to_string(),
+            response_type: ResponseType::PrivMsg,
+        });
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("notice", move |message: &str| {
+        let mut context = ctx.lock().unwrap();
+        let target = context.nick.clone();
+
+        context.responses.push(PrivMsgResponse {
+            target,
+            message: message.to_string(),
+            response_type: ResponseType::Notice,
+        });
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("action", move |message: &str| {
+        let mut context = ctx.lock().unwrap();
+        let target = context.source.clone();
+
+        context.responses.push(PrivMsgResponse {
+            target,
+            message: message.to_string(),
+            response_type: ResponseType::Action,
+        });
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("say", move |target: &str, message: &str| {
+        ctx.lock().unwrap().responses.push(PrivMsgResponse {
+            target: target.to_string(),
+            message: message.to_string(),
+            response_type: ResponseType::PrivMsg,
+        });
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("nick", move || ctx.lock().unwrap().nick.clone());
+
+    let ctx = context.clone();
+    engine.register_fn("user", move || ctx.lock().unwrap().user.clone());
+
+    let ctx = context.clone();
+    engine.register_fn("host", move || ctx.lock().unwrap().host.clone());
+
+    let ctx = context.clone();
+    engine.register_fn("channel", move || ctx.lock().unwrap().source.clone());
+
+    let ctx = context.clone();
+    engine.register_fn("is_channel", move || {
+        let context = ctx.lock().unwrap();
+
+        context.source != context.nick
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("is_action", move || ctx.lock().unwrap().is_action);
+
+    // Why someone left or was kicked
+    let ctx = context.clone();
+    engine.register_fn("reason", move || ctx.lock().unwrap().reason.clone());
+
+    // id, file, sent, size and error of the DCC SEND in the dcc_* hooks
+    let ctx = context.clone();
+    engine.register_fn("transfer", move || ctx.lock().unwrap().transfer.clone());
+
+    let ctx = context.clone();
+    engine.register_fn("channels", move || {
+        ctx.lock().unwrap().channels.iter().map(|channel| Dynamic::from(channel.clone())).collect::<Array>()
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("bot_nick", move || ctx.lock().unwrap().bot_nick.clone());
+
+    let ctx = context.clone();
+    engine.register_fn("server", move || ctx.lock().unwrap().server.clone());
+
+    // Channel settings, namespaced by script name
+    let ctx = context.clone();
+    engine.register_fn("setting", move |key: &str| {
+        let context = ctx.lock().unwrap();
+
+        match context.settings.get(&context.script).and_then(|settings| settings.get(key)) {
+            Some(serde_yaml::Value::String(value)) => Dynamic::from(value.clone()),
+            Some(serde_yaml::Value::Bool(value)) => Dynamic::from(*value),
+            Some(serde_yaml::Value::Number(value)) if value.is_i64() => Dynamic::from(value.as_i64().unwrap()),
+            Some(serde_yaml::Value::Number(value)) => Dynamic::from(value.as_f64().unwrap_or_default()),
+            _ => Dynamic::UNIT,
+        }
+    });
+
+    // Key-value storage, namespaced by script name and scoped to the server. Values are stored as JSON
+    let ctx = context.clone();
+    engine.register_fn("storage_get", move |key: &str| {
+        let context = ctx.lock().unwrap();
+
+        if let Some(scope) = context.scope() {
+            return match scope.get_json::<Dynamic>(key) {
+                Ok(value) => value.unwrap_or(Dynamic::UNIT),
+                Err(e) => {
+                    log::error!("Couldn't read {} of script {}: {}", key, context.script, e);
+
+                    Dynamic::UNIT
+                }
+            };
+        }
+
+        context.memory.get(&context.script)
+            .and_then(|storage| storage.get(key))
+            .cloned()
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("storage_set", move |key: &str, value: Dynamic| {
+        let mut context = ctx.lock().unwrap();
+
+        if let Some(scope) = context.scope() {
+            if let Err(e) = scope.set_json(key, &value) {
+                log::error!("Couldn't store {} of script {}: {}", key, context.script, e);
+            }
+
+            return;
+        }
+
+        let script = context.script.clone();
+
+        context.memory.entry(script).or_default().insert(key.to_string(), value);
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("storage_remove", move |key: &str| {
+        let mut context = ctx.lock().unwrap();
+
+        if let Some(scope) = context.scope() {
+            if let Err(e) = scope.remove(key) {
+                log::error!("Couldn't remove {} of script {}: {}", key, context.script, e);
+            }
+
+            return;
+        }
+
+        let script = context.script.clone();
+
+        if let Some(storage) = context.memory.get_mut(&script) {
+            storage.remove(key);
+        }
+    });
+
+    // Keys starting with the prefix, sorted
+    let ctx = context.clone();
+    engine.register_fn("storage_keys", move |prefix: &str| -> Array {
+        let context = ctx.lock().unwrap();
+
+        if let Some(scope) = context.scope() {
+            return match scope.keys(prefix) {
+                Ok(keys) => keys.into_iter().map(Dynamic::from).collect(),
+                Err(e) => {
+                    log::error!("Couldn't list keys of script {}: {}", context.script, e);
+
+                    Array::new()
+                }
+            };
+        }
+
+        let mut keys: Vec<&String> = context.memory.get(&context.script)
+            .map(|storage| storage.keys().filter(|key| key.starts_with(prefix)).collect())
+            .unwrap_or_default();
+
+        keys.sort();
+
+        keys.into_iter().map(|key| Dynamic::from(key.clone())).collect()
+    });
+}
+
+impl ScriptContext {
+    fn scope(&self) -> Option<StorageScope<'_>> {
+        Some(self.storage.as_ref()?.scope(&format!("script.{}", self.script), &self.server, None))
+    }
+}
@@ -0,0 +1,254 @@
+use rusqlite::types::Value;
+
+use crate::irc_ext::IrcExt;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType, UserEvent};
+use crate::storage::{self, Row};
+
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE seen_users (
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        nick TEXT NOT NULL,
+        display_nick TEXT NOT NULL,
+        action TEXT NOT NULL,
+        location TEXT NOT NULL,
+        target TEXT NOT NULL,
+        text TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        PRIMARY KEY (server, channel, nick)
+    );
+"];
+
+pub struct SeenPrivMsgEvent {}
+
+impl SeenPrivMsgEvent {
+    // Only the last thing each nick did is kept, nicks are the same as the server compares them
+    fn record(&self, request: &PrivMsgRequest, nick: &str, action: &str, target: &str, text: Option<&str>) {
+        let storage = match request.server_storage(self.name()) {
+            Some(storage) => storage,
+            None => return,
+        };
+
+        let result = storage.replace("users", &[
+            ("nick", Value::from(request.irc_state.fold(nick))),
+            ("display_nick", Value::from(nick.to_string())),
+            ("action", Value::from(action.to_string())),
+            ("location", Value::from(request.source.clone())),
+            ("target", Value::from(target.to_string())),
+            ("text", Value::from(text.unwrap_or_default().to_string())),
+            ("time", Value::from(storage::now())),
+        ]);
+
+        if let Err(e) = result {
+            log::error!("Couldn't record {} as seen: {}", nick, e);
+        }
+    }
+
+    fn seen(&self, request: &PrivMsgRequest, nick: &str) -> String {
+        if request.irc_state.fold(nick) == request.irc_state.fold(&request.user.nick) {
+            return "That's you!".to_string();
+        }
+
+        let storage = match request.server_storage(self.name()) {
+            Some(storage) => storage,
+            None => return "Storage isn't available".to_string(),
+        };
+
+        match storage.select("users", &[("nick", Value::from(request.irc_state.fold(nick)))], "", 1) {
+            Ok(rows) => match rows.first() {
+                Some(row) => {
+                    // Like log_search, what happened in a channel is only told to who is in it, so secret and private channels don't leak
+                    let location = request.irc_state.fold(&storage::text(row, "location"));
+                    let shared = request.irc_state.user(&request.user.nick).is_some_and(|user| user.channels.contains(&location));
+
+                    describe(row, shared)
+                }
+                None => format!("I haven't seen {}", nick),
+            },
+            Err(e) => {
+                log::error!("Couldn't look up {} in seen: {}", nick, e);
+
+                format!("Couldn't look up {}", nick)
+            }
+        }
+    }
+}
+
+// bob was last seen 2h 5m ago leaving #channel (bye), the channel and text are left out when it isn't shared with who asked
+fn describe(row: &Row, shared: bool) -> String {
+    let nick = storage::text(row, "display_nick");
+    let location = storage::text(row, "location");
+    let target = storage::text(row, "target");
+    let text = storage::text(row, "text");
+    let reason = if text.is_empty() { "".to_string() } else { format!(" ({})", text) };
+    let what = match (storage::text(row, "action").as_str(), shared) {
+        ("message", true) => format!("in {} saying: {}", location, text),
+        ("action", true) => format!("in {}: * {} {}", location, nick, text),
+        ("message", false) | ("action", false) => "talking in a channel".to_string(),
+        ("join", true) => format!("joining {}", location),
+        ("join", false) => "joining a channel".to_string(),
+        ("part", true) => format!("leaving {}{}", location, reason),
+        ("part", false) => "leaving a channel".to_string(),
+        ("quit", true) => format!("quitting{}", reason),
+        ("quit", false) => "quitting".to_string(),
+        ("nick", _) => format!("changing nick to {}", target),
+        ("nick_from", _) => format!("changing nick from {}", target),
+        ("kicked", true) => format!("being kicked from {} by {}{}", location, target, reason),
+        ("kicked", false) => "being kicked from a channel".to_string(),
+        (action, _) => action.to_string(),
+    };
+
+    format!("{} was last seen {} ago {}", nick, format_duration(storage::now() - storage::integer(row, "time")), what)
+}
+
+// The two largest units, like 3d 4h or 5m 10s
+pub fn format_duration(seconds: i64) -> String {
+    let units = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+    let mut remaining = seconds.max(0);
+    let mut parts: Vec<String> = vec![];
+
+    for (unit, size) in units.iter() {
+        if remaining >= *size || (parts.is_empty() && *unit == "s") {
+            parts.push(format!("{}{}", remaining / size, unit));
+
+            remaining %= size;
+        } else if !parts.is_empty() {
+            break;
+        }
+
+        if parts.len() == 2 {
+            break;
+        }
+    }
+
+    parts.join(" ")
+}
+
+impl PrivMsgEvent for SeenPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "seen"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        // Private messages aren't anyone else's business
+        if request.source.is_channel_name() {
+            let action = if request.is_action { "action" } else { "message" };
+
+            self.record(&request, &request.user.nick, action, "", Some(request.message));
+        }
+
+        if let Some(nick) = request.command("seen") {
+            let message = match nick.split_whitespace().next() {
+                Some(nick) => self.seen(&request, nick),
+                None => format!("Usage: {}seen <nick>", request.command_prefix),
+            };
+
+            return vec![PrivMsgResponse {
+                target: request.source.clone(),
+                message,
+                response_type: ResponseType::PrivMsg,
+            }];
+        }
+
+        vec![]
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+
+    fn on_event(&self, request: PrivMsgRequest, event: &UserEvent) -> Vec<PrivMsgResponse> {
+        let nick = &request.user.nick;
+
+        match event {
+            UserEvent::Join => self.record(&request, nick, "join", "", None),
+            UserEvent::Part { reason } => self.record(&request, nick, "part", "", *reason),
+            UserEvent::Quit { reason } => self.record(&request, nick, "quit", "", *reason),
+            UserEvent::Nick { new_nick } => {
+                self.record(&request, nick, "nick", new_nick, None);
+                self.record(&request, new_nick, "nick_from", nick, None);
+            }
+            UserEvent::Kick { nick: kicked, reason } => self.record(&request, kicked, "kicked", nick, *reason),
+            UserEvent::DccSendProgress { .. } | UserEvent::DccSendFinished { .. } => {}
+        }
+
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use simple_irc::Prefix;
+
+    use super::*;
+    use crate::privmsg::FakeRequest;
+
+    fn say(fake: &mut FakeRequest, source: &str, nick: &str, message: &str) -> Vec<String> {
+        let plugin = SeenPrivMsgEvent {};
+
+        fake.source = source.to_string();
+        fake.user = Prefix::new_with_all(nick, Some("user"), Some("host.example"));
+        fake.message = message.to_string();
+
+        plugin.execute(fake.request()).into_iter().map(|response| response.message).collect()
+    }
+
+    fn join(fake: &mut FakeRequest, nick: &str, channel: &str) {
+        let channel = fake.irc_state.fold(channel);
+
+        fake.irc_state.update_user(&Prefix::new(nick)).channels.insert(channel);
+    }
+
+    fn row(action: &str, location: &str, target: &str, text: &str) -> Row {
+        [("display_nick", "Bob"), ("action", action), ("location", location), ("target", target), ("text", text)].iter()
+            .map(|(column, value)| (column.to_string(), Value::from(value.to_string())))
+            .chain([("time".to_string(), Value::from(storage::now() - 3700))])
+            .collect()
+    }
+
+    #[test]
+    fn channels_are_only_told_to_who_is_in_them() {
+        let mut fake = FakeRequest::new("#secret", "bob", "").with_storage("seen", MIGRATIONS);
+
+        say(&mut fake, "#Secret[ops]", "Bob", "the password is hunter2");
+
+        assert!(say(&mut fake, "alice", "alice", ".seen bob")[0].ends_with(" ago talking in a channel"));
+
+        // Channels are compared with the server casemapping
+        join(&mut fake, "alice", "#secret{OPS}");
+
+        assert!(say(&mut fake, "alice", "Alice", ".seen BOB")[0].ends_with(" ago in #Secret[ops] saying: the password is hunter2"));
+        assert_eq!(say(&mut fake, "#secret", "bob", ".seen Bob"), vec!["That's you!"]);
+        assert_eq!(say(&mut fake, "#secret", "alice", ".seen carol"), vec!["I haven't seen carol"]);
+    }
+
+    #[test]
+    fn private_messages_are_not_recorded() {
+        let mut fake = FakeRequest::new("bot", "bob", "").with_storage("seen", MIGRATIONS);
+
+        say(&mut fake, "bot", "bob", "my password is hunter2");
+
+        assert_eq!(say(&mut fake, "#chan", "alice", ".seen bob"), vec!["I haven't seen bob"]);
+    }
+
+    #[test]
+    fn unshared_channels_and_texts_are_left_out() {
+        assert_eq!(describe(&row("part", "#secret", "", "bye"), true), "Bob was last seen 1h 1m ago leaving #secret (bye)");
+        assert_eq!(describe(&row("part", "#secret", "", "bye"), false), "Bob was last seen 1h 1m ago leaving a channel");
+        assert_eq!(describe(&row("action", "#secret", "", "waves"), true), "Bob was last seen 1h 1m ago in #secret: * Bob waves");
+        assert_eq!(describe(&row("action", "#secret", "", "waves"), false), "Bob was last seen 1h 1m ago talking in a channel");
+        assert_eq!(describe(&row("kicked", "#secret", "op", "spam"), false), "Bob was last seen 1h 1m ago being kicked from a channel");
+        assert_eq!(describe(&row("quit", "#secret", "", "Ping timeout"), false), "Bob was last seen 1h 1m ago quitting");
+        // Nick changes are seen by every channel, so they're always told
+        assert_eq!(describe(&row("nick", "#secret", "Robert", ""), false), "Bob was last seen 1h 1m ago changing nick to Robert");
+    }
+
+    #[test]
+    fn durations_keep_the_two_largest_units() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(-5), "0s");
+        assert_eq!(format_duration(3700), "1h 1m");
+        assert_eq!(format_duration(86400 + 5), "1d");
+        assert_eq!(format_duration(3 * 86400 + 4 * 3600 + 59), "3d 4h");
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::StorageConfig;
+
+// Column name -> value
+pub type Row = HashMap<String, Value>;
+
+// Shared by every server and plugin
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+// Storage of a plugin for a server and channel, an empty channel is shared by the whole server.
+// Plugin tables are named <plugin>_<table> and have server and channel columns, which are filled and filtered here
+pub struct StorageScope<'a> {
+    storage: &'a Storage,
+    // Set inside a transaction, which holds the connection
+    connection: Option<&'a Connection>,
+    plugin: String,
+    server: String,
+    channel: String,
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig) -> Result<Arc<Self>> {
+        let connection = Connection::open(&config.path)?;
+
+        // Lets the CLI read it while the bot is running
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS migrations (
+                plugin TEXT PRIMARY KEY,
+                version INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS kv (
+                plugin TEXT NOT NULL,
+                server TEXT NOT NULL,
+                channel TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                updated INTEGER NOT NULL,
+                PRIMARY KEY (plugin, server, channel, key)
+            );
+        ")?;
+
+        Ok(Arc::new(Storage { connection: Mutex::new(connection) }))
+    }
+
+    // Each migration runs once, in order, so new ones have to be added to the end
+    pub fn migrate(&self, plugin: &str, migrations: &[&str]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let version: i64 = connection.query_row("SELECT version FROM migrations WHERE plugin = ?1", [plugin], |row| row.get(0)).optional()?.unwrap_or(0);
+
+        if version as usize > migrations.len() {
+            return Err(anyhow!("Database schema of {} is newer than this version of the bot ({} > {})", plugin, version, migrations.len()));
+        }
+
+        for (index, migration) in migrations.iter().enumerate().skip(version as usize) {
+            let transaction = connection.transaction()?;
+
+            transaction.execute_batch(migration)?;
+            transaction.execute("INSERT INTO migrations (plugin, version) VALUES (?1, ?2) ON CONFLICT (plugin) DO UPDATE SET version = excluded.version", params![plugin, index as i64 + 1])?;
+            transaction.commit()?;
+
+            log::info!("Applied migration {} of {}", index + 1, plugin);
+        }
+
+        Ok(())
+    }
+
+    pub fn scope(&self, plugin: &str, server: &str, channel: Option<&str>) -> StorageScope<'_> {
+        StorageScope {
+            storage: self,
+            connection: None,
+            plugin: plugin.to_string(),
+            server: server.to_string(),
+            channel: channel.unwrap_or_default().to_string(),
+        }
+    }
+
+    // For what the scoped API can't do, like joins or full text search
+    pub fn with_connection<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Result<T> {
+        Ok(f(&self.connection.lock().unwrap())?)
+    }
+}
+
+impl StorageScope<'_> {
+    // Everything done with the scope given to f is rolled back if it fails
+    pub fn transaction<T>(&self, f: impl FnOnce(&StorageScope) -> Result<T>) -> Result<T> {
+        if self.connection.is_some() {
+            return f(self);
+        }
+
+        let mut connection = self.storage.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let result = f(&StorageScope {
+            storage: self.storage,
+            connection: Some(&transaction),
+            plugin: self.plugin.clone(),
+            server: self.server.clone(),
+            channel: self.channel.clone(),
+        })?;
+
+        transaction.commit()?;
+
+        Ok(result)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.with_connection(|connection| {
+            connection.query_row("SELECT value FROM kv WHERE plugin = ?1 AND server = ?2 AND channel = ?3 AND key = ?4",
+                                 params![self.plugin, self.server, self.channel, key],
+                                 |row| row.get(0)).optional()
+        })
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute("INSERT INTO kv (plugin, server, channel, key, value, updated) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                                ON CONFLICT (plugin, server, channel, key) DO UPDATE SET value = excluded.value, updated = excluded.updated",
+                               params![self.plugin, self.server, self.channel, key, value, now()])
+        })?;
+
+        Ok(())
+    }
+
+    // Returns whether the key existed
+    pub fn remove(&self, key: &str) -> Result<bool> {
+        let removed = self.with_connection(|connection| {
+            connection.execute("DELETE FROM kv WHERE plugin = ?1 AND server = ?2 AND channel = ?3 AND key = ?4",
+                               params![self.plugin, self.server, self.channel, key])
+        })?;
+
+        Ok(removed > 0)
+    }
+
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare("SELECT key FROM kv WHERE plugin = ?1 AND server = ?2 AND channel = ?3 AND substr(key, 1, length(?4)) = ?4 ORDER BY key")?;
+            let keys = statement.query_map(params![self.plugin, self.server, self.channel, prefix], |row| row.get(0))?;
+
+            keys.collect()
+        })
+    }
+
+    pub fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_json<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        self.set(key, &serde_json::to_string(value)?)
+    }
+
+    // Returns the rowid of the new row
+    pub fn insert(&self, table: &str, row: &[(&str, Value)]) -> Result<i64> {
+        self.write("INSERT", table, row)
+    }
+
+    // Like insert, replacing the row with the same primary key
+    pub fn replace(&self, table: &str, row: &[(&str, Value)]) -> Result<i64> {
+        self.write("REPLACE", table, row)
+    }
+
+    // Rows matching every filter column, order is a column optionally followed by ASC or DESC, a limit of 0 means all rows
+    pub fn select(&self, table: &str, filter: &[(&str, Value)], order: &str, limit: usize) -> Result<Vec<Row>> {
+        let table = self.table(table)?;
+        let (condition, values) = self.condition(filter)?;
+        let mut sql = format!("SELECT * FROM {} WHERE {}", table, condition);
+
+        if !order.is_empty() {
+            sql = format!("{} ORDER BY {}", sql, order_by(order)?);
+        }
+
+        if limit > 0 {
+            sql = format!("{} LIMIT {}", sql, limit);
+        }
+
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(&sql)?;
+            let columns: Vec<String> = statement.column_names().iter().map(|column| column.to_string()).collect();
+            let rows = statement.query_map(params_from_iter(values), |row| {
+                columns.iter().enumerate().map(|(index, column)| Ok((column.clone(), row.get::<_, Value>(index)?))).collect()
+            })?;
+
+            rows.collect()
+        })
+    }
+
+    pub fn count(&self, table: &str, filter: &[(&str, Value)]) -> Result<i64> {
+        let table = self.table(table)?;
+        let (condition, values) = self.condition(filter)?;
+        let sql = format!("SELECT count(*) FROM {} WHERE {}", table, condition);
+
+        self.with_connection(|connection| connection.query_row(&sql, params_from_iter(values), |row| row.get(0)))
+    }
+
+    // Returns how many rows were changed
+    pub fn update(&self, table: &str, filter: &[(&str, Value)], row: &[(&str, Value)]) -> Result<usize> {
+        let table = self.table(table)?;
+        let mut assignments: Vec<String> = vec![];
+        let mut values: Vec<Value> = vec![];
+
+        for (column, value) in row {
+            assignments.push(format!("{} = ?", identifier(column)?));
+            values.push(value.clone());
+        }
+
+        if assignments.is_empty() {
+            return Ok(0);
+        }
+
+        let (condition, filter_values) = self.condition(filter)?;
+        let sql = format!("UPDATE {} SET {} WHERE {}", table, assignments.join(", "), condition);
+
+        values.extend(filter_values);
+
+        self.with_connection(|connection| connection.execute(&sql, params_from_iter(values)))
+    }
+
+    // Returns how many rows were deleted
+    pub fn delete(&self, table: &str, filter: &[(&str, Value)]) -> Result<usize> {
+        let table = self.table(table)?;
+        let (condition, values) = self.condition(filter)?;
+        let sql = format!("DELETE FROM {} WHERE {}", table, condition);
+
+        self.with_connection(|connection| connection.execute(&sql, params_from_iter(values)))
+    }
+
+    fn write(&self, statement: &str, table: &str, row: &[(&str, Value)]) -> Result<i64> {
+        let table = self.table(table)?;
+        let mut columns = vec!["server", "channel"];
+        let mut values = vec![Value::from(self.server.clone()), Value::from(self.channel.clone())];
+
+        for (column, value) in row {
+            columns.push(identifier(column)?);
+            values.push(value.clone());
+        }
+
+        let placeholders = vec!["?"; columns.len()].join(", ");
+        let sql = format!("{} INTO {} ({}) VALUES ({})", statement, table, columns.join(", "), placeholders);
+
+        self.with_connection(|connection| {
+            connection.execute(&sql, params_from_iter(values))?;
+
+            Ok(connection.last_insert_rowid())
+        })
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Result<T> {
+        match self.connection {
+            Some(connection) => Ok(f(connection)?),
+            None => self.storage.with_connection(f),
+        }
+    }
+
+    fn table(&self, table: &str) -> Result<String> {
+        Ok(format!("{}_{}", identifier(&self.plugin)?, identifier(table)?))
+    }
+
+    fn condition(&self, filter: &[(&str, Value)]) -> Result<(String, Vec<Value>)> {
+        let mut conditions = vec!["server = ?".to_string(), "channel = ?".to_string()];
+        let mut values = vec![Value::from(self.server.clone()), Value::from(self.channel.clone())];
+
+        for (column, value) in filter {
+            conditions.push(format!("{} = ?", identifier(column)?));
+            values.push(value.clone());
+        }
+
+        Ok((conditions.join(" AND "), values))
+    }
+}
+
+// Table and column names can't be bound as parameters
+fn identifier(name: &str) -> Result<&str> {
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+        return Err(anyhow!("Invalid table or column name: {}", name));
+    }
+
+    Ok(name)
+}
+
+fn order_by(order: &str) -> Result<String> {
+    let mut parts = order.split_whitespace();
+    let column = identifier(parts.next().unwrap_or_default())?;
+
+    match (parts.next().map(|direction| direction.to_uppercase()), parts.next()) {
+        (None, None) => Ok(column.to_string()),
+        (Some(direction), None) if direction == "ASC" || direction == "DESC" => Ok(format!("{} {}", column, direction)),
+        _ => Err(anyhow!("Invalid order: {}", order)),
+    }
+}
+
+// Text column of a row, empty when it's missing or NULL
+pub fn text(row: &Row, column: &str) -> String {
+    match row.get(column) {
+        Some(Value::Text(text)) => text.clone(),
+        Some(Value::Integer(integer)) => integer.to_string(),
+        _ => "".to_string(),
+    }
+}
+
+// Integer column of a row, 0 when it's missing or NULL
+pub fn integer(row: &Row, column: &str) -> i64 {
+    match row.get(column) {
+        Some(Value::Integer(integer)) => *integer,
+        _ => 0,
+    }
+}
+
+// Seconds since the epoch, how times are stored
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() as i64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Arc<Storage> {
+        let storage = Storage::open(&StorageConfig { path: ":memory:".to_string() }).unwrap();
+
+        storage.migrate("test", &["CREATE TABLE test_notes (server TEXT NOT NULL, channel TEXT NOT NULL, nick TEXT NOT NULL, text TEXT NOT NULL)"]).unwrap();
+
+        storage
+    }
+
+    fn note(nick: &str, text: &str) -> Vec<(&'static str, Value)> {
+        vec![("nick", Value::from(nick.to_string())), ("text", Value::from(text.to_string()))]
+    }
+
+    #[test]
+    fn identifiers_are_validated() {
+        assert!(identifier("notes").is_ok());
+        assert!(identifier("_added_by2").is_ok());
+        assert!(identifier("").is_err());
+        assert!(identifier("2notes").is_err());
+        assert!(identifier("notes; DROP TABLE kv").is_err());
+        assert!(identifier("nick\"").is_err());
+
+        assert_eq!(order_by("nick").unwrap(), "nick");
+        assert_eq!(order_by("nick desc").unwrap(), "nick DESC");
+        assert!(order_by("nick sideways").is_err());
+        assert!(order_by("nick ASC, text").is_err());
+        assert!(order_by("").is_err());
+    }
+
+    #[test]
+    fn invalid_names_are_rejected_before_any_sql() {
+        let storage = storage();
+        let scope = storage.scope("test", "irc.example.net", Some("#rust"));
+
+        assert!(scope.insert("notes; DELETE FROM kv", &note("alice", "hi")).is_err());
+        assert!(scope.insert("notes", &[("text) VALUES ('x'); --", Value::from("hi".to_string()))]).is_err());
+        assert!(scope.select("notes", &[("1 = 1 OR nick", Value::from("alice".to_string()))], "", 0).is_err());
+        assert!(scope.select("notes", &[], "nick; DROP TABLE test_notes", 0).is_err());
+        assert!(storage.scope("te-st", "irc.example.net", None).count("notes", &[]).is_err());
+        assert_eq!(scope.count("notes", &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn rows_are_scoped_to_server_and_channel() {
+        let storage = storage();
+        let rust = storage.scope("test", "irc.example.net", Some("#rust"));
+        let other_channel = storage.scope("test", "irc.example.net", Some("#other"));
+        let other_server = storage.scope("test", "irc.example.org", Some("#rust"));
+        let server = storage.scope("test", "irc.example.net", None);
+
+        rust.insert("notes", &note("alice", "in #rust")).unwrap();
+        other_channel.insert("notes", &note("alice", "in #other")).unwrap();
+        other_server.insert("notes", &note("alice", "on another server")).unwrap();
+        server.insert("notes", &note("alice", "for the server")).unwrap();
+
+        let rows = rust.select("notes", &[("nick", Value::from("alice".to_string()))], "", 0).unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(text(&rows[0], "text"), "in #rust");
+        assert_eq!(text(&rows[0], "channel"), "#rust");
+        assert_eq!(text(&server.select("notes", &[], "", 0).unwrap()[0], "text"), "for the server");
+
+        assert_eq!(rust.update("notes", &[], &[("text", Value::from("changed".to_string()))]).unwrap(), 1);
+        assert_eq!(rust.delete("notes", &[]).unwrap(), 1);
+        assert_eq!(rust.count("notes", &[]).unwrap(), 0);
+        assert_eq!(other_channel.count("notes", &[]).unwrap(), 1);
+        assert_eq!(other_server.count("notes", &[]).unwrap(), 1);
+        assert_eq!(server.count("notes", &[]).unwrap(), 1);
+    }
+
+    #[test]
+    fn keys_are_scoped_to_plugin_server_and_channel() {
+        let storage = storage();
+        let scope = storage.scope("test", "irc.example.net", Some("#rust"));
+        let other_plugin = storage.scope("other", "irc.example.net", Some("#rust"));
+
+        scope.set("greeting:alice", "hi").unwrap();
+        scope.set("greeting:bob", "hello").unwrap();
+        scope.set("greeting:alice", "hey").unwrap();
+        other_plugin.set("greeting:carol", "yo").unwrap();
+
+        assert_eq!(scope.get("greeting:alice").unwrap().as_deref(), Some("hey"));
+        assert_eq!(other_plugin.get("greeting:alice").unwrap(), None);
+        assert_eq!(storage.scope("test", "irc.example.net", None).get("greeting:alice").unwrap(), None);
+        assert_eq!(scope.keys("greeting:").unwrap(), vec!["greeting:alice", "greeting:bob"]);
+        // The prefix is compared as text, not as a LIKE pattern
+        assert_eq!(scope.keys("greeting_").unwrap(), Vec::<String>::new());
+
+        assert!(scope.remove("greeting:bob").unwrap());
+        assert!(!scope.remove("greeting:bob").unwrap());
+        assert_eq!(other_plugin.keys("").unwrap(), vec!["greeting:carol"]);
+    }
+
+    #[test]
+    fn failed_transactions_are_rolled_back() {
+        let storage = storage();
+        let scope = storage.scope("test", "irc.example.net", Some("#rust"));
+
+        let result: Result<()> = scope.transaction(|scope| {
+            scope.insert("notes", &note("alice", "one"))?;
+            scope.transaction(|scope| scope.insert("notes", &note("alice", "two")))?;
+            scope.insert("notes; --", &note("alice", "three"))?;
+
+            Ok(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(scope.count("notes", &[]).unwrap(), 0);
+
+        assert_eq!(scope.transaction(|scope| scope.insert("notes", &note("alice", "one"))).unwrap(), 1);
+        assert_eq!(scope.count("notes", &[]).unwrap(), 1);
+    }
+
+    #[test]
+    fn migrations_run_once_and_in_order() {
+        let storage = storage();
+        let migrations = ["CREATE TABLE test_notes (server TEXT NOT NULL, channel TEXT NOT NULL, nick TEXT NOT NULL, text TEXT NOT NULL)",
+                          "ALTER TABLE test_notes ADD COLUMN added INTEGER NOT NULL DEFAULT 0"];
+
+        storage.migrate("test", &migrations).unwrap();
+        storage.migrate("test", &migrations).unwrap();
+
+        let scope = storage.scope("test", "irc.example.net", None);
+
+        scope.insert("notes", &[("nick", Value::from("alice".to_string())), ("text", Value::from("hi".to_string())), ("added", Value::from(5))]).unwrap();
+
+        assert_eq!(integer(&scope.select("notes", &[], "", 1).unwrap()[0], "added"), 5);
+        // A database from a newer version of the bot isn't touched
+        assert!(storage.migrate("test", &migrations[..1]).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use rusqlite::types::Value;
+
+use crate::config::{TellConfig, TellDelivery};
+use crate::irc_ext::IrcExt;
+use crate::privmsg::{PrivMsgEvent, PrivMsgRequest, PrivMsgResponse, ResponseType, UserEvent};
+use crate::seen::format_duration;
+use crate::storage::{self, Row, StorageScope};
+
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE tell_messages (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        nick TEXT NOT NULL,
+        display_nick TEXT NOT NULL,
+        account TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        display_sender TEXT NOT NULL,
+        location TEXT NOT NULL,
+        message TEXT NOT NULL,
+        time INTEGER NOT NULL
+    );
+    CREATE INDEX tell_messages_nick ON tell_messages (server, channel, nick);
+    CREATE INDEX tell_messages_account ON tell_messages (server, channel, account);
+"];
+// Pending messages shown by .tell list
+const MAX_LISTED: usize = 5;
+
+pub struct TellPrivMsgEvent {
+    pub config: TellConfig,
+}
+
+impl TellPrivMsgEvent {
+    fn tell(&self, request: &PrivMsgRequest, storage: &StorageScope, nick: &str, message: &str) -> Option<PrivMsgResponse> {
+        let folded = request.irc_state.fold(nick);
+
+        if folded == request.irc_state.fold(&request.user.nick) {
+            return Some(reply(request, "You can tell yourself that".to_string()));
+        }
+
+        if folded == request.irc_state.fold(&request.server.user_data.nickname) {
+            return Some(reply(request, "I'm right here".to_string()));
+        }
+
+        // The account of who is using the nick now, messages to nicks nobody is using go to whoever uses it next
+        let account = if self.config.use_account {
+            let user = request.irc_state.user(nick);
+
+            if user.is_none_or(|user| user.account.is_none()) && !request.irc_state.is_whois_completed(nick) {
+                return Some(PrivMsgResponse {
+                    target: nick.to_string(),
+                    message: "".to_string(),
+                    response_type: ResponseType::Whois,
+                });
+            }
+
+            user.and_then(|user| user.account.as_deref()).map(|account| request.irc_state.fold(account)).unwrap_or_default()
+        } else {
+            "".to_string()
+        };
+
+        let waiting = if account.is_empty() {
+            storage.count("messages", &[("nick", Value::from(folded.clone()))])
+        } else {
+            storage.count("messages", &[("account", Value::from(account.clone()))])
+        };
+
+        match waiting {
+            Ok(waiting) if waiting as usize >= self.config.max_messages => {
+                return Some(reply(request, format!("{} already has {} messages waiting", nick, waiting)));
+            }
+            Err(e) => return Some(storage_error(request, e)),
+            _ => {}
+        }
+
+        let result = storage.insert("messages", &[
+            ("nick", Value::from(folded)),
+            ("display_nick", Value::from(nick.to_string())),
+            ("account", Value::from(account)),
+            ("sender", Value::from(request.irc_state.fold(&request.user.nick))),
+            ("display_sender", Value::from(request.user.nick.clone())),
+            ("location", Value::from(if request.source.is_channel_name() { request.source.clone() } else { "".to_string() })),
+            ("message", Value::from(message.to_string())),
+            ("time", Value::from(storage::now())),
+        ]);
+
+        match result {
+            Ok(_) => Some(reply(request, format!("I'll tell {} when they're around", nick))),
+            Err(e) => Some(storage_error(request, e)),
+        }
+    }
+
+    // Messages the user sent that weren't delivered yet
+    fn list(&self, request: &PrivMsgRequest, storage: &StorageScope) -> PrivMsgResponse {
+        let messages = match storage.select("messages", &[("sender", Value::from(request.irc_state.fold(&request.user.nick)))], "id", 0) {
+            Ok(messages) => messages,
+            Err(e) => return storage_error(request, e),
+        };
+
+        if messages.is_empty() {
+            return reply(request, "You don't have any messages waiting to be delivered".to_string());
+        }
+
+        let mut listed: Vec<String> = messages.iter().take(MAX_LISTED).map(|message| {
+            format!("#{} to {} ({} ago): {}",
+                    storage::integer(message, "id"),
+                    storage::text(message, "display_nick"),
+                    format_duration(storage::now() - storage::integer(message, "time")),
+                    storage::text(message, "message"))
+        }).collect();
+
+        if messages.len() > MAX_LISTED {
+            listed.push(format!("+{} more", messages.len() - MAX_LISTED));
+        }
+
+        reply(request, listed.join(" / "))
+    }
+
+    // By the id shown in .tell list, or every message to a nick
+    fn cancel(&self, request: &PrivMsgRequest, storage: &StorageScope, target: &str) -> PrivMsgResponse {
+        let sender = Value::from(request.irc_state.fold(&request.user.nick));
+        let result = match target.trim_start_matches('#').parse::<i64>() {
+            Ok(id) => storage.delete("messages", &[("sender", sender), ("id", Value::from(id))]),
+            Err(_) => storage.delete("messages", &[("sender", sender), ("nick", Value::from(request.irc_state.fold(target)))]),
+        };
+
+        match result {
+            Ok(0) => reply(request, format!("You don't have any messages waiting for {}", target)),
+            Ok(1) => reply(request, "Message cancelled".to_string()),
+            Ok(cancelled) => reply(request, format!("{} messages cancelled", cancelled)),
+            Err(e) => storage_error(request, e),
+        }
+    }
+
+    // Everything waiting for the user, removed once it's sent
+    fn deliver(&self, request: &PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        let storage = match request.server_storage(self.name()) {
+            Some(storage) => storage,
+            None => return vec![],
+        };
+
+        let account = request.irc_state.user(&request.user.nick).and_then(|user| user.account.as_deref()).map(|account| request.irc_state.fold(account));
+        let mut messages: Vec<Row> = vec![];
+
+        // Messages for an account are only given to who is logged in to it
+        let result = storage.select("messages", &[("nick", Value::from(request.irc_state.fold(&request.user.nick))), ("account", Value::from("".to_string()))], "", 0)
+            .and_then(|by_nick| {
+                messages.extend(by_nick);
+
+                match &account {
+                    Some(account) if self.config.use_account => storage.select("messages", &[("account", Value::from(account.clone()))], "", 0),
+                    _ => Ok(vec![]),
+                }
+            });
+
+        match result {
+            Ok(by_account) => messages.extend(by_account),
+            Err(e) => {
+                log::error!("Couldn't read messages for {}: {}", request.user.nick, e);
+
+                return vec![];
+            }
+        }
+
+        messages.sort_by_key(|message| storage::integer(message, "id"));
+
+        let private = !request.source.is_channel_name() || self.config.delivery == TellDelivery::Private;
+        let mut responses: Vec<PrivMsgResponse> = vec![];
+
+        for message in messages {
+            if let Err(e) = storage.delete("messages", &[("id", Value::from(storage::integer(&message, "id")))]) {
+                log::error!("Couldn't remove delivered message: {}", e);
+
+                continue;
+            }
+
+            let location = storage::text(&message, "location");
+            let location = if location.is_empty() { "".to_string() } else { format!(" in {}", location) };
+            let text = format!("{} told you {} ago{}: {}",
+                               storage::text(&message, "display_sender"),
+                               format_duration(storage::now() - storage::integer(&message, "time")),
+                               location,
+                               storage::text(&message, "message"));
+
+            responses.push(PrivMsgResponse {
+                target: if private { request.user.nick.clone() } else { request.source.clone() },
+                message: if private { text } else { format!("{}: {}", request.user.nick, text) },
+                response_type: ResponseType::PrivMsg,
+            });
+        }
+
+        responses
+    }
+}
+
+fn reply(request: &PrivMsgRequest, message: String) -> PrivMsgResponse {
+    PrivMsgResponse {
+        target: request.source.clone(),
+        message,
+        response_type: ResponseType::PrivMsg,
+    }
+}
+
+fn storage_error(request: &PrivMsgRequest, e: anyhow::Error) -> PrivMsgResponse {
+    log::error!("Couldn't access the tell messages: {}", e);
+
+    reply(request, "Couldn't access the messages".to_string())
+}
+
+impl PrivMsgEvent for TellPrivMsgEvent {
+    fn name(&self) -> &'static str {
+        "tell"
+    }
+
+    fn execute(&self, request: PrivMsgRequest) -> Vec<PrivMsgResponse> {
+        let mut responses = self.deliver(&request);
+
+        if let Some(arguments) = request.command("tell") {
+            let storage = match request.server_storage(self.name()) {
+                Some(storage) => storage,
+                None => return vec![reply(&request, "Storage isn't available".to_string())],
+            };
+            let (nick, message) = arguments.split_once(' ').map(|(nick, message)| (nick, message.trim())).unwrap_or((arguments, ""));
+
+            let response = match (nick, message) {
+                ("list", "") => Some(self.list(&request, &storage)),
+                ("cancel", target) if !target.is_empty() => Some(self.cancel(&request, &storage, target)),
+                (nick, message) if !nick.is_empty() && !message.is_empty() => self.tell(&request, &storage, nick, message),
+                _ => Some(reply(&request, format!("Usage: {0}tell <nick> <message>, {0}tell list or {0}tell cancel <id|nick>", request.command_prefix))),
+            };
+
+            responses.extend(response);
+        }
+
+        responses
+    }
+
+    fn migrations(&self) -> &'static [&'static str] {
+        MIGRATIONS
+    }
+
+    fn on_event(&self, request: PrivMsgRequest, event: &UserEvent) -> Vec<PrivMsgResponse> {
+        match event {
+            UserEvent::Join => self.deliver(&request),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use simple_irc::Prefix;
+
+    use super::*;
+    use crate::privmsg::FakeRequest;
+
+    fn plugin(use_account: bool) -> TellPrivMsgEvent {
+        TellPrivMsgEvent { config: TellConfig { max_messages: 2, use_account, ..Default::default() } }
+    }
+
+    fn say(plugin: &TellPrivMsgEvent, fake: &mut FakeRequest, source: &str, nick: &str, message: &str) -> Vec<(String, String)> {
+        fake.source = source.to_string();
+        fake.user = Prefix::new_with_all(nick, Some("user"), Some("host.example"));
+        fake.message = message.to_string();
+
+        plugin.execute(fake.request()).into_iter().map(|response| (response.target, response.message)).collect()
+    }
+
+    fn messages(fake: &FakeRequest) -> Vec<String> {
+        let storage = fake.storage.as_ref().unwrap().scope("tell", &fake.server.hostname, None);
+
+        storage.select("messages", &[], "id", 0).unwrap().iter().map(|message| storage::text(message, "message")).collect()
+    }
+
+    fn reply(message: &str) -> Vec<(String, String)> {
+        vec![("#chan".to_string(), message.to_string())]
+    }
+
+    #[test]
+    fn inboxes_are_limited() {
+        let plugin = plugin(false);
+        let mut fake = FakeRequest::new("#chan", "alice", "").with_storage("tell", MIGRATIONS);
+
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell bob one"), reply("I'll tell bob when they're around"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "carol", ".tell Bob two"), reply("I'll tell Bob when they're around"));
+        // Nicks are compared with the server casemapping
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell BOB three"), reply("BOB already has 2 messages waiting"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell dave four"), reply("I'll tell dave when they're around"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell Alice hi"), reply("You can tell yourself that"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell bot hi"), reply("I'm right here"));
+        assert_eq!(messages(&fake), vec!["one", "two", "four"]);
+
+        let delivered = say(&plugin, &mut fake, "#chan", "bob", "hello");
+
+        assert_eq!(delivered.len(), 2);
+        assert!(delivered[0].1.starts_with("bob: alice told you ") && delivered[0].1.ends_with(" ago in #chan: one"));
+        assert!(delivered[1].1.starts_with("bob: carol told you ") && delivered[1].1.ends_with(" ago in #chan: two"));
+        assert_eq!(messages(&fake), vec!["four"]);
+    }
+
+    #[test]
+    fn senders_cancel_their_own_messages() {
+        let plugin = plugin(false);
+        let mut fake = FakeRequest::new("#chan", "alice", "").with_storage("tell", MIGRATIONS);
+
+        say(&plugin, &mut fake, "#chan", "alice", ".tell bob one");
+        say(&plugin, &mut fake, "#chan", "alice", ".tell bob two");
+        say(&plugin, &mut fake, "#chan", "alice", ".tell dave three");
+        say(&plugin, &mut fake, "#chan", "carol", ".tell dave four");
+
+        assert_eq!(say(&plugin, &mut fake, "#chan", "carol", ".tell cancel #1"), reply("You don't have any messages waiting for #1"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell cancel #3"), reply("Message cancelled"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell cancel BOB"), reply("2 messages cancelled"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell cancel bob"), reply("You don't have any messages waiting for bob"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell list"), reply("You don't have any messages waiting to be delivered"));
+        assert_eq!(messages(&fake), vec!["four"]);
+    }
+
+    #[test]
+    fn messages_to_accounts_are_only_given_to_who_is_logged_in() {
+        let plugin = plugin(true);
+        let mut fake = FakeRequest::new("#chan", "alice", "").with_storage("tell", MIGRATIONS);
+
+        fake.irc_state.update_user(&Prefix::new("bob"));
+        fake.irc_state.set_account("bob", "BobAccount");
+
+        // Unknown accounts are looked up first
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell carol hi"), vec![("carol".to_string(), "".to_string())]);
+
+        fake.irc_state.whois_completed.insert("carol".to_string(), Instant::now());
+
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell carol hi"), reply("I'll tell carol when they're around"));
+        assert_eq!(say(&plugin, &mut fake, "#chan", "alice", ".tell bob secret"), reply("I'll tell bob when they're around"));
+
+        // Someone else using the nick doesn't get it
+        fake.irc_state.remove_user("bob");
+        fake.irc_state.update_user(&Prefix::new("bob"));
+
+        assert_eq!(say(&plugin, &mut fake, "#chan", "bob", "hello"), vec![]);
+
+        fake.irc_state.update_user(&Prefix::new("Robert"));
+        fake.irc_state.set_account("Robert", "bobaccount");
+
+        let delivered = say(&plugin, &mut fake, "#chan", "Robert", "hello");
+
+        assert_eq!(delivered.len(), 1);
+        assert!(delivered[0].1.starts_with("Robert: alice told you ") && delivered[0].1.ends_with(": secret"));
+        assert_eq!(messages(&fake), vec!["hi"]);
+    }
+}